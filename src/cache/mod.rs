@@ -0,0 +1,204 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+/// A bounded cache that evicts the least recently used entries.
+///
+/// The cache is bounded by the number of entries and optionally by the total size of the values,
+/// as measured by a size function (for example the length of a file's bytes).
+pub struct LruCache<K, V> {
+    capacity: usize,
+    max_size: Option<usize>,
+    size_of: fn(&V) -> usize,
+    entries: HashMap<K, Entry<V>>,
+    /// Keys ordered by when they were last used, the first is the least recently used.
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    size: usize,
+}
+
+struct Entry<V> {
+    value: V,
+    last_used: u64,
+    size: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    /// Creates a new [`LruCache`] holding at most `capacity` entries.
+    pub fn new(capacity: usize) -> LruCache<K, V> {
+        LruCache {
+            capacity,
+            max_size: None,
+            size_of: |_| 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            size: 0,
+        }
+    }
+
+    /// Creates a new [`LruCache`] holding at most `capacity` entries, with a total size (as
+    /// returned by `size_of`) of at most `max_size`.
+    pub fn with_max_size(
+        capacity: usize,
+        max_size: usize,
+        size_of: fn(&V) -> usize,
+    ) -> LruCache<K, V> {
+        LruCache {
+            max_size: Some(max_size),
+            size_of,
+            ..LruCache::new(capacity)
+        }
+    }
+
+    /// Get a value, marking it as the most recently used.
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(key)?;
+
+        self.order.remove(&entry.last_used);
+        self.order.insert(tick, key.clone());
+        entry.last_used = tick;
+
+        Some(&entry.value)
+    }
+
+    /// Insert a value, replacing any existing value for the key, then evict the least recently
+    /// used entries until the cache is within its bounds.
+    ///
+    /// A value bigger than the maximum size is not cached.
+    pub fn insert(&mut self, key: K, value: V) {
+        self.remove(&key);
+
+        let size = (self.size_of)(&value);
+
+        if matches!(self.max_size, Some(max_size) if size > max_size) || self.capacity == 0 {
+            return;
+        }
+
+        let tick = self.next_tick();
+
+        self.order.insert(tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                last_used: tick,
+                size,
+            },
+        );
+        self.size += size;
+
+        self.evict();
+    }
+
+    /// Remove a value, returning it if it was cached.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+
+        self.order.remove(&entry.last_used);
+        self.size -= entry.size;
+
+        Some(entry.value)
+    }
+
+    /// Remove all entries.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.size = 0;
+    }
+
+    /// Returns the number of entries in this [`LruCache`].
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if this [`LruCache`] has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the total size of the values in this [`LruCache`], always 0 if it has no maximum
+    /// size.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Evict the least recently used entries until the cache is within its bounds.
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity
+            || matches!(self.max_size, Some(max_size) if self.size > max_size)
+        {
+            match self.order.pop_first() {
+                Some((_, key)) => {
+                    if let Some(entry) = self.entries.remove(&key) {
+                        self.size -= entry.size;
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let mut cache = LruCache::new(2);
+
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert_eq!(cache.get(&"a"), Some(&1));
+
+        cache.insert("c", 3);
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        assert_eq!(cache.get(&"c"), Some(&3));
+    }
+
+    #[test]
+    fn caches_stay_within_their_maximum_size() {
+        let mut cache: LruCache<&str, Vec<u8>> = LruCache::with_max_size(10, 10, Vec::len);
+
+        cache.insert("a", vec![0; 4]);
+        cache.insert("b", vec![0; 4]);
+        cache.insert("c", vec![0; 4]);
+
+        assert_eq!(cache.size(), 8);
+        assert!(cache.get(&"a").is_none());
+
+        // Too big to cache at all, the other entries are kept.
+        cache.insert("d", vec![0; 11]);
+        assert!(cache.get(&"d").is_none());
+        assert_eq!(cache.len(), 2);
+
+        // Replacing a value updates the size.
+        cache.insert("b", vec![0; 1]);
+        assert_eq!(cache.size(), 5);
+
+        assert_eq!(cache.remove(&"c"), Some(vec![0; 4]));
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn zero_capacity_caches_store_nothing() {
+        let mut cache = LruCache::new(0);
+
+        cache.insert("a", 1);
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,100 @@
+use std::time::Instant;
+
+use chrono::{DateTime, UTC};
+
+/// A source of the current time. Timing dependent logic (such as the message hub's settle
+/// period) reads the time from a [`Clock`] so tests can drive it with a [`ManualClock`] rather
+/// than waiting on the wall clock.
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+
+    /// Returns the current date and time, for timestamps shown to users.
+    fn now_utc(&self) -> DateTime<UTC>;
+}
+
+/// The system's monotonic clock.
+#[derive(Clone, Copy, Default)]
+pub struct SystemClock;
+
+/// A clock that only moves when it is advanced, clones share the same time.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct ManualClock {
+    /// The time the clock started at, as an [`Instant`] and a date and time.
+    start: (Instant, DateTime<UTC>),
+    /// How far the clock has been advanced.
+    elapsed: std::sync::Arc<std::sync::Mutex<std::time::Duration>>,
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn now_utc(&self) -> DateTime<UTC> {
+        UTC::now()
+    }
+}
+
+#[cfg(test)]
+impl ManualClock {
+    /// Creates a new [`ManualClock`], starting at the current time.
+    pub fn new() -> ManualClock {
+        ManualClock {
+            start: (Instant::now(), UTC::now()),
+            elapsed: std::sync::Arc::new(std::sync::Mutex::new(std::time::Duration::ZERO)),
+        }
+    }
+
+    /// Move the clock forward.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned.
+    pub fn advance(&self, duration: std::time::Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start.0 + *self.elapsed.lock().unwrap()
+    }
+
+    fn now_utc(&self) -> DateTime<UTC> {
+        let elapsed = chrono::Duration::from_std(*self.elapsed.lock().unwrap()).unwrap();
+
+        self.start.1 + elapsed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn manual_clocks_only_move_when_advanced() {
+        let clock = ManualClock::new();
+        let shared = clock.clone();
+        let (start, start_utc) = (clock.now(), clock.now_utc());
+
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(clock.now(), start);
+        assert_eq!(clock.now_utc(), start_utc);
+
+        shared.advance(Duration::from_secs(90));
+        assert_eq!(clock.now() - start, Duration::from_secs(90));
+        assert_eq!(clock.now_utc() - start_utc, chrono::Duration::seconds(90));
+    }
+}
@@ -0,0 +1,936 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    time::Duration,
+};
+
+use regex::Regex;
+
+use crate::{
+    files::path_matches,
+    hooks::BuildHook,
+    http::{
+        common::{
+            get_content_type, DEFAULT_BUFFER_SIZE, DEFAULT_CONTENT_TYPE, DEFAULT_MAX_BODY_SIZE,
+            DEFAULT_SERVER_NAME,
+        },
+        router::{NotFoundHandler, Router},
+        server::parse_address,
+    },
+    logging::{
+        common::LogLevel,
+        logger::{self, OverflowPolicy},
+    },
+    messaging::NotificationOverflow,
+};
+
+/// The `Cache-Control` value for responses that must never be cached.
+pub const NO_STORE: &str = "no-store";
+
+/// The `Cache-Control` values sent with files, by the kind of file.
+pub struct CachePolicy {
+    /// Sent with html documents.
+    pub html: String,
+    /// Sent with files matching the fingerprint pattern.
+    pub fingerprinted: String,
+    /// Sent with any other files.
+    pub default: String,
+    /// Matches the routes of fingerprinted files, that have a hash of their contents in the name
+    /// (such as `app.3f2a9c1e.js`), so can be cached indefinitely.
+    pub fingerprint_pattern: Regex,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        CachePolicy {
+            html: "no-cache".to_string(),
+            fingerprinted: "max-age=31536000, immutable".to_string(),
+            default: "max-age=10".to_string(),
+            fingerprint_pattern: Regex::new(r"\.[0-9a-fA-F]{8,}\.[^./]+$").unwrap(),
+        }
+    }
+}
+
+/// Configuration for the dev server.
+pub struct Config {
+    /// The address the http server binds to.
+    pub address: String,
+    /// The directory files are served from and watched.
+    pub base_path: String,
+    /// The number of worker threads handling connections.
+    pub workers: usize,
+    /// How long to wait for a client to send a request before responding with a 408.
+    /// `None` waits indefinitely.
+    pub read_timeout: Option<Duration>,
+    /// How long to keep an idle connection open waiting for another request.
+    pub keep_alive_timeout: Duration,
+    /// The maximum number of requests served on one connection before it is closed.
+    pub max_requests_per_connection: usize,
+    /// The size of the buffer requests are read into, headers must fit within it.
+    pub read_buffer_size: usize,
+    /// Requests with a bigger body (in bytes) are rejected with a 413.
+    pub max_body_size: usize,
+    /// The value of the `Server` header sent with responses. `None` omits the header.
+    pub server_name: Option<String>,
+    /// The maximum number of open connections, any more are rejected with a 503.
+    pub max_connections: usize,
+    /// The number of connections that can wait to be accepted.
+    pub accept_backlog: u32,
+    /// How long shutting down waits for open connections to finish.
+    pub shutdown_timeout: Duration,
+    /// Inject the live reload script into served html documents.
+    pub inject_reload: bool,
+    /// Html documents larger than this (in bytes) are served without the live reload script.
+    pub max_inject_size: usize,
+    /// Hot-swap changed stylesheets rather than reloading the whole page.
+    pub css_hot_reload: bool,
+    /// How long the reload client waits for further notifications before reloading, so a burst
+    /// of changes causes a single reload.
+    pub reload_debounce: Duration,
+    /// The longest the reload client waits between attempts to reconnect after losing its
+    /// connection.
+    pub reconnect_max_backoff: Duration,
+    /// Let the reload client fall back to server-sent events if it can not open a WebSocket.
+    pub sse_fallback: bool,
+    /// The largest message (in bytes) accepted from a WebSocket client, the connection is closed
+    /// if a client sends a larger one.
+    pub ws_max_message_size: usize,
+    /// Compress notifications with the `permessage-deflate` extension for clients offering it.
+    pub ws_deflate: bool,
+    /// Additional directories to serve, as `(url_prefix, directory)` pairs. The mount with the
+    /// longest matching prefix is used before falling back to the base path.
+    pub mounts: Vec<(String, String)>,
+    /// Respond to TRACE requests by echoing the request. Disabled by default, when disabled
+    /// TRACE requests receive a 405.
+    pub allow_trace: bool,
+    /// Trust the `X-Forwarded-For` header for the client's address, only enable this when the
+    /// server is behind a reverse proxy.
+    pub trust_forwarded: bool,
+    /// The `Cache-Control` values sent with files.
+    pub cache_policy: CachePolicy,
+    /// Glob patterns (such as `config.json` or `/api/*`) for routes that must never be cached,
+    /// these are sent with `Cache-Control: no-store` and no ETag.
+    pub no_store: Vec<String>,
+    /// Compress text based responses with Brotli or gzip when the client accepts it.
+    pub compression: bool,
+    /// The charset added to the `Content-Type` of responses, by content type (for example
+    /// `text/html` to `utf-8`). Types without an entry are sent without a charset.
+    pub charsets: HashMap<String, String>,
+    /// Values substituted for `{{KEY}}` placeholders in served html documents, by key. Empty
+    /// (the default) leaves documents untouched.
+    pub template_vars: HashMap<String, String>,
+    /// The content type of files with an unknown extension whose contents are not recognised.
+    pub default_content_type: String,
+    /// Send `application/octet-stream` files with `Content-Disposition: attachment`, so browsers
+    /// download them rather than trying to display them.
+    pub download_octet_stream: bool,
+    /// The number of files kept in memory so they are not read from disk for every request,
+    /// 0 disables the cache.
+    pub file_cache_entries: usize,
+    /// The total size (in bytes) of the files kept in memory, larger files are never cached.
+    pub file_cache_size: usize,
+    /// The minimum level of log items printed, `trace` includes the raw header of every
+    /// request.
+    pub log_level: LogLevel,
+    /// Color log lines by their level, `None` to color them only when printing to a terminal.
+    pub log_color: Option<bool>,
+    /// The `strftime` style format of the timestamp at the start of each log line.
+    pub log_time_format: String,
+    /// The number of log items waiting to be printed the log holds.
+    pub log_capacity: usize,
+    /// What to do with log items when the log is full.
+    pub log_overflow: OverflowPolicy,
+    /// When set, only these routes (for example `/` or `/app.css`) are served from the file
+    /// system, any other route returns a 404 even if the file exists.
+    pub allowlist: Option<HashSet<String>>,
+    /// Poll for file changes at this interval instead of using the platform's native file
+    /// events, for file systems that do not deliver them. `None` uses native events.
+    pub poll_interval: Option<Duration>,
+    /// How long there must be no changes for before a files settled notification is sent.
+    pub settle_period: Duration,
+    /// The number of recent notifications kept for debugging and `/__dev/changes`.
+    pub recent_notifications: usize,
+    /// The number of file change notifications that can wait for the message hub, `None` for no
+    /// limit.
+    pub notification_capacity: Option<usize>,
+    /// What happens to file change notifications when `notification_capacity` is reached.
+    pub notification_overflow: NotificationOverflow,
+    /// Commands to run when matching files change, reload notifications are sent once they
+    /// succeed.
+    pub build_hooks: Vec<BuildHook>,
+    /// How long there must be no changes for before the build hooks run.
+    pub hook_debounce: Duration,
+    /// The longest changes are collected for before the build hooks run, even if changes keep
+    /// arriving.
+    pub hook_max_batch_age: Duration,
+    /// Handlers for custom routes, checked before serving static files.
+    pub router: Router,
+    /// Creates the response for requests not matching a route or a file, `None` for a plain 404.
+    pub not_found: Option<NotFoundHandler>,
+    /// The files served for a directory, the first that exists is used.
+    pub index_names: Vec<String>,
+    /// How many directories deep (below the base path or a mount) index files are served for,
+    /// `None` for any depth and `Some(0)` for only the root.
+    pub index_depth: Option<usize>,
+    /// Serve a built in icon for `/favicon.ico` when the file does not exist, rather than a 404
+    /// for every page load.
+    pub default_favicon: bool,
+    /// Authoring mode, `PUT` requests write their body to the file for the route and `DELETE`
+    /// requests remove it.
+    pub writable: bool,
+    /// List the entries of directories without an index file, rather than returning a 404.
+    pub directory_listing: bool,
+    /// The path of the html template for directory listings, with `{{entries}}` replaced by the
+    /// table rows and `{{path}}` by the route. `None` uses the built in template.
+    pub directory_template: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: "127.0.0.1:8080".to_string(),
+            base_path: ".".to_string(),
+            workers: 4,
+            read_timeout: Some(Duration::from_secs(10)),
+            keep_alive_timeout: Duration::from_secs(2),
+            max_requests_per_connection: 100,
+            read_buffer_size: DEFAULT_BUFFER_SIZE,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            server_name: Some(DEFAULT_SERVER_NAME.to_string()),
+            max_connections: 64,
+            accept_backlog: 1024,
+            shutdown_timeout: Duration::from_secs(5),
+            inject_reload: true,
+            css_hot_reload: true,
+            reload_debounce: Duration::from_millis(100),
+            reconnect_max_backoff: Duration::from_secs(10),
+            sse_fallback: true,
+            max_inject_size: 5 * 1024 * 1024,
+            ws_max_message_size: 64 * 1024,
+            ws_deflate: true,
+            mounts: Vec::new(),
+            allow_trace: false,
+            trust_forwarded: false,
+            cache_policy: CachePolicy::default(),
+            no_store: Vec::new(),
+            compression: true,
+            charsets: default_charsets(),
+            template_vars: HashMap::new(),
+            default_content_type: DEFAULT_CONTENT_TYPE.to_string(),
+            download_octet_stream: false,
+            file_cache_entries: 256,
+            file_cache_size: 32 * 1024 * 1024,
+            log_level: logger::DEFAULT_LEVEL,
+            log_color: None,
+            log_time_format: logger::DEFAULT_TIME_FORMAT.to_string(),
+            log_capacity: logger::DEFAULT_CAPACITY,
+            log_overflow: OverflowPolicy::DropOldest,
+            allowlist: None,
+            poll_interval: None,
+            settle_period: Duration::from_millis(500),
+            recent_notifications: 50,
+            notification_capacity: None,
+            notification_overflow: NotificationOverflow::DropNewest,
+            build_hooks: Vec::new(),
+            hook_debounce: Duration::from_millis(100),
+            hook_max_batch_age: Duration::from_secs(1),
+            router: Router::new(),
+            not_found: None,
+            index_names: vec!["index.html".to_string()],
+            index_depth: None,
+            default_favicon: true,
+            writable: false,
+            directory_listing: false,
+            directory_template: None,
+        }
+    }
+}
+
+impl Config {
+    /// Create a [`Config`] from the environment, falling back to the defaults for any variables
+    /// that are not set.
+    ///
+    /// * `DEV_SERVER_ROOT` - the base path.
+    /// * `DEV_SERVER_ADDR` - the address to bind to.
+    /// * `DEV_SERVER_WORKERS` - the number of worker threads.
+    /// * `DEV_SERVER_ALLOWLIST` - a comma separated list of routes, enables allowlist mode.
+    /// * `DEV_SERVER_MAX_BODY_SIZE` - the largest request body accepted (in bytes).
+    /// * `DEV_SERVER_POLL_MS` - poll for file changes at this interval (in milliseconds).
+    /// * `DEV_SERVER_LOG_LEVEL` - the minimum level logged (`trace`, `debug`, `info`, `warning`
+    ///   or `error`).
+    /// * `DEV_SERVER_LOG_COLOR` - `always`, `never` or `auto` (color when printing to a
+    ///   terminal).
+    /// * `DEV_SERVER_LOG_TIME_FORMAT` - the `strftime` style format of log timestamps (RFC 3339
+    ///   by default).
+    /// * `DEV_SERVER_LOG_CAPACITY` - the number of log items waiting to be printed the log holds.
+    /// * `DEV_SERVER_LOG_OVERFLOW` - `drop` (the oldest items) or `block` when the log is full.
+    /// * `DEV_SERVER_CHARSETS` - a comma separated list of `type=charset` entries (such as
+    ///   `text/plain=iso-8859-1`) added to the default charsets, an empty charset removes one.
+    /// * `DEV_SERVER_TEMPLATE_VARS` - a comma separated list of `KEY=value` entries (such as
+    ///   `TITLE=Mockup,BASE_URL=/`) substituted for `{{KEY}}` in served html documents.
+    /// * `DEV_SERVER_DEFAULT_CONTENT_TYPE` - the content type of files with an unknown extension
+    ///   (`application/octet-stream` by default).
+    /// * `DEV_SERVER_DOWNLOAD_OCTET_STREAM` - `on` to send `application/octet-stream` files as
+    ///   attachments, so browsers download them.
+    /// * `DEV_SERVER_INDEX_DEPTH` - how many directories deep index files are served for.
+    /// * `DEV_SERVER_WRITABLE` - `on` to let `PUT` and `DELETE` requests write and remove files.
+    /// * `DEV_SERVER_DIRECTORY_LISTING` - `on` to list directories without an index file, `off`
+    ///   to return a 404.
+    /// * `DEV_SERVER_DIRECTORY_TEMPLATE` - the path of the html template for directory listings.
+    /// * `DEV_SERVER_RECENT_NOTIFICATIONS` - the number of recent notifications kept.
+    /// * `DEV_SERVER_NOTIFICATION_CAPACITY` - the number of file change notifications that can
+    ///   wait to be handled (unlimited if not set).
+    /// * `DEV_SERVER_NOTIFICATION_OVERFLOW` - `drop` (the newest notification) or `block` when
+    ///   the notification capacity is reached.
+    /// * `DEV_SERVER_WS_DEFLATE` - `on` to compress notifications for clients that support it,
+    ///   `off` to always send them uncompressed.
+    /// * `DEV_SERVER_CSS_RELOAD` - `hot` (swap changed stylesheets) or `full` (reload the page).
+    /// * `DEV_SERVER_RELOAD_DEBOUNCE_MS` - how long the reload client waits for further changes
+    ///   (in milliseconds).
+    /// * `DEV_SERVER_BACKLOG` - the number of connections that can wait to be accepted.
+    /// * `DEV_SERVER_SHUTDOWN_TIMEOUT_MS` - how long shutting down waits for open connections to
+    ///   finish (in milliseconds).
+    /// * `DEV_SERVER_RECONNECT_MAX_MS` - the longest the reload client waits between reconnection
+    ///   attempts (in milliseconds).
+    /// * `DEV_SERVER_SSE_FALLBACK` - `off` to stop the reload client falling back to server-sent
+    ///   events when it can not open a WebSocket.
+    /// * `DEV_SERVER_MOUNTS` - a comma separated list of `/prefix=directory` entries (such as
+    ///   `/vendor=./node_modules`) served alongside the base path.
+    /// * `DEV_SERVER_BUILD_HOOKS` - a comma separated list of `pattern=command` entries (such as
+    ///   `*.scss=sass style.scss style.css`) run when matching files change.
+    /// * `DEV_SERVER_HOOK_DEBOUNCE_MS` - how long there must be no changes for before the build
+    ///   hooks run (in milliseconds).
+    /// * `DEV_SERVER_HOOK_MAX_BATCH_MS` - the longest changes are collected for before the build
+    ///   hooks run (in milliseconds).
+    /// * `DEV_SERVER_TRUST_FORWARDED` - `on` to take the client's address from
+    ///   `X-Forwarded-For`, only when behind a reverse proxy.
+    /// * `DEV_SERVER_ALLOW_TRACE` - `on` to echo TRACE requests.
+    /// * `DEV_SERVER_NO_STORE` - a comma separated list of glob patterns for routes sent with
+    ///   `Cache-Control: no-store`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `DEV_SERVER_WORKERS`, `DEV_SERVER_POLL_MS` or
+    /// `DEV_SERVER_LOG_CAPACITY` is not a positive number or another variable is not one of its
+    /// accepted values.
+    pub fn from_env() -> Result<Config, &'static str> {
+        let mut config = Config::default();
+
+        if let Ok(base_path) = env::var("DEV_SERVER_ROOT") {
+            config.base_path = base_path;
+        }
+
+        if let Ok(address) = env::var("DEV_SERVER_ADDR") {
+            config.address = address;
+        }
+
+        if let Ok(workers) = env::var("DEV_SERVER_WORKERS") {
+            config.workers = match workers.trim().parse::<usize>() {
+                Ok(workers) if workers > 0 => workers,
+                _ => return Err("DEV_SERVER_WORKERS must be a positive number"),
+            };
+        }
+
+        if let Ok(size) = env::var("DEV_SERVER_MAX_BODY_SIZE") {
+            config.max_body_size = size
+                .trim()
+                .parse()
+                .map_err(|_| "DEV_SERVER_MAX_BODY_SIZE must be a number")?;
+        }
+
+        if let Ok(interval) = env::var("DEV_SERVER_POLL_MS") {
+            config.poll_interval = match interval.trim().parse::<u64>() {
+                Ok(interval) if interval > 0 => Some(Duration::from_millis(interval)),
+                _ => return Err("DEV_SERVER_POLL_MS must be a positive number"),
+            };
+        }
+
+        if let Ok(level) = env::var("DEV_SERVER_LOG_LEVEL") {
+            config.log_level = level
+                .parse()
+                .map_err(|_| "DEV_SERVER_LOG_LEVEL must be a log level")?;
+        }
+
+        if let Ok(color) = env::var("DEV_SERVER_LOG_COLOR") {
+            config.log_color = match color.trim().to_lowercase().as_str() {
+                "always" => Some(true),
+                "never" => Some(false),
+                "auto" => None,
+                _ => return Err("DEV_SERVER_LOG_COLOR must be always, never or auto"),
+            };
+        }
+
+        if let Ok(format) = env::var("DEV_SERVER_LOG_TIME_FORMAT") {
+            if !logger::is_valid_time_format(&format) {
+                return Err("DEV_SERVER_LOG_TIME_FORMAT must be a valid time format");
+            }
+
+            config.log_time_format = format;
+        }
+
+        if let Ok(capacity) = env::var("DEV_SERVER_LOG_CAPACITY") {
+            config.log_capacity = match capacity.trim().parse::<usize>() {
+                Ok(capacity) if capacity > 0 => capacity,
+                _ => return Err("DEV_SERVER_LOG_CAPACITY must be a positive number"),
+            };
+        }
+
+        if let Ok(policy) = env::var("DEV_SERVER_LOG_OVERFLOW") {
+            config.log_overflow = policy
+                .parse()
+                .map_err(|_| "DEV_SERVER_LOG_OVERFLOW must be drop or block")?;
+        }
+
+        if let Ok(charsets) = env::var("DEV_SERVER_CHARSETS") {
+            for entry in charsets.split(',').filter(|e| !e.trim().is_empty()) {
+                let (content_type, charset) = entry
+                    .split_once('=')
+                    .ok_or("DEV_SERVER_CHARSETS entries must be type=charset")?;
+                let content_type = content_type.trim().to_lowercase();
+
+                if charset.trim().is_empty() {
+                    config.charsets.remove(&content_type);
+                } else {
+                    config
+                        .charsets
+                        .insert(content_type, charset.trim().to_string());
+                }
+            }
+        }
+
+        if let Ok(vars) = env::var("DEV_SERVER_TEMPLATE_VARS") {
+            for entry in vars.split(',').filter(|e| !e.trim().is_empty()) {
+                let (key, value) = entry
+                    .split_once('=')
+                    .ok_or("DEV_SERVER_TEMPLATE_VARS entries must be KEY=value")?;
+
+                config
+                    .template_vars
+                    .insert(key.trim().to_string(), value.to_string());
+            }
+        }
+
+        if let Ok(content_type) = env::var("DEV_SERVER_DEFAULT_CONTENT_TYPE") {
+            let content_type = content_type.trim();
+
+            if !content_type.contains('/') {
+                return Err("DEV_SERVER_DEFAULT_CONTENT_TYPE must be a type/subtype content type");
+            }
+
+            config.default_content_type = content_type.to_string();
+        }
+
+        if let Some(download) = parse_switch("DEV_SERVER_DOWNLOAD_OCTET_STREAM")? {
+            config.download_octet_stream = download;
+        }
+
+        if let Ok(depth) = env::var("DEV_SERVER_INDEX_DEPTH") {
+            config.index_depth = match depth.trim().parse::<usize>() {
+                Ok(depth) => Some(depth),
+                _ => return Err("DEV_SERVER_INDEX_DEPTH must be a number"),
+            };
+        }
+
+        if let Some(writable) = parse_switch("DEV_SERVER_WRITABLE")? {
+            config.writable = writable;
+        }
+
+        if let Some(listing) = parse_switch("DEV_SERVER_DIRECTORY_LISTING")? {
+            config.directory_listing = listing;
+        }
+
+        if let Ok(template) = env::var("DEV_SERVER_DIRECTORY_TEMPLATE") {
+            config.directory_template = Some(template);
+        }
+
+        if let Ok(count) = env::var("DEV_SERVER_RECENT_NOTIFICATIONS") {
+            config.recent_notifications = count
+                .trim()
+                .parse()
+                .map_err(|_| "DEV_SERVER_RECENT_NOTIFICATIONS must be a number")?;
+        }
+
+        if let Ok(capacity) = env::var("DEV_SERVER_NOTIFICATION_CAPACITY") {
+            config.notification_capacity = match capacity.trim().parse::<usize>() {
+                Ok(capacity) if capacity > 0 => Some(capacity),
+                _ => return Err("DEV_SERVER_NOTIFICATION_CAPACITY must be a positive number"),
+            };
+        }
+
+        if let Ok(policy) = env::var("DEV_SERVER_NOTIFICATION_OVERFLOW") {
+            config.notification_overflow = policy
+                .parse()
+                .map_err(|_| "DEV_SERVER_NOTIFICATION_OVERFLOW must be drop or block")?;
+        }
+
+        if let Some(deflate) = parse_switch("DEV_SERVER_WS_DEFLATE")? {
+            config.ws_deflate = deflate;
+        }
+
+        if let Ok(reload) = env::var("DEV_SERVER_CSS_RELOAD") {
+            config.css_hot_reload = match reload.trim().to_lowercase().as_str() {
+                "hot" => true,
+                "full" => false,
+                _ => return Err("DEV_SERVER_CSS_RELOAD must be hot or full"),
+            };
+        }
+
+        if let Ok(debounce) = env::var("DEV_SERVER_RELOAD_DEBOUNCE_MS") {
+            config.reload_debounce = debounce
+                .trim()
+                .parse()
+                .map(Duration::from_millis)
+                .map_err(|_| "DEV_SERVER_RELOAD_DEBOUNCE_MS must be a number")?;
+        }
+
+        if let Ok(backlog) = env::var("DEV_SERVER_BACKLOG") {
+            config.accept_backlog = match backlog.trim().parse() {
+                Ok(backlog) if backlog > 0 => backlog,
+                _ => return Err("DEV_SERVER_BACKLOG must be a positive number"),
+            };
+        }
+
+        if let Ok(timeout) = env::var("DEV_SERVER_SHUTDOWN_TIMEOUT_MS") {
+            config.shutdown_timeout = timeout
+                .trim()
+                .parse()
+                .map(Duration::from_millis)
+                .map_err(|_| "DEV_SERVER_SHUTDOWN_TIMEOUT_MS must be a number")?;
+        }
+
+        if let Ok(backoff) = env::var("DEV_SERVER_RECONNECT_MAX_MS") {
+            config.reconnect_max_backoff = match backoff.trim().parse() {
+                Ok(ms) if ms > 0 => Duration::from_millis(ms),
+                _ => return Err("DEV_SERVER_RECONNECT_MAX_MS must be a positive number"),
+            };
+        }
+
+        if let Some(fallback) = parse_switch("DEV_SERVER_SSE_FALLBACK")? {
+            config.sse_fallback = fallback;
+        }
+
+        if let Ok(mounts) = env::var("DEV_SERVER_MOUNTS") {
+            for entry in mounts.split(',').filter(|e| !e.trim().is_empty()) {
+                let (prefix, dir) = match entry.split_once('=') {
+                    Some((prefix, dir))
+                        if prefix.trim().starts_with('/') && !dir.trim().is_empty() =>
+                    {
+                        (prefix.trim(), dir.trim())
+                    }
+                    _ => return Err("DEV_SERVER_MOUNTS entries must be /prefix=directory"),
+                };
+
+                config.mounts.push((prefix.to_string(), dir.to_string()));
+            }
+        }
+
+        if let Ok(hooks) = env::var("DEV_SERVER_BUILD_HOOKS") {
+            for entry in hooks.split(',').filter(|e| !e.trim().is_empty()) {
+                let (pattern, command) = match entry.split_once('=') {
+                    Some((pattern, command))
+                        if !pattern.trim().is_empty() && !command.trim().is_empty() =>
+                    {
+                        (pattern.trim(), command.trim())
+                    }
+                    _ => return Err("DEV_SERVER_BUILD_HOOKS entries must be pattern=command"),
+                };
+
+                config.build_hooks.push(BuildHook {
+                    pattern: pattern.to_string(),
+                    command: command.to_string(),
+                });
+            }
+        }
+
+        if let Ok(debounce) = env::var("DEV_SERVER_HOOK_DEBOUNCE_MS") {
+            config.hook_debounce = debounce
+                .trim()
+                .parse()
+                .map(Duration::from_millis)
+                .map_err(|_| "DEV_SERVER_HOOK_DEBOUNCE_MS must be a number")?;
+        }
+
+        if let Ok(age) = env::var("DEV_SERVER_HOOK_MAX_BATCH_MS") {
+            config.hook_max_batch_age = match age.trim().parse() {
+                Ok(ms) if ms > 0 => Duration::from_millis(ms),
+                _ => return Err("DEV_SERVER_HOOK_MAX_BATCH_MS must be a positive number"),
+            };
+        }
+
+        if let Some(trust) = parse_switch("DEV_SERVER_TRUST_FORWARDED")? {
+            config.trust_forwarded = trust;
+        }
+
+        if let Some(trace) = parse_switch("DEV_SERVER_ALLOW_TRACE")? {
+            config.allow_trace = trace;
+        }
+
+        if let Ok(patterns) = env::var("DEV_SERVER_NO_STORE") {
+            config.no_store = patterns
+                .split(',')
+                .map(|pattern| pattern.trim().to_string())
+                .filter(|pattern| !pattern.is_empty())
+                .collect();
+        }
+
+        if let Ok(allowlist) = env::var("DEV_SERVER_ALLOWLIST") {
+            config.allowlist = Some(
+                allowlist
+                    .split(',')
+                    .map(|route| route.trim().to_string())
+                    .filter(|route| !route.is_empty())
+                    .collect(),
+            );
+        }
+
+        Ok(config)
+    }
+
+    /// Check the config can be used to start the server.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the base path or a mounted directory does not exist
+    /// or is not a directory, the address is invalid or there are no workers.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        match fs::metadata(&self.base_path) {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => return Err("The base path is not a directory"),
+            Err(_) => return Err("The base path does not exist"),
+        }
+
+        parse_address(&self.address)?;
+
+        if self.workers == 0 {
+            return Err("There must be at least one worker");
+        }
+
+        for (_, dir) in &self.mounts {
+            if !fs::metadata(dir).is_ok_and(|metadata| metadata.is_dir()) {
+                return Err("A mounted directory does not exist or is not a directory");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the `Cache-Control` value for a route from the cache policy. Routes matching a
+    /// `no_store` pattern must never be cached, html documents (including the index files served
+    /// for directory routes) are always revalidated and fingerprinted files are cached
+    /// indefinitely.
+    pub fn cache_control_for(&self, route: &str) -> String {
+        let policy = &self.cache_policy;
+        let name = route.rsplit('/').next().unwrap_or_default();
+
+        if self
+            .no_store
+            .iter()
+            .any(|pattern| path_matches(pattern, route))
+        {
+            NO_STORE.to_string()
+        } else if !name.contains('.') || get_content_type(name) == "text/html" {
+            policy.html.clone()
+        } else if policy.fingerprint_pattern.is_match(route) {
+            policy.fingerprinted.clone()
+        } else {
+            policy.default.clone()
+        }
+    }
+
+    /// Get a summary of the main settings, for printing at startup.
+    pub fn get_summary(&self) -> String {
+        let mut summary = format!(
+            "Address: {}\nBase path: {}\nWorkers: {}\n",
+            self.address, self.base_path, self.workers
+        );
+
+        for (prefix, dir) in &self.mounts {
+            summary.push_str(&format!("Mount: {} -> {}\n", prefix, dir));
+        }
+
+        summary.push_str(&match self.poll_interval {
+            Some(interval) => format!("Watching: polling every {}ms\n", interval.as_millis()),
+            None => "Watching: native\n".to_string(),
+        });
+
+        for hook in &self.build_hooks {
+            summary.push_str(&format!(
+                "Build hook: {} -> {}\n",
+                hook.pattern, hook.command
+            ));
+        }
+
+        summary.push_str(&format!("Log level: {}", self.log_level));
+        summary
+    }
+}
+
+/// Read an `on`/`off` environment variable, `None` if it is not set.
+///
+/// # Errors
+///
+/// This function will return an error if the variable is set to anything else.
+fn parse_switch(name: &str) -> Result<Option<bool>, &'static str> {
+    match env::var(name) {
+        Ok(value) => match value.trim().to_lowercase().as_str() {
+            "on" => Ok(Some(true)),
+            "off" => Ok(Some(false)),
+            // The config is only read once at startup, so leaking the message is fine.
+            _ => Err(Box::leak(
+                format!("{} must be on or off", name).into_boxed_str(),
+            )),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// The charsets sent by default, text based types are sent as UTF-8.
+fn default_charsets() -> HashMap<String, String> {
+    [
+        "text/html",
+        "text/css",
+        "text/plain",
+        "application/javascript",
+        "application/json",
+    ]
+    .iter()
+    .map(|content_type| (content_type.to_string(), "utf-8".to_string()))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Held by tests setting environment variables, so they can not see each other's.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Read a config with the variables set, removing them again afterwards.
+    fn from_env_with(vars: &[(&str, &str)]) -> Result<Config, &'static str> {
+        for (name, value) in vars {
+            env::set_var(name, value);
+        }
+
+        let config = Config::from_env();
+
+        for (name, _) in vars {
+            env::remove_var(name);
+        }
+
+        config
+    }
+
+    #[test]
+    fn from_env_reads_the_root_address_and_workers() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        env::set_var("DEV_SERVER_ROOT", "/srv/site");
+        env::set_var("DEV_SERVER_ADDR", "0.0.0.0:3000");
+        env::set_var("DEV_SERVER_WORKERS", "8");
+
+        let config = Config::from_env().unwrap();
+
+        assert_eq!(config.base_path, "/srv/site");
+        assert_eq!(config.address, "0.0.0.0:3000");
+        assert_eq!(config.workers, 8);
+
+        env::set_var("DEV_SERVER_WORKERS", "0");
+        assert_eq!(
+            Config::from_env().err(),
+            Some("DEV_SERVER_WORKERS must be a positive number")
+        );
+
+        for name in ["DEV_SERVER_ROOT", "DEV_SERVER_ADDR", "DEV_SERVER_WORKERS"] {
+            env::remove_var(name);
+        }
+
+        let config = Config::from_env().unwrap();
+        let default = Config::default();
+
+        assert_eq!(config.base_path, default.base_path);
+        assert_eq!(config.address, default.address);
+        assert_eq!(config.workers, default.workers);
+    }
+
+    #[test]
+    fn from_env_reads_mounts() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let config = from_env_with(&[(
+            "DEV_SERVER_MOUNTS",
+            "/vendor=./node_modules, /lib = /opt/lib,",
+        )])
+        .unwrap();
+
+        assert_eq!(
+            config.mounts,
+            [
+                ("/vendor".to_string(), "./node_modules".to_string()),
+                ("/lib".to_string(), "/opt/lib".to_string()),
+            ]
+        );
+
+        for mounts in ["vendor=./node_modules", "/vendor=", "/vendor"] {
+            assert_eq!(
+                from_env_with(&[("DEV_SERVER_MOUNTS", mounts)]).err(),
+                Some("DEV_SERVER_MOUNTS entries must be /prefix=directory"),
+                "{}",
+                mounts
+            );
+        }
+    }
+
+    #[test]
+    fn from_env_reads_build_hooks() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let config = from_env_with(&[(
+            "DEV_SERVER_BUILD_HOOKS",
+            "*.scss=sass style.scss style.css,/js/*.ts = tsc -p .",
+        )])
+        .unwrap();
+        let hooks: Vec<(&str, &str)> = config
+            .build_hooks
+            .iter()
+            .map(|hook| (hook.pattern.as_str(), hook.command.as_str()))
+            .collect();
+
+        assert_eq!(
+            hooks,
+            [
+                ("*.scss", "sass style.scss style.css"),
+                ("/js/*.ts", "tsc -p ."),
+            ]
+        );
+
+        for hooks in ["*.scss", "*.scss=", "=make"] {
+            assert_eq!(
+                from_env_with(&[("DEV_SERVER_BUILD_HOOKS", hooks)]).err(),
+                Some("DEV_SERVER_BUILD_HOOKS entries must be pattern=command"),
+                "{}",
+                hooks
+            );
+        }
+    }
+
+    #[test]
+    fn from_env_reads_the_build_hook_timings() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let config = from_env_with(&[
+            ("DEV_SERVER_HOOK_DEBOUNCE_MS", "250"),
+            ("DEV_SERVER_HOOK_MAX_BATCH_MS", "5000"),
+        ])
+        .unwrap();
+
+        assert_eq!(config.hook_debounce, Duration::from_millis(250));
+        assert_eq!(config.hook_max_batch_age, Duration::from_secs(5));
+
+        assert_eq!(
+            from_env_with(&[("DEV_SERVER_HOOK_DEBOUNCE_MS", "soon")]).err(),
+            Some("DEV_SERVER_HOOK_DEBOUNCE_MS must be a number")
+        );
+        assert_eq!(
+            from_env_with(&[("DEV_SERVER_HOOK_MAX_BATCH_MS", "0")]).err(),
+            Some("DEV_SERVER_HOOK_MAX_BATCH_MS must be a positive number")
+        );
+    }
+
+    #[test]
+    fn from_env_reads_trust_forwarded_and_allow_trace() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let config = from_env_with(&[
+            ("DEV_SERVER_TRUST_FORWARDED", "on"),
+            ("DEV_SERVER_ALLOW_TRACE", "ON"),
+        ])
+        .unwrap();
+
+        assert!(config.trust_forwarded);
+        assert!(config.allow_trace);
+
+        let config = from_env_with(&[
+            ("DEV_SERVER_TRUST_FORWARDED", "off"),
+            ("DEV_SERVER_ALLOW_TRACE", "off"),
+        ])
+        .unwrap();
+
+        assert!(!config.trust_forwarded);
+        assert!(!config.allow_trace);
+
+        assert_eq!(
+            from_env_with(&[("DEV_SERVER_TRUST_FORWARDED", "yes")]).err(),
+            Some("DEV_SERVER_TRUST_FORWARDED must be on or off")
+        );
+        assert_eq!(
+            from_env_with(&[("DEV_SERVER_ALLOW_TRACE", "1")]).err(),
+            Some("DEV_SERVER_ALLOW_TRACE must be on or off")
+        );
+    }
+
+    #[test]
+    fn from_env_reads_no_store_patterns() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let config = from_env_with(&[("DEV_SERVER_NO_STORE", "config.json, /api/*,,")]).unwrap();
+
+        assert_eq!(config.no_store, ["config.json", "/api/*"]);
+        assert!(Config::from_env().unwrap().no_store.is_empty());
+    }
+
+    #[test]
+    fn no_store_routes_are_never_cached() {
+        let config = Config {
+            no_store: vec!["config.json".to_string(), "/api/*".to_string()],
+            ..Default::default()
+        };
+
+        for route in ["/config.json", "/nested/config.json", "/api/users", "/api/"] {
+            assert_eq!(config.cache_control_for(route), "no-store", "{}", route);
+        }
+
+        assert_eq!(config.cache_control_for("/app.js"), "max-age=10");
+        assert_eq!(config.cache_control_for("/config.json.bak"), "max-age=10");
+    }
+
+    #[test]
+    fn cache_control_depends_on_the_kind_of_file() {
+        let config = Config::default();
+
+        for route in ["/index.html", "/docs/page.htm", "/", "/docs/", "/docs"] {
+            assert_eq!(config.cache_control_for(route), "no-cache", "{}", route);
+        }
+
+        for route in ["/app.3f2a9c1e.js", "/css/site.0123456789abcdef.css"] {
+            assert_eq!(
+                config.cache_control_for(route),
+                "max-age=31536000, immutable",
+                "{}",
+                route
+            );
+        }
+
+        for route in [
+            "/app.js",
+            "/logo.png",
+            "/app.3f2a.js",
+            "/data.12345678/list.json",
+        ] {
+            assert_eq!(config.cache_control_for(route), "max-age=10", "{}", route);
+        }
+
+        let config = Config {
+            cache_policy: CachePolicy {
+                html: "no-store".to_string(),
+                default: "max-age=60".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(config.cache_control_for("/index.html"), "no-store");
+        assert_eq!(config.cache_control_for("/app.js"), "max-age=60");
+    }
+}
@@ -1,36 +1,132 @@
 use std::{
-    path::PathBuf,
-    sync::mpsc::{self, Sender},
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
-use notify::{watcher, RecursiveMode, Watcher};
+use notify::{watcher, PollWatcher, RecursiveMode, Watcher};
 
-use crate::{logging::logger::Log, messaging::Notification};
+use crate::{
+    clock::{Clock, SystemClock},
+    http::common::{is_valid_header_name, is_valid_header_value},
+    logging::logger::{Log, Logger},
+    messaging::{Notification, NotificationSender},
+};
+
+/// An error starting the file watcher.
+#[derive(Debug)]
+pub enum WatchError {
+    /// The underlying watcher could not be created.
+    Create(notify::Error),
+    /// The base path could not be watched.
+    Watch(notify::Error),
+    /// The system limit on the number of watches (`fs.inotify.max_user_watches` on Linux) was
+    /// reached.
+    WatchLimit,
+}
+
+/// The error code returned on Linux when the inotify watch limit is reached.
+const ENOSPC: i32 = 28;
+
+/// The name of the file listing extra response headers for the files in its directory (and its
+/// subdirectories without their own).
+pub const HEADERS_FILE_NAME: &str = "_headers";
+
+/// The headers listed in a `_headers` file, as `(name, value)` pairs.
+pub type HeaderList = Vec<(String, String)>;
+
+/// Reads `_headers` files, caching the parsed headers until the file is modified.
+///
+/// Each line of the file is a `Name: value` header, blank lines and lines starting with `#` are
+/// ignored. Invalid lines are logged when the file is read and otherwise ignored.
+#[derive(Default)]
+pub struct HeaderFiles {
+    /// The parsed headers by the path of the `_headers` file, with its modified time.
+    cache: Mutex<HashMap<PathBuf, (SystemTime, HeaderList)>>,
+}
 
 pub struct FileWatcher {
     thread: JoinHandle<()>,
+    running: Arc<AtomicBool>,
 }
 
 impl FileWatcher {
     /// Start the file watcher. This will return a FileWatcher with the related thread's
     /// JoinHandle.
     ///
-    /// # Panics
+    /// The platform's native watcher is used unless a `poll_interval` is given, then the files
+    /// are polled for changes instead. Polling works where native events are not delivered, such
+    /// as network file systems and some containers.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the watcher can not be created or the base path can
+    /// not be watched (for example if it does not exist).
+    pub fn start(
+        sender: NotificationSender,
+        base_path: String,
+        poll_interval: Option<Duration>,
+        log: &Log,
+    ) -> Result<FileWatcher, WatchError> {
+        FileWatcher::start_with_clock(sender, base_path, poll_interval, log, Arc::new(SystemClock))
+    }
+
+    /// Start the file watcher, timing the changes it sees with `clock` (to check them against
+    /// the server's own recent changes).
+    ///
+    /// # Errors
     ///
-    /// Panics if an DebouncedEvent::Error is returned.
-    pub fn start(sender: Sender<Notification>, base_path: String, log: &Log) -> FileWatcher {
+    /// This function will return an error if the watcher can not be created or the base path can
+    /// not be watched (for example if it does not exist).
+    pub fn start_with_clock(
+        sender: NotificationSender,
+        base_path: String,
+        poll_interval: Option<Duration>,
+        log: &Log,
+        clock: Arc<dyn Clock>,
+    ) -> Result<FileWatcher, WatchError> {
         let (tx, rx) = mpsc::channel();
         let logger = log.get_logger("file_watcher".to_string());
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
 
-        let thread = thread::spawn(move || {
-            let mut watcher = watcher(tx, Duration::from_secs(1)).unwrap();
+        let watcher: Box<dyn Send> = match poll_interval {
+            Some(interval) => {
+                let mut watcher = PollWatcher::new(tx, interval).map_err(WatchError::Create)?;
 
-            watcher.watch(base_path, RecursiveMode::Recursive).unwrap();
+                watcher
+                    .watch(&base_path, RecursiveMode::Recursive)
+                    .map_err(WatchError::from_watch)?;
 
-            loop {
-                match rx.recv() {
+                Box::new(watcher)
+            }
+            None => {
+                let mut watcher =
+                    watcher(tx, Duration::from_secs(1)).map_err(WatchError::Create)?;
+
+                watcher
+                    .watch(&base_path, RecursiveMode::Recursive)
+                    .map_err(WatchError::from_watch)?;
+
+                Box::new(watcher)
+            }
+        };
+
+        let thread = thread::spawn(move || {
+            // Keep the watcher alive for as long as the thread runs.
+            let _watcher = watcher;
+
+            // Wake up periodically to check if the watcher has been stopped.
+            while thread_running.load(Ordering::SeqCst) {
+                match rx.recv_timeout(Duration::from_millis(250)) {
                     Ok(event) => {
                         match event {
                             notify::DebouncedEvent::NoticeWrite(_) => {}
@@ -38,15 +134,21 @@ impl FileWatcher {
                             notify::DebouncedEvent::Create(e) => send_message(
                                 &sender,
                                 Notification::FileCreated(path_buf_to_string(e)),
+                                clock.now(),
+                                &logger,
                             ),
                             notify::DebouncedEvent::Write(e) => send_message(
                                 &sender,
                                 Notification::FileUpdated(path_buf_to_string(e)),
+                                clock.now(),
+                                &logger,
                             ),
                             notify::DebouncedEvent::Chmod(_) => {}
                             notify::DebouncedEvent::Remove(e) => send_message(
                                 &sender,
                                 Notification::FileRemoved(path_buf_to_string(e)),
+                                clock.now(),
+                                &logger,
                             ),
                             notify::DebouncedEvent::Rename(o, n) => send_message(
                                 &sender,
@@ -54,22 +156,159 @@ impl FileWatcher {
                                     path_buf_to_string(o),
                                     path_buf_to_string(n),
                                 ),
+                                clock.now(),
+                                &logger,
                             ),
                             notify::DebouncedEvent::Rescan => {}
-                            notify::DebouncedEvent::Error(_, _) => todo!(),
+                            notify::DebouncedEvent::Error(e, path) => {
+                                let message = if is_watch_limit(&e) {
+                                    WatchError::WatchLimit.get_str().to_string()
+                                } else {
+                                    e.to_string()
+                                };
+
+                                logger
+                                    .log_error(format!(
+                                        "Watch error: {}, Path: {:?}",
+                                        message, path
+                                    ))
+                                    .unwrap();
+                            }
                         };
                     }
-                    Err(_) => {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => {
                         logger.log_error("Watcher error.".to_string()).unwrap();
                     }
                 }
             }
         });
 
-        FileWatcher { thread }
+        Ok(FileWatcher { thread, running })
+    }
+
+    /// Stop watching and wait for the watcher thread to finish.
+    pub fn stop(self) {
+        self.running.store(false, Ordering::SeqCst);
+        let _ = self.thread.join();
+    }
+}
+
+impl WatchError {
+    /// Create a [`WatchError`] from an error watching a path, recognising when the watch limit
+    /// was reached.
+    pub fn from_watch(error: notify::Error) -> WatchError {
+        if is_watch_limit(&error) {
+            WatchError::WatchLimit
+        } else {
+            WatchError::Watch(error)
+        }
+    }
+
+    /// Returns a description of this [`WatchError`].
+    pub fn get_str(&self) -> &'static str {
+        match self {
+            WatchError::Create(_) => "Could not create the file watcher",
+            WatchError::Watch(notify::Error::PathNotFound) => {
+                "Could not watch the base path, it does not exist"
+            }
+            WatchError::Watch(notify::Error::Io(e)) if e.kind() == ErrorKind::NotFound => {
+                "Could not watch the base path, it does not exist"
+            }
+            WatchError::Watch(_) => "Could not watch the base path",
+            WatchError::WatchLimit => "The limit on watched files was reached, on Linux raise it with `sudo sysctl fs.inotify.max_user_watches=524288`",
+        }
+    }
+}
+
+/// Check if an error is caused by reaching the inotify watch limit. notify reports this either as
+/// a generic error or as the raw `ENOSPC` error.
+fn is_watch_limit(error: &notify::Error) -> bool {
+    match error {
+        notify::Error::Generic(message) => {
+            message.contains("limit on the total number of inotify watches")
+        }
+        notify::Error::Io(e) => cfg!(target_os = "linux") && e.raw_os_error() == Some(ENOSPC),
+        _ => false,
+    }
+}
+
+impl HeaderFiles {
+    /// Creates a new [`HeaderFiles`].
+    pub fn new() -> HeaderFiles {
+        HeaderFiles::default()
+    }
+
+    /// Get the headers for a file from the nearest `_headers` file, searching the file's
+    /// directory then its parents up to `root`. Returns no headers if there is no `_headers`
+    /// file.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the cache's lock is poisoned or there is an issue with the logger.
+    pub fn get_headers(&self, path: &Path, root: &Path, logger: &Logger) -> HeaderList {
+        let mut dir = path.parent();
+
+        while let Some(current) = dir.filter(|d| d.starts_with(root)) {
+            let headers_path = current.join(HEADERS_FILE_NAME);
+
+            if let Ok(modified) = fs::metadata(&headers_path).and_then(|m| m.modified()) {
+                let mut cache = self.cache.lock().unwrap();
+
+                match cache.get(&headers_path) {
+                    Some((cached, headers)) if *cached == modified => return headers.clone(),
+                    _ => {
+                        let content = fs::read_to_string(&headers_path).unwrap_or_default();
+                        let (headers, invalid) = parse_headers_file(&content);
+
+                        for line in invalid {
+                            logger
+                                .log_warning(format!(
+                                    "Ignoring invalid header in {}: {:?}",
+                                    headers_path.display(),
+                                    line
+                                ))
+                                .unwrap();
+                        }
+
+                        cache.insert(headers_path, (modified, headers.clone()));
+                        return headers;
+                    }
+                }
+            }
+
+            dir = current.parent();
+        }
+
+        Vec::new()
     }
 }
 
+/// Parse the contents of a `_headers` file into `(name, value)` pairs. Also returns the lines
+/// that are not a valid header, those without a `:` or with a name that is not a token (such as
+/// `X Frame Options: DENY`) or a value with control characters.
+pub fn parse_headers_file(content: &str) -> (HeaderList, Vec<String>) {
+    let mut headers = Vec::new();
+    let mut invalid = Vec::new();
+
+    for line in content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    {
+        match line.split_once(':') {
+            Some((name, value))
+                if is_valid_header_name(name.trim()) && is_valid_header_value(value.trim()) =>
+            {
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+            _ => invalid.push(line.to_string()),
+        }
+    }
+
+    (headers, invalid)
+}
+
 /// Convert a PathBuf to a String.
 ///
 /// # Panics
@@ -79,12 +318,212 @@ fn path_buf_to_string(path_buf: PathBuf) -> String {
     path_buf.to_str().unwrap().to_string()
 }
 
-/// Send a notification message.
-fn send_message(sender: &Sender<Notification>, notification: Notification) {
-    let r = sender.send(notification);
+/// Send a notification message for a change seen at `now`.
+///
+/// # Panics
+///
+/// Panics if there is an issue with the logger.
+fn send_message(
+    sender: &NotificationSender,
+    notification: Notification,
+    now: Instant,
+    logger: &Logger,
+) {
+    let r = sender.send_at(notification, now);
 
     match r {
         Ok(_) => {}
-        Err(e) => println!("{}", e),
+        Err(e) => {
+            logger
+                .log_warning(format!("Could not send change notification. Error: {}", e))
+                .unwrap();
+        }
+    };
+}
+
+/// Match a path against a glob pattern. A pattern without a `/` is matched against the file name
+/// (`*.scss`), otherwise it is matched against the full path (`/css/*.scss`).
+pub fn path_matches(pattern: &str, path: &str) -> bool {
+    if pattern.contains('/') {
+        glob_match(pattern, path)
+    } else {
+        Path::new(path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| glob_match(pattern, name))
+            .unwrap_or(false)
+    }
+}
+
+/// Match text against a glob pattern, where `*` matches any characters (including none) and `?`
+/// matches a single character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // The position of the last `*` and the text position it was tried at, to backtrack to.
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::ManualClock,
+        logging::{
+            common::{LogItemType, LogLevel},
+            logger::{LogMessage, LogQueue, OverflowPolicy},
+        },
+        messaging::{notification_channel, NotificationOverflow, ServerChanges},
     };
+
+    /// Wait for the watcher to send a notification for a path, returning the notifications sent
+    /// up to and including it.
+    fn wait_for(receiver: &mpsc::Receiver<Notification>, path: &Path) -> Vec<Notification> {
+        let path = path.to_string_lossy();
+        let mut received = Vec::new();
+
+        loop {
+            let notification = receiver
+                .recv_timeout(Duration::from_secs(5))
+                .expect("timed out waiting for a notification");
+            let done = notification.get_path() == Some(path.as_ref());
+
+            received.push(notification);
+
+            if done {
+                return received;
+            }
+        }
+    }
+
+    #[test]
+    fn failed_sends_are_logged_as_warnings() {
+        let queue = Arc::new(LogQueue::new(10, OverflowPolicy::DropOldest));
+        let logger = Logger::create("watcher".to_string(), queue.clone());
+        let (sender, receiver) = notification_channel(None, NotificationOverflow::Block);
+
+        drop(receiver);
+        send_message(
+            &sender,
+            Notification::FileUpdated("/a.html".to_string()),
+            Instant::now(),
+            &logger,
+        );
+
+        match queue.pop() {
+            LogMessage::Item(item) => {
+                assert!(matches!(item.item_type, LogItemType::Warning));
+                assert!(item
+                    .message
+                    .starts_with("Could not send change notification."));
+            }
+            _ => panic!("expected a log item"),
+        }
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn headers_files_skip_invalid_lines() {
+        let content = "# Security headers\n\
+                       X-Frame-Options: DENY\n\
+                       \n\
+                       Bad Name: value\n\
+                       no colon\n\
+                       Content-Security-Policy: default-src 'self'; img-src *\n";
+
+        let (headers, invalid) = parse_headers_file(content);
+
+        assert_eq!(
+            headers,
+            [
+                ("X-Frame-Options".to_string(), "DENY".to_string()),
+                (
+                    "Content-Security-Policy".to_string(),
+                    "default-src 'self'; img-src *".to_string()
+                ),
+            ]
+        );
+        assert_eq!(invalid, ["Bad Name: value", "no colon"]);
+    }
+
+    #[test]
+    fn watcher_drops_server_changes_by_its_clock() {
+        let root = std::env::temp_dir().join(format!("dev_server_watch_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+
+        let log = Log::start().unwrap();
+        log.set_level(LogLevel::Error);
+
+        let clock = ManualClock::new();
+        let changes = ServerChanges::new(Duration::from_secs(5));
+        let (sender, receiver) = notification_channel(None, NotificationOverflow::Block);
+        let watcher = FileWatcher::start_with_clock(
+            sender.with_server_changes(changes.clone()),
+            root.to_string_lossy().into_owned(),
+            Some(Duration::from_millis(50)),
+            &log,
+            Arc::new(clock.clone()),
+        )
+        .unwrap();
+
+        // The server wrote a.html itself, the watcher's notification for it is dropped.
+        let (served, other, marker) = (
+            root.join("a.html"),
+            root.join("b.html"),
+            root.join("c.html"),
+        );
+        changes.record(&served.to_string_lossy(), clock.now());
+        fs::write(&served, "a").unwrap();
+        fs::write(&other, "b").unwrap();
+
+        let mut received = wait_for(&receiver, &other);
+
+        // A later scan, so any notification for a.html has been sent by now.
+        fs::write(&marker, "c").unwrap();
+        received.extend(wait_for(&receiver, &marker));
+
+        assert!(received
+            .iter()
+            .all(|n| n.get_path() != Some(served.to_string_lossy().as_ref())));
+
+        // Once the window has passed on the watcher's clock the file's changes are sent again.
+        clock.advance(Duration::from_secs(5));
+        fs::remove_file(&served).unwrap();
+
+        let received = wait_for(&receiver, &served);
+        assert!(matches!(
+            received.last(),
+            Some(Notification::FileRemoved(_))
+        ));
+
+        watcher.stop();
+        log.stop();
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
@@ -0,0 +1,395 @@
+use std::{
+    process::{Command, Output},
+    sync::{
+        mpsc::{Receiver, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    files::path_matches,
+    logging::logger::{Log, Logger},
+    messaging::Notification,
+};
+
+/// A shell command run when a file matching a glob pattern changes, for example compiling SCSS
+/// when a `.scss` file is saved.
+#[derive(Clone)]
+pub struct BuildHook {
+    /// The glob pattern files are matched against, see [`path_matches`].
+    pub pattern: String,
+    /// The command to run, passed to the shell.
+    pub command: String,
+}
+
+/// Runs build hooks for file change notifications before passing the notifications on.
+///
+/// A notification matching a hook is only forwarded once all its hooks' commands exit
+/// successfully, so clients reload after the build has finished. Every matching hook runs even
+/// if another fails, and a failed hook only holds back the changes it matches. Notifications not
+/// matching any hook are forwarded regardless of the builds' results.
+///
+/// Changes are debounced, a burst of changes (or changes made while a build is running) runs
+/// each matching hook once. A batch is closed once it reaches its maximum age even if changes
+/// keep arriving.
+pub struct HookRunner {
+    thread: JoinHandle<()>,
+}
+
+/// Tracks when a batch of changes closes, once there have been no changes for the debounce
+/// period or the batch is at its maximum age.
+struct BatchTimer {
+    /// How long to wait for further changes before running the hooks.
+    debounce: Duration,
+    /// The longest the batch collects further changes for, so a steady stream of changes (each
+    /// within the debounce period of the last) still runs the hooks.
+    max_age: Duration,
+    /// When the first change of the batch arrived.
+    started: Instant,
+    /// When the latest change of the batch arrived.
+    last_change: Instant,
+}
+
+impl BuildHook {
+    /// Creates a new [`BuildHook`].
+    pub fn new(pattern: &str, command: &str) -> BuildHook {
+        BuildHook {
+            pattern: pattern.to_string(),
+            command: command.to_string(),
+        }
+    }
+
+    /// Check if a changed file's path matches this hook's pattern.
+    pub fn is_match(&self, path: &str) -> bool {
+        path_matches(&self.pattern, path)
+    }
+}
+
+impl HookRunner {
+    /// Start the [`HookRunner`]. The thread runs until the notification sender is dropped.
+    ///
+    /// A batch of changes closes once there have been no changes for `debounce`, or once it is
+    /// `max_batch_age` old.
+    pub fn start(
+        notifications: Receiver<Notification>,
+        sender: Sender<Notification>,
+        hooks: Vec<BuildHook>,
+        debounce: Duration,
+        max_batch_age: Duration,
+        log: &Log,
+    ) -> HookRunner {
+        HookRunner::start_with_clock(
+            notifications,
+            sender,
+            hooks,
+            debounce,
+            max_batch_age,
+            log,
+            Arc::new(SystemClock),
+        )
+    }
+
+    /// Start the [`HookRunner`], timing the batches of changes with `clock`.
+    pub fn start_with_clock(
+        notifications: Receiver<Notification>,
+        sender: Sender<Notification>,
+        hooks: Vec<BuildHook>,
+        debounce: Duration,
+        max_batch_age: Duration,
+        log: &Log,
+        clock: Arc<dyn Clock>,
+    ) -> HookRunner {
+        let logger = log.get_logger("build_hooks".to_string());
+
+        let thread = thread::spawn(move || {
+            while let Ok(notification) = notifications.recv() {
+                // Collect the changes arriving within the debounce window, this also picks up
+                // any changes made while the previous build was running so they trigger a
+                // single rerun rather than one per change.
+                let mut timer = BatchTimer::new(clock.now(), debounce, max_batch_age);
+                let mut batch = vec![notification];
+
+                while let Some(timeout) = timer.remaining(clock.now()) {
+                    match notifications.recv_timeout(timeout) {
+                        Ok(notification) => {
+                            batch.push(notification);
+                            timer.record_change(clock.now());
+                        }
+                        Err(_) => break,
+                    }
+                }
+
+                // Each matching hook runs once per batch, whatever the result of the others.
+                let failed: Vec<&BuildHook> = hooks
+                    .iter()
+                    .filter(|hook| batch.iter().any(|n| hook.is_match(get_path(n))))
+                    .filter(|hook| !run_hook(hook, &logger))
+                    .collect();
+
+                for notification in batch {
+                    let path = get_path(&notification);
+
+                    if failed.iter().any(|hook| hook.is_match(path)) {
+                        logger
+                            .log_warning(format!("Build failed, reload skipped. Path: {}", path))
+                            .unwrap();
+                        continue;
+                    }
+
+                    if sender.send(notification).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        HookRunner { thread }
+    }
+
+    /// Wait for the runner to finish, this should be called once the file watcher has stopped.
+    pub fn stop(self) {
+        let _ = self.thread.join();
+    }
+}
+
+impl BatchTimer {
+    /// Creates a new [`BatchTimer`] for a batch starting with a change at `now`.
+    fn new(now: Instant, debounce: Duration, max_age: Duration) -> BatchTimer {
+        BatchTimer {
+            debounce,
+            max_age,
+            started: now,
+            last_change: now,
+        }
+    }
+
+    /// Record a change added to the batch at `now`, restarting the debounce window.
+    fn record_change(&mut self, now: Instant) {
+        self.last_change = now;
+    }
+
+    /// Returns how long to wait for another change at `now`, `None` once the batch is closed.
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        let debounce = self
+            .debounce
+            .saturating_sub(now.saturating_duration_since(self.last_change));
+        let age = self
+            .max_age
+            .saturating_sub(now.saturating_duration_since(self.started));
+
+        Some(debounce.min(age)).filter(|remaining| !remaining.is_zero())
+    }
+}
+
+/// Run a hook's command, logging its output. Returns `true` if the command succeeded.
+fn run_hook(hook: &BuildHook, logger: &Logger) -> bool {
+    logger
+        .log_info(format!("Running build hook. Command: {}", hook.command))
+        .unwrap();
+
+    match shell_command(&hook.command).output() {
+        Ok(output) => {
+            log_output(&output, logger);
+
+            if output.status.success() {
+                logger
+                    .log_success(format!("Build hook finished. Command: {}", hook.command))
+                    .unwrap();
+                true
+            } else {
+                logger
+                    .log_error(format!(
+                        "Build hook failed. Command: {}, Status: {}",
+                        hook.command, output.status
+                    ))
+                    .unwrap();
+                false
+            }
+        }
+        Err(e) => {
+            logger
+                .log_error(format!(
+                    "Could not run build hook. Command: {}, Error: {}",
+                    hook.command, e
+                ))
+                .unwrap();
+            false
+        }
+    }
+}
+
+/// Log the lines a command wrote to stdout (as information) and stderr (as warnings).
+fn log_output(output: &Output, logger: &Logger) {
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        logger.log_info(line.to_string()).unwrap();
+    }
+
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        logger.log_warning(line.to_string()).unwrap();
+    }
+}
+
+/// Create a command running through the platform's shell.
+fn shell_command(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.args(["/C", command]);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", command]);
+        cmd
+    }
+}
+
+/// Get the path of the file a notification is for, empty for notifications not for a file.
+fn get_path(notification: &Notification) -> &str {
+    notification.get_path().unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::mpsc};
+
+    use super::*;
+    use crate::{clock::ManualClock, logging::common::LogLevel};
+
+    const DEBOUNCE: Duration = Duration::from_millis(100);
+    const MAX_BATCH_AGE: Duration = Duration::from_secs(1);
+
+    /// Run hooks for a set of changes, returning the paths forwarded.
+    fn run_batch(hooks: Vec<BuildHook>, paths: &[&str]) -> Vec<String> {
+        let log = Log::start().unwrap();
+        log.set_level(LogLevel::Error);
+
+        let (change_tx, change_rx) = mpsc::channel();
+        let (not_tx, not_rx) = mpsc::channel();
+        let runner = HookRunner::start(change_rx, not_tx, hooks, DEBOUNCE, MAX_BATCH_AGE, &log);
+
+        for path in paths {
+            change_tx
+                .send(Notification::FileUpdated(path.to_string()))
+                .unwrap();
+        }
+
+        drop(change_tx);
+        runner.stop();
+        log.stop();
+
+        not_rx
+            .try_iter()
+            .map(|n| get_path(&n).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn failed_hooks_only_hold_back_their_own_changes() {
+        let marker = std::env::temp_dir().join(format!("dev_server_hook_{}", std::process::id()));
+        let _ = fs::remove_file(&marker);
+
+        let hooks = vec![
+            BuildHook::new("*.scss", "exit 1"),
+            BuildHook::new("*.ts", &format!("touch '{}'", marker.display())),
+        ];
+
+        let forwarded = run_batch(hooks, &["/a.scss", "/b.ts", "/c.html"]);
+
+        // The second hook ran even though the first failed.
+        assert!(marker.exists());
+        assert_eq!(forwarded, ["/b.ts", "/c.html"]);
+
+        fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    fn successful_hooks_forward_their_changes() {
+        let hooks = vec![BuildHook::new("*.scss", "true")];
+
+        assert_eq!(
+            run_batch(hooks, &["/a.scss", "/b.html"]),
+            ["/a.scss", "/b.html"]
+        );
+    }
+
+    #[test]
+    fn batches_close_once_the_changes_stop() {
+        let clock = ManualClock::new();
+        let mut timer = BatchTimer::new(clock.now(), DEBOUNCE, MAX_BATCH_AGE);
+
+        assert_eq!(timer.remaining(clock.now()), Some(DEBOUNCE));
+
+        clock.advance(DEBOUNCE / 2);
+        timer.record_change(clock.now());
+        assert_eq!(timer.remaining(clock.now()), Some(DEBOUNCE));
+
+        clock.advance(DEBOUNCE - Duration::from_millis(1));
+        assert_eq!(timer.remaining(clock.now()), Some(Duration::from_millis(1)));
+
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(timer.remaining(clock.now()), None);
+    }
+
+    #[test]
+    fn a_steady_stream_of_changes_closes_at_the_maximum_age() {
+        let clock = ManualClock::new();
+        let mut timer = BatchTimer::new(clock.now(), DEBOUNCE, MAX_BATCH_AGE);
+
+        // Each change arrives within the debounce window of the last.
+        let step = DEBOUNCE / 2;
+        let steps = (MAX_BATCH_AGE.as_millis() / step.as_millis()) as u32;
+
+        for _ in 1..steps {
+            clock.advance(step);
+            timer.record_change(clock.now());
+            assert!(timer.remaining(clock.now()).is_some());
+        }
+
+        // The debounce window has time left, but the batch is as old as allowed.
+        clock.advance(step);
+        timer.record_change(clock.now());
+        assert_eq!(timer.remaining(clock.now()), None);
+    }
+
+    #[test]
+    fn runners_forward_changes_in_order() {
+        let log = Log::start().unwrap();
+        log.set_level(LogLevel::Error);
+
+        let clock = ManualClock::new();
+        let (change_tx, change_rx) = mpsc::channel();
+        let (not_tx, not_rx) = mpsc::channel();
+        let runner = HookRunner::start_with_clock(
+            change_rx,
+            not_tx,
+            Vec::new(),
+            DEBOUNCE,
+            MAX_BATCH_AGE,
+            &log,
+            Arc::new(clock.clone()),
+        );
+
+        change_tx
+            .send(Notification::FileUpdated("/a.html".to_string()))
+            .unwrap();
+
+        // Whether or not the age closes the first batch, each change is forwarded once, in order.
+        clock.advance(MAX_BATCH_AGE);
+        change_tx
+            .send(Notification::FileUpdated("/b.html".to_string()))
+            .unwrap();
+
+        let forwarded: Vec<String> = not_rx
+            .iter()
+            .take(2)
+            .map(|n| get_path(&n).to_string())
+            .collect();
+        assert_eq!(forwarded, ["/a.html", "/b.html"]);
+
+        drop(change_tx);
+        runner.stop();
+        log.stop();
+    }
+}
@@ -1,9 +1,30 @@
-﻿use crate::logging::logger::Logger;
-use std::collections::HashMap;
-use std::io::Read;
-use std::net::TcpStream;
-
-#[derive(Clone, Copy)]
+﻿use crate::http::headers::HeaderMap;
+use crate::logging::{common::LogLevel, logger::Logger};
+use chrono::{DateTime, UTC};
+use indexmap::IndexMap;
+use serde::{de::DeserializeOwned, Serialize};
+use sha1::{Digest, Sha1};
+use std::borrow::Cow;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::path::Path;
+use std::str::{self, FromStr, Utf8Error};
+use std::time::Duration;
+
+/// The default size of the buffer used to read requests and responses from a stream.
+pub const DEFAULT_BUFFER_SIZE: usize = 4096;
+/// The default largest request body (in bytes) a [`RequestReader`] will read.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// The content type used when a file's type can not be determined.
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// The default server identification, the crate name and version.
+pub const DEFAULT_SERVER_NAME: &str =
+    concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Clone, Copy, PartialEq)]
 pub enum HttpVerb {
     GET,
     HEAD,
@@ -19,23 +40,57 @@ pub enum HttpVerb {
 pub enum HttpStatus {
     SwitchingProtocols,
     Ok,
+    Created,
+    Accepted,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    NotModified,
+    TemporaryRedirect,
+    PermanentRedirect,
     BadRequest,
     Unauthorized,
+    Forbidden,
     NotFound,
     MethodNotAllowed,
+    NotAcceptable,
+    RequestTimeout,
+    Conflict,
+    ContentTooLarge,
+    RangeNotSatisfiable,
+    UnprocessableContent,
+    UpgradeRequired,
+    TooManyRequests,
     InternalError,
+    BadGateway,
+    ServiceUnavailable,
+    /// Any other status, with its code and reason phrase. The phrase may be empty, for example
+    /// for codes read with [`HttpStatus::from_code`].
+    Custom(i16, &'static str),
 }
 
 pub struct HttpRequest {
     pub header: HttpRequestHeader,
     pub body: Option<Vec<u8>>,
+    /// The address of the client that sent the request, `None` if it could not be determined
+    /// (or the request was not read from a stream).
+    pub peer_addr: Option<SocketAddr>,
 }
 
+#[derive(Clone)]
 pub struct HttpRequestHeader {
     pub route: String,
+    /// The query string of the request target (without the leading `?`), if any.
+    pub query: Option<String>,
+    /// The host from an absolute-form request target (`GET http://example.com/path HTTP/1.1`),
+    /// as sent to proxies. `None` for the usual origin-form (`GET /path HTTP/1.1`).
+    pub target_host: Option<String>,
     pub verb: HttpVerb,
     pub content_length: usize,
-    pub headers: HashMap<String, String>,
+    /// The headers in the order they were received or added, so a request serializes the
+    /// same way every time.
+    pub headers: HeaderMap,
     pub http_version: String,
 }
 
@@ -47,32 +102,40 @@ pub struct HttpResponse {
 pub struct HttpResponseHeader {
     pub http_version: String,
     pub status: HttpStatus,
+    /// A reason phrase sent in place of the status's standard one, see
+    /// [`HttpResponseHeader::set_reason`].
+    pub reason: Option<String>,
     pub content_length: usize,
     //pub content_type: String,
-    pub headers: HashMap<String, String>,
+    pub headers: HeaderMap,
 }
 
-impl HttpVerb {
-    /// Create a HttpVerb from a name.
-    ///
-    /// # Errors
-    ///
-    /// This function will return an error if the name is unknown.
-    pub fn from_str(data: &str) -> Result<HttpVerb, &'static str> {
-        match data.to_uppercase().as_str() {
-            "GET" => Ok(HttpVerb::GET),
-            "HEAD" => Ok(HttpVerb::HEAD),
-            "POST" => Ok(HttpVerb::POST),
-            "PUT" => Ok(HttpVerb::PUT),
-            "DELETE" => Ok(HttpVerb::DELETE),
-            "CONNECT" => Ok(HttpVerb::CONNECT),
-            "PATCH" => Ok(HttpVerb::PATCH),
-            "OPTIONS" => Ok(HttpVerb::OPTIONS),
-            "TRACE" => Ok(HttpVerb::TRACE),
-            _ => Err("Unknown http verb"),
-        }
-    }
+/// Errors returned when a request can not be read from a stream.
+#[derive(Debug)]
+pub enum RequestError {
+    /// The request was malformed or could not be read.
+    Malformed(&'static str),
+    /// The client did not send the request before the read timeout.
+    Timeout,
+    /// The client closed the connection without sending a request, no response is needed.
+    Closed,
+    /// The request body is larger than the reader's maximum body size.
+    TooLarge,
+}
+
+/// Reads requests from a stream, so several requests can be read from one connection.
+///
+/// Bytes read past the end of a request (the start of a pipelined request sent before the
+/// response was received) are kept for the next request.
+pub struct RequestReader {
+    buffer: Vec<u8>,
+    read: usize,
+    served: usize,
+    in_sync: bool,
+    max_body_size: usize,
+}
 
+impl HttpVerb {
     /// Returns a reference to the name of this [`HttpVerb`].
     pub fn get_str(&self) -> &'static str {
         match self {
@@ -89,22 +152,65 @@ impl HttpVerb {
     }
 }
 
+impl FromStr for HttpVerb {
+    type Err = &'static str;
+
+    /// Parse a HttpVerb from a name, ignoring case.
+    fn from_str(data: &str) -> Result<HttpVerb, &'static str> {
+        match data.to_uppercase().as_str() {
+            "GET" => Ok(HttpVerb::GET),
+            "HEAD" => Ok(HttpVerb::HEAD),
+            "POST" => Ok(HttpVerb::POST),
+            "PUT" => Ok(HttpVerb::PUT),
+            "DELETE" => Ok(HttpVerb::DELETE),
+            "CONNECT" => Ok(HttpVerb::CONNECT),
+            "PATCH" => Ok(HttpVerb::PATCH),
+            "OPTIONS" => Ok(HttpVerb::OPTIONS),
+            "TRACE" => Ok(HttpVerb::TRACE),
+            _ => Err("Unknown http verb"),
+        }
+    }
+}
+
 impl HttpStatus {
-    /// Create a HttpStatus from a status code.
+    /// Create a HttpStatus from a status code. Codes without a variant of their own give a
+    /// [`HttpStatus::Custom`] with an empty reason phrase.
     ///
     /// # Errors
     ///
-    /// This function will return an error if the status code is unknown.
+    /// This function will return an error if the status code is outside the valid range
+    /// (100 to 599).
     pub fn from_code(code: i16) -> Result<HttpStatus, &'static str> {
         match code {
             101 => Ok(HttpStatus::SwitchingProtocols),
             200 => Ok(HttpStatus::Ok),
+            201 => Ok(HttpStatus::Created),
+            202 => Ok(HttpStatus::Accepted),
+            204 => Ok(HttpStatus::NoContent),
+            206 => Ok(HttpStatus::PartialContent),
+            301 => Ok(HttpStatus::MovedPermanently),
+            302 => Ok(HttpStatus::Found),
+            304 => Ok(HttpStatus::NotModified),
+            307 => Ok(HttpStatus::TemporaryRedirect),
+            308 => Ok(HttpStatus::PermanentRedirect),
             400 => Ok(HttpStatus::BadRequest),
             401 => Ok(HttpStatus::Unauthorized),
+            403 => Ok(HttpStatus::Forbidden),
             404 => Ok(HttpStatus::NotFound),
             405 => Ok(HttpStatus::MethodNotAllowed),
+            406 => Ok(HttpStatus::NotAcceptable),
+            408 => Ok(HttpStatus::RequestTimeout),
+            409 => Ok(HttpStatus::Conflict),
+            413 => Ok(HttpStatus::ContentTooLarge),
+            416 => Ok(HttpStatus::RangeNotSatisfiable),
+            422 => Ok(HttpStatus::UnprocessableContent),
+            426 => Ok(HttpStatus::UpgradeRequired),
+            429 => Ok(HttpStatus::TooManyRequests),
             500 => Ok(HttpStatus::InternalError),
-            _ => Err("Unknown response type code"),
+            502 => Ok(HttpStatus::BadGateway),
+            503 => Ok(HttpStatus::ServiceUnavailable),
+            100..=599 => Ok(HttpStatus::Custom(code, "")),
+            _ => Err("Status code out of range"),
         }
     }
 
@@ -113,24 +219,96 @@ impl HttpStatus {
         match self {
             HttpStatus::SwitchingProtocols => 101,
             HttpStatus::Ok => 200,
+            HttpStatus::Created => 201,
+            HttpStatus::Accepted => 202,
+            HttpStatus::NoContent => 204,
+            HttpStatus::PartialContent => 206,
+            HttpStatus::MovedPermanently => 301,
+            HttpStatus::Found => 302,
+            HttpStatus::NotModified => 304,
+            HttpStatus::TemporaryRedirect => 307,
+            HttpStatus::PermanentRedirect => 308,
             HttpStatus::BadRequest => 400,
             HttpStatus::Unauthorized => 401,
+            HttpStatus::Forbidden => 403,
             HttpStatus::NotFound => 404,
             HttpStatus::MethodNotAllowed => 405,
+            HttpStatus::NotAcceptable => 406,
+            HttpStatus::RequestTimeout => 408,
+            HttpStatus::Conflict => 409,
+            HttpStatus::ContentTooLarge => 413,
+            HttpStatus::RangeNotSatisfiable => 416,
+            HttpStatus::UnprocessableContent => 422,
+            HttpStatus::UpgradeRequired => 426,
+            HttpStatus::TooManyRequests => 429,
             HttpStatus::InternalError => 500,
+            HttpStatus::BadGateway => 502,
+            HttpStatus::ServiceUnavailable => 503,
+            HttpStatus::Custom(code, _) => *code,
         }
     }
 
+    /// Returns true if a response with this [`HttpStatus`] can carry a body.
+    /// Informational (1xx), 204 and 304 responses can not.
+    pub fn allows_body(&self) -> bool {
+        let code = self.get_code();
+
+        !(100..200).contains(&code) && code != 204 && code != 304
+    }
+
     /// Returns a reference to the name of this [`HttpStatus`].
     pub fn get_str(&self) -> &'static str {
         match self {
             HttpStatus::SwitchingProtocols => "Switching Protocols",
             HttpStatus::Ok => "OK",
+            HttpStatus::Created => "Created",
+            HttpStatus::Accepted => "Accepted",
+            HttpStatus::NoContent => "No Content",
+            HttpStatus::PartialContent => "Partial Content",
+            HttpStatus::MovedPermanently => "Moved Permanently",
+            HttpStatus::Found => "Found",
+            HttpStatus::NotModified => "Not Modified",
+            HttpStatus::TemporaryRedirect => "Temporary Redirect",
+            HttpStatus::PermanentRedirect => "Permanent Redirect",
             HttpStatus::BadRequest => "Bad Request",
             HttpStatus::Unauthorized => "Unauthorized",
+            HttpStatus::Forbidden => "Forbidden",
             HttpStatus::NotFound => "Not Found",
             HttpStatus::MethodNotAllowed => "Method Not Allowed",
-            HttpStatus::InternalError => "Internal Error",
+            HttpStatus::NotAcceptable => "Not Acceptable",
+            HttpStatus::RequestTimeout => "Request Timeout",
+            HttpStatus::Conflict => "Conflict",
+            HttpStatus::ContentTooLarge => "Content Too Large",
+            HttpStatus::RangeNotSatisfiable => "Range Not Satisfiable",
+            HttpStatus::UnprocessableContent => "Unprocessable Content",
+            HttpStatus::UpgradeRequired => "Upgrade Required",
+            HttpStatus::TooManyRequests => "Too Many Requests",
+            HttpStatus::InternalError => "Internal Server Error",
+            HttpStatus::BadGateway => "Bad Gateway",
+            HttpStatus::ServiceUnavailable => "Service Unavailable",
+            HttpStatus::Custom(_, reason) => reason,
+        }
+    }
+}
+
+impl RequestError {
+    /// Returns the response status for this [`RequestError`].
+    pub fn get_status(&self) -> HttpStatus {
+        match self {
+            RequestError::Malformed(_) => HttpStatus::BadRequest,
+            RequestError::Timeout => HttpStatus::RequestTimeout,
+            RequestError::Closed => HttpStatus::BadRequest,
+            RequestError::TooLarge => HttpStatus::ContentTooLarge,
+        }
+    }
+
+    /// Returns a reference to the message of this [`RequestError`].
+    pub fn get_str(&self) -> &'static str {
+        match self {
+            RequestError::Malformed(message) => message,
+            RequestError::Timeout => "Timed out waiting for request",
+            RequestError::Closed => "Connection closed",
+            RequestError::TooLarge => "Request body too large",
         }
     }
 }
@@ -141,7 +319,7 @@ impl HttpRequest {
         route: String,
         verb: HttpVerb,
         content_type: String,
-        addition_headers: HashMap<String, String>,
+        addition_headers: IndexMap<String, String>,
         body: Option<Vec<u8>>,
     ) -> HttpRequest {
         let len = match &body {
@@ -152,54 +330,89 @@ impl HttpRequest {
         HttpRequest {
             header: HttpRequestHeader::create(route, verb, content_type, addition_headers, len),
             body,
+            peer_addr: None,
         }
     }
 
     /// Create a HttpRequest from a TcpStream.
     ///
+    /// The stream's read timeout is set to `timeout` (`None` blocks indefinitely) before reading.
+    /// Use a [`RequestReader`] to read several requests from the same stream.
+    ///
     /// # Panics
     ///
-    /// Panics if the stream can not be read or there is an issue with the logger.
+    /// Panics if there is an issue with the logger.
     ///
     /// # Errors
     ///
-    /// Does not currently error, however it should error instead of panic.
+    /// This function will return an error if the client does not send the request before the
+    /// timeout, the stream can not be read or the request is malformed.
     pub fn from_stream(
-        mut stream: &TcpStream,
+        stream: &TcpStream,
         logger: &Logger,
-    ) -> Result<HttpRequest, &'static str> {
-        let mut buffer = [0; 4096];
-        let mut body: Vec<u8> = Vec::new();
-        logger
-            .log_debug(format!("Parsing http request header."))
-            .unwrap();
-        stream.read(&mut buffer).unwrap();
-        logger.log_debug(format!("Read to buffer.")).unwrap();
-        let (header, body_start_index) = HttpRequestHeader::create_from_buffer(buffer)?;
-        let body = match (
-            header.content_length > 0,
-            body_start_index + header.content_length as usize > 4096,
-        ) {
-            // Short cut -> content length is 0 so no body
-            (false, _) => None,
-            // If the body_start_index + content length
-            // the request of the body is bigger than the buffer and more reads needed
-            (true, true) => {
-                // TODO handle!
-                None
-            }
-            // If the body_start_index + content length < 2048,
-            // the body is in the initial buffer and no more reading is needed.
-            (true, false) => {
-                let end = body_start_index + header.content_length as usize;
+        timeout: Option<Duration>,
+        buffer_size: usize,
+    ) -> Result<HttpRequest, RequestError> {
+        RequestReader::new(buffer_size).next_request(stream, logger, timeout)
+    }
 
-                let body = buffer[body_start_index..end].to_vec();
+    /// Returns the IP address of the client that made the request.
+    ///
+    /// When `trust_forwarded` is set (the server is behind a trusted proxy) the first address in
+    /// the `X-Forwarded-For` header is used, falling back to the peer address if the header is
+    /// missing or invalid.
+    pub fn client_ip(&self, trust_forwarded: bool) -> Option<IpAddr> {
+        let forwarded = self
+            .header
+            .headers
+            .get("X-FORWARDED-FOR")
+            .filter(|_| trust_forwarded)
+            .and_then(|value| value.split(',').next())
+            .and_then(|ip| ip.trim().parse::<IpAddr>().ok());
+
+        forwarded.or_else(|| self.peer_addr.map(|addr| addr.ip()))
+    }
 
-                Some(body)
-            }
-        };
+    /// Returns the body of this [`HttpRequest`] as a string, invalid UTF-8 sequences are replaced
+    /// with `U+FFFD`. Returns `None` if there is no body.
+    pub fn body_str(&self) -> Option<Cow<'_, str>> {
+        self.body.as_ref().map(|body| String::from_utf8_lossy(body))
+    }
 
-        Ok(HttpRequest { header, body })
+    /// Returns the body of this [`HttpRequest`] as a string, an empty string if there is no body.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the body is not valid UTF-8.
+    pub fn body_str_strict(&self) -> Result<&str, Utf8Error> {
+        match &self.body {
+            Some(body) => str::from_utf8(body),
+            None => Ok(""),
+        }
+    }
+
+    /// Deserialize the JSON body of this [`HttpRequest`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the content type is not `application/json`, there
+    /// is no body or the body can not be deserialized.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, &'static str> {
+        let is_json = self
+            .header
+            .headers
+            .get("CONTENT-TYPE")
+            .map(|v| v.trim().starts_with("application/json"))
+            .unwrap_or(false);
+
+        if !is_json {
+            return Err("Request content type is not application/json");
+        }
+
+        match &self.body {
+            Some(body) => serde_json::from_slice(body).map_err(|_| "Invalid JSON body"),
+            None => Err("Request has no body"),
+        }
     }
 
     /// Get the bytes of this [`HttpRequest`].
@@ -217,32 +430,207 @@ impl HttpRequest {
     }
 }
 
+impl RequestReader {
+    /// Creates a new [`RequestReader`] with a buffer of `buffer_size` bytes, headers must fit
+    /// within it.
+    pub fn new(buffer_size: usize) -> RequestReader {
+        RequestReader {
+            buffer: vec![0; buffer_size],
+            read: 0,
+            served: 0,
+            in_sync: true,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+
+    /// Sets the largest request body (in bytes) this [`RequestReader`] will read, requests with a
+    /// bigger `Content-Length` are rejected before any of the body is read.
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> RequestReader {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Read the next request from a stream.
+    ///
+    /// The stream's read timeout is set to `timeout` (`None` blocks indefinitely) before reading.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is an issue with the logger.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the client does not send the request before the
+    /// timeout, the stream can not be read or the request is malformed. If the client closes the
+    /// connection (or is idle past the timeout after an earlier request) before sending any of
+    /// the request [`RequestError::Closed`] is returned. A request with a body bigger than the
+    /// maximum body size gives [`RequestError::TooLarge`].
+    pub fn next_request(
+        &mut self,
+        stream: &TcpStream,
+        logger: &Logger,
+        timeout: Option<Duration>,
+    ) -> Result<HttpRequest, RequestError> {
+        logger
+            .log_debug("Parsing http request header.".to_string())
+            .unwrap();
+
+        if stream.set_read_timeout(timeout).is_err() {
+            return Err(RequestError::Malformed("Could not set read timeout"));
+        }
+
+        // Keep reading until the end of the header has been received (or the buffer is full).
+        while self.read < self.buffer.len() && !contains_header_end(&self.buffer[0..self.read]) {
+            self.read += self.read_more(stream)?;
+        }
+
+        logger.log_debug("Read to buffer.".to_string()).unwrap();
+
+        if logger.is_enabled(LogLevel::Trace) {
+            let end = find_header_end(&self.buffer[0..self.read]).unwrap_or(self.read);
+
+            logger
+                .log_trace(format!(
+                    "Raw request header:\n{}",
+                    String::from_utf8_lossy(&self.buffer[0..end])
+                ))
+                .unwrap();
+        }
+
+        // Nothing after a bad request can be trusted.
+        self.in_sync = false;
+
+        let (header, body_start_index) =
+            HttpRequestHeader::create_from_buffer(&self.buffer[0..self.read])
+                .map_err(RequestError::Malformed)?;
+
+        // Checked before anything is allocated for the body, the length is whatever the client
+        // claims.
+        if header.content_length > self.max_body_size {
+            return Err(RequestError::TooLarge);
+        }
+
+        let end = body_start_index + header.content_length;
+
+        let body = if header.content_length == 0 {
+            None
+        } else if end <= self.buffer.len() {
+            // The rest of the body might not have arrived with the header.
+            while self.read < end {
+                self.read += read_to_buffer(stream, &mut self.buffer[self.read..])
+                    .map_err(short_body_error)?;
+            }
+
+            Some(self.buffer[body_start_index..end].to_vec())
+        } else {
+            // The body is bigger than the buffer, read the rest of it straight from the stream.
+            let mut body = vec![0; header.content_length];
+            let mut filled = self.read - body_start_index;
+
+            body[..filled].copy_from_slice(&self.buffer[body_start_index..self.read]);
+
+            while filled < body.len() {
+                filled += read_to_buffer(stream, &mut body[filled..]).map_err(short_body_error)?;
+            }
+
+            Some(body)
+        };
+
+        if let Some(body) = &body {
+            logger
+                .log_debug(format!("Read request body. Bytes: {}", body.len()))
+                .unwrap();
+        }
+
+        // Keep anything after this request (the start of a pipelined request) for the next read.
+        let consumed = end.min(self.read);
+
+        self.buffer.copy_within(consumed..self.read, 0);
+        self.read -= consumed;
+        self.in_sync = true;
+
+        self.served += 1;
+
+        // The request can still be handled without the peer address.
+        let peer_addr = match stream.peer_addr() {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                logger
+                    .log_warning(format!("Could not get peer address. Error: {}", e))
+                    .unwrap();
+                None
+            }
+        };
+
+        Ok(HttpRequest {
+            header,
+            body,
+            peer_addr,
+        })
+    }
+
+    /// Returns the number of requests read by this [`RequestReader`].
+    pub fn get_served(&self) -> usize {
+        self.served
+    }
+
+    /// Returns `true` if the last request was read completely, so the next request starts where
+    /// expected. Reading more requests is not possible otherwise, for example after a bad request
+    /// or a body shorter than its `Content-Length`.
+    pub fn is_in_sync(&self) -> bool {
+        self.in_sync
+    }
+
+    /// Read more of the request into the buffer, returning the number of bytes read.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the read times out, fails or the stream is closed.
+    fn read_more(&mut self, stream: &TcpStream) -> Result<usize, RequestError> {
+        match read_to_buffer(stream, &mut self.buffer[self.read..]) {
+            Ok(n) => Ok(n),
+            // The stream closed part way through the request.
+            Err(RequestError::Closed) if self.read > 0 => Err(RequestError::Malformed(
+                "Connection closed before request was received",
+            )),
+            // An idle connection that has already been used timing out is closed.
+            Err(RequestError::Timeout) if self.read == 0 && self.served > 0 => {
+                Err(RequestError::Closed)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
 impl HttpRequestHeader {
     /// Create a new HttpRequestHeader.
     pub fn create(
         route: String,
         verb: HttpVerb,
         content_type: String,
-        addition_headers: HashMap<String, String>,
+        addition_headers: IndexMap<String, String>,
         content_length: usize,
     ) -> HttpRequestHeader {
         let http_version = String::from("HTTP/1.1");
 
         // Map the headers.
-        let mut headers: HashMap<String, String> = HashMap::new();
+        let mut headers = HeaderMap::new();
 
         // Add any standardized headers.
-        headers.insert("Server".to_string(), "Psionic 0.0.1".to_string());
         headers.insert("Content-Length".to_string(), format!("{}", content_length));
-        headers.insert("Connection".to_string(), "Closed".to_string());
+        headers.insert("Connection".to_string(), "close".to_string());
         headers.insert("Content-Type".to_string(), content_type);
 
         for (k, v) in addition_headers {
             headers.insert(k, v);
         }
 
+        let (route, query) = split_target(&route);
+
         HttpRequestHeader {
             route,
+            query,
+            target_host: None,
             verb,
             content_length,
             headers,
@@ -255,9 +643,7 @@ impl HttpRequestHeader {
     /// # Errors
     ///
     /// This function will return an error if the request header is larger than the buffer.
-    pub fn create_from_buffer(
-        buffer: [u8; 4096],
-    ) -> Result<(HttpRequestHeader, usize), &'static str> {
+    pub fn create_from_buffer(buffer: &[u8]) -> Result<(HttpRequestHeader, usize), &'static str> {
         for i in 0..buffer.len() {
             if i > 4
                 && buffer[i] == 10
@@ -266,7 +652,7 @@ impl HttpRequestHeader {
                 && buffer[i - 3] == 13
             {
                 // \r\n\r\n found, after this its the body.
-                let header = String::from_utf8_lossy(&buffer[0..i]).into_owned();
+                let header = String::from_utf8_lossy(&buffer[0..=i]).into_owned();
 
                 //println!("{}", header);
 
@@ -279,46 +665,46 @@ impl HttpRequestHeader {
         Err("Request header larger than buffer")
     }
 
+    /// Returns the verb the request should be routed as. A POST with an
+    /// `X-HTTP-Method-Override` header naming a known verb is treated as that verb, so html forms
+    /// can test other methods. The request's own verb is left unchanged.
+    pub fn get_effective_verb(&self) -> HttpVerb {
+        if self.verb != HttpVerb::POST {
+            return self.verb;
+        }
+
+        self.headers
+            .get("X-HTTP-METHOD-OVERRIDE")
+            .and_then(|verb| verb.trim().parse().ok())
+            .unwrap_or(self.verb)
+    }
+
     /// Parse a HttpRequestHeader from a string.
     ///
     /// # Errors
     ///
-    /// This function will not return an error if the HttpVerb can not be created.
+    /// This function will return an error if the request line is not a verb, target and version
+    /// separated by single spaces, the HttpVerb can not be created or a header is invalid.
     pub fn parse_from_string(data: String) -> Result<HttpRequestHeader, &'static str> {
         let split_header: Vec<&str> = data.split("\r\n").collect();
 
-        let mut headers = HashMap::new();
-
-        let mut content_length: usize = 0;
-
         let split_status_line: Vec<&str> = split_header[0].split(" ").collect();
 
-        let verb = HttpVerb::from_str(split_status_line[0])?;
-        let route = String::from(split_status_line[1]);
-        let http_version = String::from(split_status_line[2]);
-
-        for i in 1..split_header.len() {
-            let split_item: Vec<&str> = split_header[i].split(": ").collect();
-
-            // If the split item has more than 1 item, add a header.
-            if split_item.len() > 1 {
-                let k = String::from(split_item[0]).to_uppercase();
-                let v = String::from(split_item[1]);
+        if split_status_line.len() != 3 || split_status_line[1].is_empty() {
+            return Err("Malformed request line");
+        }
 
-                // If the header item is `Content-Length` set it as such.
-                if k == "CONTENT-LENGTH" {
-                    match v.parse::<usize>() {
-                        Ok(i) => content_length = i,
-                        Err(_) => {}
-                    }
-                }
+        let verb: HttpVerb = split_status_line[0].parse()?;
+        let (target_host, target) = split_absolute_target(split_status_line[1]);
+        let (route, query) = split_target(target);
+        let http_version = String::from(split_status_line[2]);
 
-                headers.insert(k, v);
-            }
-        }
+        let (headers, content_length) = parse_header_lines(&split_header[1..])?;
 
         Ok(HttpRequestHeader {
             route,
+            query,
+            target_host,
             verb,
             content_length,
             headers,
@@ -326,22 +712,61 @@ impl HttpRequestHeader {
         })
     }
 
+    /// Check if the client wants the connection kept open after this request. HTTP/1.1
+    /// connections are kept open unless the client sends `Connection: close`, older versions only
+    /// if the client sends `Connection: keep-alive`.
+    pub fn is_keep_alive(&self) -> bool {
+        let has_option = |option: &str| {
+            self.headers
+                .get("CONNECTION")
+                .map(|v| v.split(',').any(|o| o.trim().eq_ignore_ascii_case(option)))
+                .unwrap_or(false)
+        };
+
+        if has_option("close") {
+            false
+        } else {
+            has_option("keep-alive") || self.http_version == "HTTP/1.1"
+        }
+    }
+
+    /// Returns the value of a query string parameter, if present.
+    /// A parameter without a value (`?name`) returns an empty string.
+    pub fn get_query_param(&self, name: &str) -> Option<&str> {
+        self.query.as_ref().and_then(|query| {
+            query.split('&').find_map(|pair| {
+                let mut split = pair.splitn(2, '=');
+
+                match split.next() {
+                    Some(k) if k == name => Some(split.next().unwrap_or("")),
+                    _ => None,
+                }
+            })
+        })
+    }
+
     /// Returns the string of this [`HttpRequestHeader`].
     pub fn get_string(&self) -> String {
         let mut header_string = String::new();
 
-        header_string.push_str(&self.verb.get_str());
+        header_string.push_str(self.verb.get_str());
         header_string.push(' ');
         header_string.push_str(&self.route);
+
+        if let Some(query) = &self.query {
+            header_string.push('?');
+            header_string.push_str(query);
+        }
+
         header_string.push(' ');
         header_string.push_str(&self.http_version);
 
         header_string.push_str("\r\n");
 
         for header in &self.headers {
-            header_string.push_str(&header.0);
+            header_string.push_str(header.0);
             header_string.push_str(": ");
-            header_string.push_str(&header.1);
+            header_string.push_str(header.1);
             header_string.push_str("\r\n");
         }
 
@@ -351,18 +776,17 @@ impl HttpRequestHeader {
 
     /// Get the bytes of this [`HttpRequestHeader`].
     pub fn to_bytes(&mut self) -> Vec<u8> {
-        let mut bytes = Vec::from(self.get_string().as_bytes());
-
-        bytes
+        Vec::from(self.get_string().as_bytes())
     }
 }
 
 impl HttpResponse {
-    /// Create a new HttpResponse.
+    /// Create a new HttpResponse. The addition headers are sent in order, after the standard
+    /// headers.
     pub fn create(
         status: HttpStatus,
         content_type: String,
-        addition_headers: HashMap<String, String>,
+        addition_headers: IndexMap<String, String>,
         body: Option<Vec<u8>>,
     ) -> HttpResponse {
         let len = match &body {
@@ -376,48 +800,142 @@ impl HttpResponse {
         }
     }
 
-    /// Create a new HttpResponse from a TcpStream.
+    /// Create a 200 response with the contents of a file.
     ///
-    /// # Panics
+    /// The content type is based on the extension of `base_for_type` (usually the route), if the
+    /// extension is unknown the file's contents are sniffed instead.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file can not be read, with
+    /// [`ErrorKind::NotFound`] if it does not exist.
+    pub fn from_file(path: &Path, base_for_type: &str) -> io::Result<HttpResponse> {
+        Ok(HttpResponse::from_file_contents(
+            fs::read(path)?,
+            base_for_type,
+        ))
+    }
+
+    /// Create a 200 response with a file's contents (for example from a cache) as the body, the
+    /// content type is found the same way as [`HttpResponse::from_file`].
+    pub fn from_file_contents(body: Vec<u8>, base_for_type: &str) -> HttpResponse {
+        let content_type = match get_content_type(base_for_type) {
+            default if default == DEFAULT_CONTENT_TYPE => sniff_content_type(&body)
+                .map(|t| t.to_string())
+                .unwrap_or(default),
+            content_type => content_type,
+        };
+
+        HttpResponse::create(HttpStatus::Ok, content_type, IndexMap::new(), Some(body))
+    }
+
+    /// Create a plain text error response with a message as the body.
+    pub fn create_error(status: HttpStatus, message: &str) -> HttpResponse {
+        HttpResponse::create(
+            status,
+            "text/plain".to_string(),
+            IndexMap::new(),
+            Some(message.as_bytes().to_vec()),
+        )
+    }
+
+    /// Create a response with a value serialized as the JSON body.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the value can not be serialized.
+    pub fn json<T: Serialize>(status: HttpStatus, value: &T) -> Result<HttpResponse, &'static str> {
+        match serde_json::to_vec(value) {
+            Ok(body) => Ok(HttpResponse::create(
+                status,
+                "application/json".to_string(),
+                IndexMap::new(),
+                Some(body),
+            )),
+            Err(_) => Err("Could not serialize JSON body"),
+        }
+    }
+
+    /// Create a new HttpResponse from a stream, such as a TcpStream.
     ///
-    /// Panics if the stream can not be read.
+    /// The body is read in full, however many reads it takes, either `Content-Length` bytes or
+    /// the chunks of a `Transfer-Encoding: chunked` body. A chunked body is decoded, the response
+    /// gets a `Content-Length` in place of the `Transfer-Encoding` header. Without either, a
+    /// response closing the connection (`Connection: close` or HTTP/1.0) has a body ending when
+    /// the stream does, it is read until then and given a `Content-Length`.
     ///
     /// # Errors
     ///
-    /// This function will return an error if the HttpResponseHeader can not be created.
-    pub fn from_stream(
-        mut stream: &TcpStream, /*, logger: &Logger*/
+    /// This function will return an error if the stream can not be read, it closes before the
+    /// whole response is received or the HttpResponseHeader can not be created.
+    pub fn from_stream<R: Read>(
+        mut stream: R,
+        buffer_size: usize,
     ) -> Result<HttpResponse, &'static str> {
-        let mut buffer = [0; 4096];
-        let mut body: Vec<u8> = Vec::new();
-        //logger.log_debug( format!("Parsing http response header.")).unwrap();
-        let read = stream.read(&mut buffer).unwrap();
-        //logger.log_debug(format!("Read to buffer.")).unwrap();
-        let (header, body_start_index) = HttpResponseHeader::create_from_buffer(buffer)?;
-        let body = match (
-            header.content_length > 0,
-            body_start_index + header.content_length as usize > 4096,
-        ) {
-            // Short cut -> content length is 0 so no body
-            (false, _) => None,
-            // If the body_start_index + content length
-            // the request of the body is bigger than the buffer and more reads needed
-            (true, true) => {
-                // TODO handle!
-                None
+        let mut buffer = vec![0; buffer_size];
+        let mut read = 0;
+
+        // Keep reading until the end of the header has been received (or the buffer is full).
+        while read < buffer.len() && !contains_header_end(&buffer[0..read]) {
+            read += read_response(&mut stream, &mut buffer[read..])
+                .map_err(|_| "Could not read response header")?;
+        }
+
+        let (mut header, body_start_index) =
+            HttpResponseHeader::create_from_buffer(&buffer[0..read])?;
+
+        // Whatever arrived with the header is the start of the body.
+        let received = buffer[body_start_index..read].to_vec();
+
+        let chunked = header
+            .headers
+            .get("TRANSFER-ENCODING")
+            .is_some_and(|encoding| encoding.to_lowercase().contains("chunked"));
+
+        if !header.status.allows_body() {
+            return Ok(HttpResponse { header, body: None });
+        }
+
+        if chunked {
+            let body = read_chunked_body(&mut stream, received)?;
+
+            header.headers.remove("TRANSFER-ENCODING");
+
+            let mut response = HttpResponse { header, body: None };
+            response.set_body(body);
+            return Ok(response);
+        }
+
+        if !header.headers.contains_key("CONTENT-LENGTH") && header.closes_connection() {
+            let mut body = received;
+
+            stream
+                .read_to_end(&mut body)
+                .map_err(|_| "Could not read response body")?;
+
+            let mut response = HttpResponse { header, body: None };
+
+            if !body.is_empty() {
+                response.set_body(body);
             }
-            // If the body_start_index + content length < 2048,
-            // the body is in the initial buffer and no more reading is needed.
-            (true, false) => {
-                if read == body_start_index {
-                    // Only head was send (might be general.
-                    // Therefore clear the array
-                    buffer.fill(0);
-                    stream.read(&mut buffer).unwrap();
-                    body = buffer[0..header.content_length].to_vec();
-                } else {
-                    let end = body_start_index + header.content_length as usize;
-                    body = buffer[body_start_index..end].to_vec();
+
+            return Ok(response);
+        }
+
+        let body = match header.content_length {
+            // Short cut -> content length is 0 so no body
+            0 => None,
+            content_length => {
+                let mut body = received;
+                body.truncate(content_length);
+
+                // The rest of the body might not have arrived with the header.
+                let mut filled = body.len();
+                body.resize(content_length, 0);
+
+                while filled < content_length {
+                    filled += read_response(&mut stream, &mut body[filled..])
+                        .map_err(|_| "Response body shorter than Content-Length")?;
                 }
 
                 Some(body)
@@ -427,8 +945,37 @@ impl HttpResponse {
         Ok(HttpResponse { header, body })
     }
 
+    /// Replace the body of this [`HttpResponse`], updating `Content-Length` to match. Use this
+    /// rather than setting `body` directly when transforming a body (for example injecting a
+    /// script or compressing it).
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.header.content_length = body.len();
+
+        if self.header.status.allows_body() {
+            self.header
+                .headers
+                .insert("Content-Length".to_string(), body.len().to_string());
+        }
+
+        self.body = Some(body);
+    }
+
     /// Returns the bytes of this [`HttpResponse`].
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if the `Content-Length` header does not match the body length.
     pub fn to_bytes(&mut self) -> Vec<u8> {
+        debug_assert!(
+            self.header
+                .headers
+                .get("Content-Length")
+                .is_none_or(|length| {
+                    *length == self.body.as_ref().map_or(0, |b| b.len()).to_string()
+                }),
+            "Content-Length does not match the response body"
+        );
+
         // Get the bytes for the header and append the response body.
         let mut bytes = self.header.to_bytes();
 
@@ -440,6 +987,12 @@ impl HttpResponse {
 
         bytes
     }
+
+    /// Returns the bytes of this [`HttpResponse`] without the body, as sent for a HEAD request.
+    /// The headers (including `Content-Length`) are the same as for a GET.
+    pub fn to_head_bytes(&mut self) -> Vec<u8> {
+        self.header.to_bytes()
+    }
 }
 
 impl HttpResponseHeader {
@@ -447,19 +1000,24 @@ impl HttpResponseHeader {
     pub fn create(
         status: HttpStatus,
         content_type: String,
-        addition_headers: HashMap<String, String>,
+        addition_headers: IndexMap<String, String>,
         content_length: usize,
     ) -> HttpResponseHeader {
         let http_version = String::from("HTTP/1.1");
 
         // Map the headers.
-        let mut headers: HashMap<String, String> = HashMap::new();
+        let mut headers = HeaderMap::new();
 
         // Add any standardized headers.
-        headers.insert("Server".to_string(), "Psionic 0.0.1".to_string());
-        headers.insert("Content-Length".to_string(), format!("{}", content_length));
-        headers.insert("Connection".to_string(), "Closed".to_string());
-        headers.insert("Content-Type".to_string(), content_type);
+        headers.insert("Server".to_string(), DEFAULT_SERVER_NAME.to_string());
+        headers.insert("Connection".to_string(), "close".to_string());
+        headers.insert("Date".to_string(), format_http_date(UTC::now()));
+
+        // Responses that can not carry a body should not describe one.
+        if status.allows_body() {
+            headers.insert("Content-Length".to_string(), format!("{}", content_length));
+            headers.insert("Content-Type".to_string(), content_type);
+        }
 
         for (k, v) in addition_headers {
             headers.insert(k, v);
@@ -468,19 +1026,70 @@ impl HttpResponseHeader {
         HttpResponseHeader {
             http_version,
             status,
+            reason: None,
             content_length,
             headers,
         }
     }
 
+    /// Set the `Server` header of this [`HttpResponseHeader`], `None` removes it.
+    pub fn set_server(&mut self, name: Option<&str>) {
+        match name {
+            Some(name) => {
+                self.headers.insert("Server".to_string(), name.to_string());
+            }
+            None => {
+                self.headers.remove("Server");
+            }
+        }
+    }
+
+    /// Returns `true` if the connection closes after this response, it has a `Connection: close`
+    /// header or is HTTP/1.0 without `Connection: keep-alive`.
+    pub fn closes_connection(&self) -> bool {
+        match self.headers.get("CONNECTION") {
+            Some(connection) if connection.eq_ignore_ascii_case("close") => true,
+            Some(connection) if connection.eq_ignore_ascii_case("keep-alive") => false,
+            _ => self.http_version == "HTTP/1.0",
+        }
+    }
+
+    /// Set the reason phrase of this [`HttpResponseHeader`], for testing how clients handle
+    /// unusual phrases. `None` restores the status's standard phrase. Line breaks are removed so
+    /// the phrase can not end the status line.
+    pub fn set_reason(&mut self, reason: Option<&str>) {
+        self.reason = reason.map(|reason| reason.replace(['\r', '\n'], ""));
+    }
+
+    /// Returns the reason phrase of this [`HttpResponseHeader`], the custom one if set.
+    pub fn get_reason(&self) -> &str {
+        self.reason.as_deref().unwrap_or(self.status.get_str())
+    }
+
+    /// Add a request header name to the `Vary` header of this [`HttpResponseHeader`].
+    /// Should be called whenever the response depends on a request header, for example
+    /// `Accept-Encoding` when compressing or `Accept` when negotiating the content type.
+    /// Names already present are not repeated.
+    pub fn add_vary(&mut self, name: &str) {
+        match self.headers.get_mut("Vary") {
+            Some(vary) => {
+                if !vary.split(',').any(|v| v.trim().eq_ignore_ascii_case(name)) {
+                    vary.push_str(", ");
+                    vary.push_str(name);
+                }
+            }
+            None => {
+                self.headers.insert("Vary".to_string(), name.to_string());
+            }
+        }
+    }
+
     /// Create a new HttpResponseHeader from a bufffer.
     ///
     /// # Errors
     ///
     /// This function will return an error if the header is bigger than the buffer.
-    pub fn create_from_buffer(
-        buffer: [u8; 4096],
-    ) -> Result<(HttpResponseHeader, usize), &'static str> {
+    pub fn create_from_buffer(buffer: &[u8]) -> Result<(HttpResponseHeader, usize), &'static str> {
         for i in 0..buffer.len() {
             if i > 4
                 && buffer[i] == 10
@@ -489,7 +1098,7 @@ impl HttpResponseHeader {
                 && buffer[i - 3] == 13
             {
                 // \r\n\r\n found, after this its the body.
-                let header = String::from_utf8_lossy(&buffer[0..i]).into_owned();
+                let header = String::from_utf8_lossy(&buffer[0..=i]).into_owned();
 
                 //println!("{}", header);
 
@@ -502,18 +1111,16 @@ impl HttpResponseHeader {
         Err("Request header larger than buffer")
     }
 
-    /// Parse a HttpResponseHeader from a string.
+    /// Parse a HttpResponseHeader from a string. Unknown status codes are read as a
+    /// [`HttpStatus::Custom`], with the reason phrase kept in `reason`.
     ///
     /// # Errors
     ///
-    /// This function will return an error if HttpStatus can not be created.
+    /// This function will return an error if the status line has no status code or the
+    /// HttpStatus can not be created.
     pub fn parse_from_string(data: String) -> Result<HttpResponseHeader, &'static str> {
         let split_header: Vec<&str> = data.split("\r\n").collect();
 
-        let mut headers = HashMap::new();
-
-        let mut content_length: usize = 0;
-
         let split_status_line: Vec<&str> = split_header[0].split(" ").collect();
 
         //let verb = HttpVerb::from_str(split_status_line[0])?;
@@ -521,42 +1128,88 @@ impl HttpResponseHeader {
         let http_version = String::from(split_status_line[0]);
         //let response = split_status_line[1].parse::<i32>();
 
-        let status = match split_status_line[1].parse::<i16>() {
+        let code = split_status_line.get(1).ok_or("Malformed status line")?;
+
+        let status = match code.parse::<i16>() {
             Ok(status_code) => HttpStatus::from_code(status_code),
             Err(_) => Err("Failed to parse status code"),
         }?;
 
-        for i in 1..split_header.len() {
-            //println!("Head: {}", split_header[i]);
-
-            let split_item: Vec<&str> = split_header[i].split(": ").collect();
-
-            // If the split item has more than 1 item, add a header.
-            if split_item.len() > 1 {
-                let k = String::from(split_item[0]).to_uppercase();
-                let v = String::from(split_item[1]);
-
-                // If the header item is `Content-Length` set it as such.
-                if k == "CONTENT-LENGTH" {
-                    match v.parse::<usize>() {
-                        Ok(i) => content_length = i,
-                        Err(_) => {}
-                    }
-                }
+        // Only kept if it differs from the standard phrase.
+        let reason = split_status_line
+            .get(2..)
+            .map(|words| words.join(" "))
+            .filter(|reason| reason != status.get_str());
 
-                headers.insert(k, v);
-            }
-        }
+        let (headers, content_length) = parse_header_lines(&split_header[1..])?;
 
         Ok(HttpResponseHeader {
             headers,
             http_version,
             status,
+            reason,
             content_length,
         })
     }
 
-    /// Returns the string of this [`HttpResponseHeader`].
+    /// Check this [`HttpResponseHeader`] serializes to a valid HTTP/1.x header: an
+    /// `HTTP/1.<digit>` version, a three digit status code, a reason phrase and header values
+    /// without line breaks or other control characters, and header names that are tokens.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error describing the first invalid part.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let version_ok = self
+            .http_version
+            .strip_prefix("HTTP/1.")
+            .is_some_and(|minor| minor.len() == 1 && minor.bytes().all(|b| b.is_ascii_digit()));
+
+        if !version_ok {
+            return Err("Invalid HTTP version");
+        }
+
+        if !(100..=999).contains(&self.status.get_code()) {
+            return Err("Status code must be three digits");
+        }
+
+        if !is_valid_header_value(self.get_reason()) {
+            return Err("Invalid reason phrase");
+        }
+
+        for (name, value) in &self.headers {
+            if !is_valid_header_name(name) {
+                return Err("Invalid header name");
+            }
+
+            if !is_valid_header_value(value) {
+                return Err("Invalid header value");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove the headers that would make this [`HttpResponseHeader`] invalid, those with a name
+    /// that is not a token or a value with a line break or other control character. Returns the
+    /// names of the removed headers.
+    pub fn remove_invalid_headers(&mut self) -> Vec<String> {
+        let invalid: Vec<String> = self
+            .headers
+            .iter()
+            .filter(|(name, value)| !is_valid_header_name(name) || !is_valid_header_value(value))
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for name in &invalid {
+            self.headers.remove(name);
+        }
+
+        invalid
+    }
+
+    /// Returns the string of this [`HttpResponseHeader`]. The header is serialized as it is,
+    /// check it with [`HttpResponseHeader::validate`] before sending it.
     pub fn get_string(&self) -> String {
         // Create the header.
         let mut header_string = String::new();
@@ -565,14 +1218,14 @@ impl HttpResponseHeader {
         header_string.push(' ');
         header_string.push_str(&self.status.get_code().to_string());
         header_string.push(' ');
-        header_string.push_str(self.status.get_str());
+        header_string.push_str(self.get_reason());
 
         header_string.push_str("\r\n");
 
         for header in &self.headers {
-            header_string.push_str(&header.0);
+            header_string.push_str(header.0);
             header_string.push_str(": ");
-            header_string.push_str(&header.1);
+            header_string.push_str(header.1);
             header_string.push_str("\r\n");
         }
 
@@ -582,9 +1235,963 @@ impl HttpResponseHeader {
 
     /// Returns the bytes of this [`HttpResponseHeader`].
     pub fn to_bytes(&mut self) -> Vec<u8> {
-        // Get the bytes for the header and append the response body.
-        let bytes = Vec::from(self.get_string());
+        Vec::from(self.get_string())
+    }
+}
 
-        bytes
+/// A writer that counts the bytes written through it, used to measure the size of responses.
+pub struct CountingWriter<W: Write> {
+    inner: W,
+    count: usize,
+}
+
+impl<W: Write> CountingWriter<W> {
+    /// Creates a new [`CountingWriter`] wrapping a writer.
+    pub fn new(inner: W) -> CountingWriter<W> {
+        CountingWriter { inner, count: 0 }
+    }
+
+    /// Returns the number of bytes written so far.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Get the content type from a path based on it's file extension, [`DEFAULT_CONTENT_TYPE`] if
+/// the extension is unknown.
+pub fn get_content_type(path: &str) -> String {
+    match path {
+        _ if path.ends_with(".html") || path.ends_with(".htm") => "text/html".to_string(),
+        _ if path.ends_with(".css") => "text/css".to_string(),
+        _ if path.ends_with(".js") || path.ends_with(".mjs") => {
+            "application/javascript".to_string()
+        }
+        _ if path.ends_with(".wasm") => "application/wasm".to_string(),
+        _ if path.ends_with(".png") => "image/png".to_string(),
+        _ if path.ends_with(".jpg") || path.ends_with(".jpeg") => "image/jpeg".to_string(),
+        _ if path.ends_with(".ico") => "image/x-icon".to_string(),
+        _ if path.ends_with(".gif") => "image/gif".to_string(),
+        _ if path.ends_with(".svg") => "image/svg+xml".to_string(),
+        _ if path.ends_with(".webp") => "image/webp".to_string(),
+        _ if path.ends_with(".avif") => "image/avif".to_string(),
+        _ if path.ends_with(".woff2") => "font/woff2".to_string(),
+        _ if path.ends_with(".woff") => "font/woff".to_string(),
+        _ if path.ends_with(".ttf") => "font/ttf".to_string(),
+        _ if path.ends_with(".otf") => "font/otf".to_string(),
+        _ if path.ends_with(".mp4") => "video/mp4".to_string(),
+        _ if path.ends_with(".webm") => "video/webm".to_string(),
+        _ if path.ends_with(".mp3") => "audio/mpeg".to_string(),
+        _ if path.ends_with(".pdf") => "application/pdf".to_string(),
+        _ if path.ends_with(".xml") => "application/xml".to_string(),
+        _ if path.ends_with(".json") || path.ends_with(".map") => "application/json".to_string(),
+        _ if path.ends_with(".txt") || path.ends_with(".md") => "text/plain".to_string(),
+        _ if path.ends_with(".csv") => "text/csv".to_string(),
+        _ => DEFAULT_CONTENT_TYPE.to_string(),
+    }
+}
+
+/// Guess the content type of a file from its first bytes, for files without a known extension.
+/// Returns `None` if the contents are not recognised.
+pub fn sniff_content_type(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => Some("image/png"),
+        [0xff, 0xd8, 0xff, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some("image/gif"),
+        [b'%', b'P', b'D', b'F', b'-', ..] => Some("application/pdf"),
+        _ => match data.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{' | b'[') => Some("application/json"),
+            _ => None,
+        },
+    }
+}
+
+/// Format a time as a http date (RFC 7231), for example `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn format_http_date(time: DateTime<UTC>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Parse a single `Range` header value (`bytes=0-99`, `bytes=100-` or `bytes=-100`) for a body
+/// of `length` bytes, returning the first and last byte positions (inclusive). An end past the
+/// end of the body is limited to the last byte.
+///
+/// Returns `None` if the range is malformed or can not be satisfied (it starts past the end of
+/// the body).
+pub fn parse_range(range: &str, length: usize) -> Option<(usize, usize)> {
+    let (start, end) = range.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let (start, end) = match (start.is_empty(), end.is_empty()) {
+        // A suffix range, the last `end` bytes.
+        (true, false) => {
+            let suffix = end.parse::<usize>().ok()?;
+
+            if suffix == 0 {
+                return None;
+            }
+
+            (length.saturating_sub(suffix), length.checked_sub(1)?)
+        }
+        (false, true) => (start.parse::<usize>().ok()?, length.checked_sub(1)?),
+        (false, false) => {
+            let end = end.parse::<usize>().ok()?;
+
+            (
+                start.parse::<usize>().ok()?,
+                end.min(length.checked_sub(1)?),
+            )
+        }
+        (true, true) => return None,
+    };
+
+    if start <= end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+/// Check if an `If-None-Match` header value matches an ETag.
+///
+/// The value can be `*` (matching any ETag) or a comma separated list of ETags. ETags are
+/// compared with the weak comparison used for conditional GET requests, so `W/"a"` matches `"a"`.
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let if_none_match = if_none_match.trim();
+
+    if if_none_match == "*" {
+        return true;
+    }
+
+    let opaque_tag = |tag: &str| {
+        let tag = tag.trim();
+        tag.strip_prefix("W/").unwrap_or(tag).to_string()
+    };
+    let etag = opaque_tag(etag);
+
+    if_none_match.split(',').any(|tag| opaque_tag(tag) == etag)
+}
+
+/// Create a strong ETag (a quoted sha1 hex digest) for some data.
+pub fn create_etag(data: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+
+    hasher.update(data);
+
+    format!("\"{:x}\"", hasher.finalize())
+}
+
+/// Split an absolute-form request target (`http://example.com/path`) into the host and the rest of
+/// the target. Other targets are returned unchanged without a host.
+fn split_absolute_target(target: &str) -> (Option<String>, &str) {
+    let rest = ["http://", "https://"]
+        .iter()
+        .find_map(|scheme| match target.get(..scheme.len()) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(scheme) => Some(&target[scheme.len()..]),
+            _ => None,
+        });
+
+    match rest {
+        Some(rest) => {
+            let (host, path) = match rest.find(['/', '?']) {
+                Some(i) => rest.split_at(i),
+                None => (rest, ""),
+            };
+
+            (Some(host.to_string()), path)
+        }
+        None => (None, target),
+    }
+}
+
+/// Split a request target into the route and query string.
+/// An empty route (as in `http://example.com?a=b`) is the root.
+fn split_target(target: &str) -> (String, Option<String>) {
+    let (route, query) = match target.split_once('?') {
+        Some((route, query)) => (route, Some(query.to_string())),
+        None => (target, None),
+    };
+
+    if route.is_empty() {
+        ("/".to_string(), query)
+    } else {
+        (route.to_string(), query)
+    }
+}
+
+/// Parse the header lines of a request or response (everything after the start line).
+///
+/// Obsolete line folding is supported, a line starting with a space or tab is treated as a
+/// continuation of the previous header's value. Returns the headers (in the order they appear)
+/// and the value of `Content-Length` (0 if missing or invalid).
+///
+/// # Errors
+///
+/// This function will return an error if a header name is not a valid token or a value contains
+/// a stray carriage return or line feed.
+fn parse_header_lines(lines: &[&str]) -> Result<(HeaderMap, usize), &'static str> {
+    let mut headers = HeaderMap::new();
+    let mut last_key: Option<String> = None;
+
+    // The header ends at the first blank line.
+    for line in lines.iter().take_while(|line| !line.is_empty()) {
+        // A continuation line, append it to the previous header's value.
+        if line.starts_with(' ') || line.starts_with('\t') {
+            let value = last_key
+                .as_ref()
+                .and_then(|k| headers.get_mut(k))
+                .ok_or("Continuation line without a header")?;
+            let continuation = line.trim();
+
+            if !is_valid_header_value(continuation) {
+                return Err("Invalid header value");
+            }
+
+            value.push(' ');
+            value.push_str(continuation);
+            continue;
+        }
+
+        // Only the first `:` is split on so values containing colons are kept whole, the space
+        // after it is optional.
+        let (name, value) = line.split_once(':').ok_or("Header line without a colon")?;
+        let (name, value) = (name.trim(), value.trim());
+
+        if !is_valid_header_name(name) {
+            return Err("Invalid header name");
+        }
+
+        if !is_valid_header_value(value) {
+            return Err("Invalid header value");
+        }
+
+        let k = String::from(name);
+        let v = String::from(value);
+
+        headers.insert(k.clone(), v);
+        last_key = Some(k);
+    }
+
+    // If the header item is `Content-Length` set it as such.
+    // A length that can not be parsed leaves the end of the request unknown.
+    let content_length = match headers.get("CONTENT-LENGTH") {
+        Some(value) => value
+            .parse::<usize>()
+            .map_err(|_| "Invalid Content-Length")?,
+        None => 0,
+    };
+
+    Ok((headers, content_length))
+}
+
+/// Check a header name only contains token characters (as defined in RFC 7230).
+pub fn is_valid_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.bytes().all(|b| {
+            b.is_ascii_alphanumeric()
+                || matches!(
+                    b,
+                    b'!' | b'#'
+                        | b'$'
+                        | b'%'
+                        | b'&'
+                        | b'\''
+                        | b'*'
+                        | b'+'
+                        | b'-'
+                        | b'.'
+                        | b'^'
+                        | b'_'
+                        | b'`'
+                        | b'|'
+                        | b'~'
+                )
+        })
+}
+
+/// Check a header value does not contain a carriage return, line feed or other control characters
+/// (horizontal tabs are allowed).
+pub fn is_valid_header_value(value: &str) -> bool {
+    value.bytes().all(|b| b == b'\t' || !b.is_ascii_control())
+}
+
+/// Check if a buffer contains the `\r\n\r\n` marking the end of a header.
+fn contains_header_end(buffer: &[u8]) -> bool {
+    buffer.windows(4).any(|w| w == b"\r\n\r\n")
+}
+
+/// Find the index of the end of a header (the `\r\n\r\n`), if it has been received.
+fn find_header_end(buffer: &[u8]) -> Option<usize> {
+    buffer.windows(4).position(|w| w == b"\r\n\r\n")
+}
+
+/// Map an error reading a request body, the stream closing before the body was received means
+/// the body was shorter than its `Content-Length`.
+fn short_body_error(error: RequestError) -> RequestError {
+    match error {
+        RequestError::Closed => RequestError::Malformed("Request body shorter than Content-Length"),
+        error => error,
+    }
+}
+
+/// Read from a response stream into a buffer, returning the number of bytes read. Reads
+/// interrupted by a signal are retried.
+///
+/// # Errors
+///
+/// This function will return an error if the read fails or the stream is closed (an
+/// [`ErrorKind::UnexpectedEof`] error).
+fn read_response<R: Read>(stream: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match stream.read(buffer) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => return Ok(n),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Read and decode a chunked body, `data` is the part of it already received. Each chunk is its
+/// size in hex (optionally followed by `;` and extensions) on a line, then the chunk's bytes and
+/// a line break. A zero size chunk ends the body, followed by optional trailers which are
+/// discarded.
+///
+/// # Errors
+///
+/// This function will return an error if a chunk size is invalid or the stream can not be read
+/// or closes before the body has been received.
+fn read_chunked_body<R: Read>(stream: &mut R, mut data: Vec<u8>) -> Result<Vec<u8>, &'static str> {
+    let mut body = Vec::new();
+    let mut position = 0;
+
+    loop {
+        let line_end = read_chunk_line(stream, &mut data, position)?;
+        let line = String::from_utf8_lossy(&data[position..line_end]);
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| "Invalid chunk size")?;
+
+        position = line_end + 2;
+
+        if size == 0 {
+            // Skip the trailers, up to the blank line ending the body. The whole body has been
+            // received, so the stream closing first is not an error.
+            while let Ok(line_end) = read_chunk_line(stream, &mut data, position) {
+                if line_end == position {
+                    break;
+                }
+
+                position = line_end + 2;
+            }
+
+            return Ok(body);
+        }
+
+        while data.len() < position + size + 2 {
+            read_chunk_data(stream, &mut data)?;
+        }
+
+        body.extend_from_slice(&data[position..position + size]);
+
+        if &data[position + size..position + size + 2] != b"\r\n" {
+            return Err("Chunk not followed by a line break");
+        }
+
+        position += size + 2;
+    }
+}
+
+/// Find the end of the line of a chunked body starting at `start`, reading more of the body
+/// until it has been received.
+///
+/// # Errors
+///
+/// This function will return an error if the stream can not be read or closes first.
+fn read_chunk_line<R: Read>(
+    stream: &mut R,
+    data: &mut Vec<u8>,
+    start: usize,
+) -> Result<usize, &'static str> {
+    loop {
+        if let Some(i) = data[start..].windows(2).position(|w| w == b"\r\n") {
+            return Ok(start + i);
+        }
+
+        read_chunk_data(stream, data)?;
+    }
+}
+
+/// Read more of a chunked body onto the end of `data`.
+///
+/// # Errors
+///
+/// This function will return an error if the stream can not be read or is closed.
+fn read_chunk_data<R: Read>(stream: &mut R, data: &mut Vec<u8>) -> Result<(), &'static str> {
+    let mut buffer = [0; 4096];
+    let n = read_response(stream, &mut buffer)
+        .map_err(|_| "Connection closed before chunked body was received")?;
+
+    data.extend_from_slice(&buffer[..n]);
+    Ok(())
+}
+
+/// Read from a stream into a buffer, returning the number of bytes read.
+///
+/// # Errors
+///
+/// This function will return an error if the read times out, fails or the stream is closed.
+fn read_to_buffer(mut stream: &TcpStream, buffer: &mut [u8]) -> Result<usize, RequestError> {
+    match stream.read(buffer) {
+        Ok(0) => Err(RequestError::Closed),
+        Ok(n) => Ok(n),
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            Err(RequestError::Timeout)
+        }
+        Err(_) => Err(RequestError::Malformed("Could not read request")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stream returning at most `step` bytes per read, so a response takes several reads.
+    struct Trickle {
+        data: Vec<u8>,
+        position: usize,
+        step: usize,
+    }
+
+    impl Trickle {
+        fn new(data: Vec<u8>, step: usize) -> Trickle {
+            Trickle {
+                data,
+                position: 0,
+                step,
+            }
+        }
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let end = (self.position + self.step.min(buf.len())).min(self.data.len());
+            let n = end - self.position;
+
+            buf[..n].copy_from_slice(&self.data[self.position..end]);
+            self.position = end;
+            Ok(n)
+        }
+    }
+
+    /// Open a loopback connection, returning the client and server ends.
+    fn connect() -> (TcpStream, TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        (client, stream)
+    }
+
+    /// Read the next request from a stream with a 1 second timeout.
+    fn next_request(
+        reader: &mut RequestReader,
+        stream: &TcpStream,
+    ) -> Result<HttpRequest, RequestError> {
+        reader.next_request(stream, &Logger::for_tests(), Some(Duration::from_secs(1)))
+    }
+
+    /// Send raw bytes to a [`RequestReader`] over a loopback connection.
+    fn read_request(raw: &[u8]) -> Result<HttpRequest, RequestError> {
+        let (mut client, stream) = connect();
+
+        client.write_all(raw).unwrap();
+
+        next_request(&mut RequestReader::new(DEFAULT_BUFFER_SIZE), &stream)
+    }
+
+    #[test]
+    fn header_values_keep_their_colons() {
+        let raw = "GET / HTTP/1.1\r\nReferer: https://example.com:8080/path\r\n\r\n";
+        let header = HttpRequestHeader::parse_from_string(raw.to_string()).unwrap();
+
+        assert_eq!(
+            header.headers.get("Referer"),
+            Some(&"https://example.com:8080/path".to_string())
+        );
+    }
+
+    #[test]
+    fn folded_header_lines_are_joined() {
+        let raw = "GET / HTTP/1.1\r\nX-Long: part1\r\n part2\r\n\tpart3\r\nX-Next: next\r\n\r\n";
+        let header = HttpRequestHeader::parse_from_string(raw.to_string()).unwrap();
+
+        assert_eq!(
+            header.headers.get("X-Long"),
+            Some(&"part1 part2 part3".to_string())
+        );
+        assert_eq!(header.headers.get("X-Next"), Some(&"next".to_string()));
+    }
+
+    #[test]
+    fn absolute_form_targets_are_split_into_host_and_route() {
+        let parse = |target: &str| {
+            HttpRequestHeader::parse_from_string(format!("GET {} HTTP/1.1\r\n\r\n", target))
+                .unwrap()
+        };
+
+        let header = parse("http://example.com:8080/docs/page.html?a=b");
+
+        assert_eq!(header.target_host, Some("example.com:8080".to_string()));
+        assert_eq!(header.route, "/docs/page.html");
+        assert_eq!(header.query, Some("a=b".to_string()));
+
+        let header = parse("HTTPS://example.com?a=b");
+
+        assert_eq!(header.target_host, Some("example.com".to_string()));
+        assert_eq!(header.route, "/");
+        assert_eq!(header.query, Some("a=b".to_string()));
+
+        let header = parse("/docs/page.html");
+
+        assert_eq!(header.target_host, None);
+        assert_eq!(header.route, "/docs/page.html");
+    }
+
+    #[test]
+    fn header_lines_split_on_the_first_colon() {
+        let raw = "GET / HTTP/1.1\r\nX-Tight:value\r\nX-Spaced:   padded  \r\n\
+                   X-Url:http://example.com:8080/\r\n\r\n";
+        let header = HttpRequestHeader::parse_from_string(raw.to_string()).unwrap();
+
+        assert_eq!(header.headers.get("X-Tight"), Some(&"value".to_string()));
+        assert_eq!(header.headers.get("X-Spaced"), Some(&"padded".to_string()));
+        assert_eq!(
+            header.headers.get("X-Url"),
+            Some(&"http://example.com:8080/".to_string())
+        );
+    }
+
+    #[test]
+    fn header_lines_without_a_colon_are_bad_requests() {
+        for (raw, error) in [
+            (
+                &b"GET / HTTP/1.1\r\nNo colon here\r\n\r\n"[..],
+                "Header line without a colon",
+            ),
+            (
+                b"GET / HTTP/1.1\r\n orphan continuation\r\nHost: x\r\n\r\n",
+                "Continuation line without a header",
+            ),
+        ] {
+            match read_request(raw) {
+                Err(e) => {
+                    assert_eq!(e.get_status().get_code(), 400);
+                    assert_eq!(e.get_str(), error);
+                }
+                Ok(_) => panic!("accepted {:?}", String::from_utf8_lossy(raw)),
+            }
+        }
+    }
+
+    #[test]
+    fn header_names_with_spaces_are_rejected() {
+        let raw = "GET / HTTP/1.1\r\nBad Name: value\r\n\r\n";
+
+        assert_eq!(
+            HttpRequestHeader::parse_from_string(raw.to_string()).err(),
+            Some("Invalid header name")
+        );
+    }
+
+    #[test]
+    fn header_values_with_newlines_are_rejected() {
+        let raw = "GET / HTTP/1.1\r\nX-Value: one\ntwo\r\n\r\n";
+
+        assert_eq!(
+            HttpRequestHeader::parse_from_string(raw.to_string()).err(),
+            Some("Invalid header value")
+        );
+    }
+
+    #[test]
+    fn malformed_request_lines_are_bad_requests() {
+        for raw in [
+            &b"GET\r\n\r\n"[..],
+            b"GET /\r\n\r\n",
+            b"GET  / HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.1 extra\r\n\r\n",
+        ] {
+            match read_request(raw) {
+                Err(e) => assert_eq!(e.get_status().get_code(), 400),
+                Ok(_) => panic!("accepted {:?}", String::from_utf8_lossy(raw)),
+            }
+        }
+    }
+
+    #[test]
+    fn partial_headers_time_out() {
+        let (mut client, stream) = connect();
+
+        client.write_all(b"GET / HTTP/1.1\r\nHost: loc").unwrap();
+
+        let error = RequestReader::new(DEFAULT_BUFFER_SIZE)
+            .next_request(
+                &stream,
+                &Logger::for_tests(),
+                Some(Duration::from_millis(100)),
+            )
+            .err()
+            .unwrap();
+
+        assert_eq!(error.get_status().get_code(), 408);
+    }
+
+    #[test]
+    fn bodies_shorter_than_the_content_length_are_bad_requests() {
+        let (mut client, stream) = connect();
+
+        client
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc")
+            .unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let error = next_request(&mut RequestReader::new(DEFAULT_BUFFER_SIZE), &stream)
+            .err()
+            .unwrap();
+
+        assert_eq!(error.get_status().get_code(), 400);
+        assert_eq!(error.get_str(), "Request body shorter than Content-Length");
+    }
+
+    #[test]
+    fn bytes_after_the_body_are_the_next_request() {
+        let (mut client, stream) = connect();
+        let mut reader = RequestReader::new(DEFAULT_BUFFER_SIZE);
+
+        client
+            .write_all(
+                b"POST /first HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcGET /next HTTP/1.1\r\n\r\n",
+            )
+            .unwrap();
+
+        let first = next_request(&mut reader, &stream).unwrap();
+
+        assert_eq!(first.header.route, "/first");
+        assert_eq!(first.body, Some(b"abc".to_vec()));
+
+        let next = next_request(&mut reader, &stream).unwrap();
+
+        assert_eq!(next.header.route, "/next");
+        assert_eq!(next.body, None);
+        assert_eq!(reader.get_served(), 2);
+    }
+
+    #[test]
+    fn invalid_content_lengths_are_bad_requests() {
+        for length in ["abc", "-1", "3, 3", ""] {
+            let raw = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\nabc", length);
+
+            match read_request(raw.as_bytes()) {
+                Err(e) => {
+                    assert_eq!(e.get_status().get_code(), 400);
+                    assert_eq!(e.get_str(), "Invalid Content-Length");
+                }
+                Ok(_) => panic!("accepted Content-Length {:?}", length),
+            }
+        }
+    }
+
+    #[test]
+    fn bodies_over_the_maximum_size_are_rejected_before_reading() {
+        let (mut client, stream) = connect();
+        let mut reader = RequestReader::new(DEFAULT_BUFFER_SIZE).with_max_body_size(16);
+
+        client
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 99999999999\r\n\r\nabc")
+            .unwrap();
+
+        let error = next_request(&mut reader, &stream).err().unwrap();
+
+        assert_eq!(error.get_status().get_code(), 413);
+        assert!(!reader.is_in_sync());
+
+        // A body at the limit is still read.
+        let (mut client, stream) = connect();
+        let mut reader = RequestReader::new(DEFAULT_BUFFER_SIZE).with_max_body_size(3);
+
+        client
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc")
+            .unwrap();
+
+        assert_eq!(
+            next_request(&mut reader, &stream).unwrap().body,
+            Some(b"abc".to_vec())
+        );
+    }
+
+    #[test]
+    fn etags_match_lists_weak_tags_and_wildcards() {
+        let etag = "\"abc\"";
+
+        assert!(etag_matches("\"abc\"", etag));
+        assert!(etag_matches("\"xyz\", \"abc\"", etag));
+        assert!(etag_matches("\"xyz\",W/\"abc\"", etag));
+        assert!(etag_matches("W/\"abc\"", etag));
+        assert!(etag_matches("\"abc\"", "W/\"abc\""));
+        assert!(etag_matches(" * ", etag));
+
+        assert!(!etag_matches("\"xyz\", \"abd\"", etag));
+        assert!(!etag_matches("abc", etag));
+        assert!(!etag_matches("", etag));
+    }
+
+    #[test]
+    fn status_codes_round_trip() {
+        for code in 100..=599 {
+            assert_eq!(HttpStatus::from_code(code).unwrap().get_code(), code);
+        }
+
+        for code in [
+            101, 200, 201, 202, 204, 206, 301, 302, 304, 307, 308, 400, 401, 403, 404, 405, 406,
+            408, 409, 413, 416, 422, 426, 429, 500, 502, 503,
+        ] {
+            let status = HttpStatus::from_code(code).unwrap();
+
+            assert!(!matches!(status, HttpStatus::Custom(..)), "{}", code);
+            assert!(!status.get_str().is_empty(), "{}", code);
+
+            let line = format!("HTTP/1.1 {} {}", code, status.get_str());
+            let header = HttpResponseHeader::parse_from_string(line).unwrap();
+
+            assert_eq!(header.status.get_code(), code);
+            assert_eq!(header.status.get_str(), status.get_str());
+        }
+
+        for code in [0, 99, 600, -1] {
+            assert!(HttpStatus::from_code(code).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn custom_statuses_keep_their_reason() {
+        let response = HttpResponse::create(
+            HttpStatus::Custom(418, "I'm a teapot"),
+            "text/plain".to_string(),
+            IndexMap::new(),
+            Some(b"short and stout".to_vec()),
+        );
+        let raw = response.header.get_string();
+
+        assert!(raw.starts_with("HTTP/1.1 418 I'm a teapot\r\n"), "{}", raw);
+
+        let header = HttpResponseHeader::parse_from_string(raw).unwrap();
+
+        assert_eq!(header.status.get_code(), 418);
+        assert!(header
+            .get_string()
+            .starts_with("HTTP/1.1 418 I'm a teapot\r\n"));
+    }
+
+    #[test]
+    fn requests_reserialize_with_their_header_order() {
+        let raw = "POST /form?a=b HTTP/1.1\r\nZ-Last: 1\r\nHost: example.com\r\n\
+                   Accept: */*\r\nContent-Length: 0\r\nA-First: 2\r\n\r\n";
+        let header = HttpRequestHeader::parse_from_string(raw.to_string()).unwrap();
+
+        assert_eq!(header.get_string(), raw);
+        assert_eq!(
+            HttpRequestHeader::parse_from_string(header.get_string())
+                .unwrap()
+                .get_string(),
+            raw
+        );
+    }
+
+    #[test]
+    fn response_addition_headers_keep_their_order() {
+        let names = ["X-Zulu", "Cache-Control", "X-Alpha", "ETag", "X-Mike"];
+        let addition_headers: IndexMap<String, String> = names
+            .iter()
+            .map(|name| (name.to_string(), "1".to_string()))
+            .collect();
+        let response = HttpResponse::create(
+            HttpStatus::Ok,
+            "text/plain".to_string(),
+            addition_headers,
+            None,
+        );
+        let raw = response.header.get_string();
+        let sent: Vec<&str> = raw
+            .lines()
+            .filter_map(|line| line.split(": ").next())
+            .filter(|name| names.contains(name))
+            .collect();
+
+        assert_eq!(sent, names);
+    }
+
+    #[test]
+    fn status_lines_without_a_code_are_rejected() {
+        assert!(HttpResponseHeader::parse_from_string("HTTP/1.1".to_string()).is_err());
+    }
+
+    #[test]
+    fn server_name_is_only_sent_on_responses() {
+        let mut response = HttpResponse::create(
+            HttpStatus::Ok,
+            "text/plain".to_string(),
+            IndexMap::new(),
+            None,
+        );
+
+        response.header.set_server(Some("custom/1.0"));
+        assert!(response
+            .header
+            .get_string()
+            .contains("\r\nServer: custom/1.0\r\n"));
+
+        response.header.set_server(None);
+        assert!(!response.header.get_string().contains("Server:"));
+
+        let request = HttpRequest::create(
+            "/".to_string(),
+            HttpVerb::GET,
+            "text/plain".to_string(),
+            IndexMap::new(),
+            None,
+        );
+        assert!(!request.header.get_string().contains("Server:"));
+    }
+
+    #[test]
+    fn responses_carry_a_date_header() {
+        use chrono::TimeZone;
+
+        assert_eq!(
+            format_http_date(UTC.ymd(1994, 11, 6).and_hms(8, 49, 37)),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+
+        let response = HttpResponse::create(
+            HttpStatus::NotModified,
+            "text/plain".to_string(),
+            IndexMap::new(),
+            None,
+        );
+        let date = response.header.headers.get("Date").unwrap();
+        let pattern =
+            regex::Regex::new(r"^[A-Z][a-z]{2}, \d{2} [A-Z][a-z]{2} \d{4} \d{2}:\d{2}:\d{2} GMT$")
+                .unwrap();
+
+        assert!(pattern.is_match(date), "{}", date);
+    }
+
+    #[test]
+    fn response_headers_are_framed_exactly() {
+        for (status, line) in [
+            (HttpStatus::Ok, "HTTP/1.1 200 OK"),
+            (HttpStatus::NoContent, "HTTP/1.1 204 No Content"),
+            (HttpStatus::NotFound, "HTTP/1.1 404 Not Found"),
+            (HttpStatus::Custom(599, "Odd"), "HTTP/1.1 599 Odd"),
+            (HttpStatus::Custom(299, ""), "HTTP/1.1 299 "),
+        ] {
+            let mut header =
+                HttpResponseHeader::create(status, "text/plain".to_string(), IndexMap::new(), 0);
+
+            assert!(header.validate().is_ok());
+
+            let string = header.get_string();
+            assert!(string.starts_with(&format!("{}\r\n", line)));
+            assert!(string.ends_with("\r\n\r\n"));
+            assert!(!string[..string.len() - 4].contains("\r\n\r\n"));
+
+            // With no headers the status line is followed by the blank line.
+            header.headers = HeaderMap::new();
+            assert_eq!(header.get_string(), format!("{}\r\n\r\n", line));
+        }
+    }
+
+    #[test]
+    fn invalid_response_headers_are_found_and_removed() {
+        let mut header = HttpResponseHeader::create(
+            HttpStatus::Ok,
+            "text/plain".to_string(),
+            IndexMap::new(),
+            0,
+        );
+
+        header
+            .headers
+            .insert("X-Split".to_string(), "a\r\nInjected: yes".to_string());
+        header
+            .headers
+            .insert("Bad Name".to_string(), "value".to_string());
+        assert_eq!(header.validate(), Err("Invalid header value"));
+
+        assert_eq!(header.remove_invalid_headers(), ["X-Split", "Bad Name"]);
+        assert!(header.validate().is_ok());
+        assert!(header.headers.contains_key("Content-Type"));
+
+        header.http_version = "HTTP/2".to_string();
+        assert_eq!(header.validate(), Err("Invalid HTTP version"));
+    }
+
+    #[test]
+    fn common_web_files_have_their_content_type() {
+        for (path, content_type) in [
+            ("/app.mjs", "application/javascript"),
+            ("/module.wasm", "application/wasm"),
+            ("/feed.xml", "application/xml"),
+            ("/photo.webp", "image/webp"),
+            ("/font.woff2", "font/woff2"),
+            ("/font.woff", "font/woff"),
+            ("/data.csv", "text/csv"),
+            ("/unknown.bin", DEFAULT_CONTENT_TYPE),
+        ] {
+            assert_eq!(get_content_type(path), content_type, "{}", path);
+        }
+    }
+
+    #[test]
+    fn response_from_stream_reads_a_large_body() {
+        let body: Vec<u8> = (0..10 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut raw =
+            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        raw.extend_from_slice(&body);
+
+        let response =
+            HttpResponse::from_stream(Trickle::new(raw, 1000), DEFAULT_BUFFER_SIZE).unwrap();
+
+        assert_eq!(response.header.content_length, body.len());
+        assert_eq!(response.body, Some(body));
+    }
+
+    #[test]
+    fn response_from_stream_decodes_a_chunked_body() {
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n".to_vec();
+
+        let response =
+            HttpResponse::from_stream(Trickle::new(raw, 7), DEFAULT_BUFFER_SIZE).unwrap();
+
+        assert_eq!(response.body, Some(b"hello, world".to_vec()));
+        assert!(!response.header.headers.contains_key("TRANSFER-ENCODING"));
+        assert_eq!(response.header.content_length, 12);
+    }
+
+    #[test]
+    fn response_from_stream_fails_on_a_short_body() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort".to_vec();
+
+        assert!(HttpResponse::from_stream(Trickle::new(raw, 100), DEFAULT_BUFFER_SIZE).is_err());
     }
 }
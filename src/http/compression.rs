@@ -0,0 +1,171 @@
+use std::io::Write;
+
+use flate2::{write::GzEncoder, Compression};
+
+/// The Brotli quality level, lower than the maximum (11) to keep responses quick to generate.
+const BROTLI_QUALITY: u32 = 9;
+
+/// The Brotli window size (as a power of 2).
+const BROTLI_WINDOW: u32 = 22;
+
+/// Bodies smaller than this are not worth compressing.
+pub const MIN_COMPRESS_SIZE: usize = 256;
+
+/// An encoding a response body can be sent with.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ContentEncoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+impl ContentEncoding {
+    /// Returns the `Content-Encoding` token of this [`ContentEncoding`].
+    pub fn get_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Identity => "identity",
+        }
+    }
+}
+
+/// Select the encoding to use for a response from the request's `Accept-Encoding` header.
+///
+/// Brotli is preferred, then gzip, then identity. Encodings with a quality of 0 (`br;q=0`) are
+/// not accepted and `*` accepts any encoding not otherwise listed.
+pub fn select_encoding(accept_encoding: &str) -> ContentEncoding {
+    let mut accepted: Vec<(String, bool)> = Vec::new();
+
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+
+        if name.is_empty() {
+            continue;
+        }
+
+        let quality = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .filter_map(|q| q.trim().parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+
+        accepted.push((name, quality > 0.0));
+    }
+
+    let is_accepted = |name: &str| {
+        accepted
+            .iter()
+            .find(|(n, _)| n == name)
+            .or_else(|| accepted.iter().find(|(n, _)| n == "*"))
+            .map(|(_, ok)| *ok)
+            .unwrap_or(false)
+    };
+
+    if is_accepted("br") {
+        ContentEncoding::Brotli
+    } else if is_accepted("gzip") {
+        ContentEncoding::Gzip
+    } else {
+        ContentEncoding::Identity
+    }
+}
+
+/// Check if a content type benefits from compression (text based formats).
+pub fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || content_type.starts_with("application/javascript")
+        || content_type.starts_with("application/json")
+        || content_type.starts_with("application/xml")
+        || content_type.starts_with("application/wasm")
+        || content_type.starts_with("image/svg+xml")
+}
+
+/// Compress data with an encoding. Identity returns the data unchanged.
+///
+/// # Errors
+///
+/// This function will return an error if the data can not be compressed.
+pub fn compress(data: &[u8], encoding: ContentEncoding) -> Result<Vec<u8>, &'static str> {
+    match encoding {
+        ContentEncoding::Brotli => {
+            let mut compressed = Vec::new();
+
+            {
+                let mut writer = brotli::CompressorWriter::new(
+                    &mut compressed,
+                    4096,
+                    BROTLI_QUALITY,
+                    BROTLI_WINDOW,
+                );
+
+                writer
+                    .write_all(data)
+                    .map_err(|_| "Could not compress with brotli")?;
+            }
+
+            Ok(compressed)
+        }
+        ContentEncoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+
+            encoder
+                .write_all(data)
+                .map_err(|_| "Could not compress with gzip")?;
+
+            encoder.finish().map_err(|_| "Could not compress with gzip")
+        }
+        ContentEncoding::Identity => Ok(data.to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    #[test]
+    fn brotli_is_preferred_over_gzip() {
+        assert_eq!(
+            select_encoding("gzip, deflate, br"),
+            ContentEncoding::Brotli
+        );
+        assert_eq!(select_encoding("gzip, br;q=0"), ContentEncoding::Gzip);
+        assert_eq!(select_encoding("deflate"), ContentEncoding::Identity);
+        assert_eq!(select_encoding("*"), ContentEncoding::Brotli);
+        assert_eq!(select_encoding("*, br;q=0"), ContentEncoding::Gzip);
+        assert_eq!(select_encoding(""), ContentEncoding::Identity);
+    }
+
+    #[test]
+    fn compressed_bodies_decompress_to_the_original() {
+        let data = "body { color: red; }\n".repeat(50).into_bytes();
+
+        let mut gzip = Vec::new();
+        GzDecoder::new(&compress(&data, ContentEncoding::Gzip).unwrap()[..])
+            .read_to_end(&mut gzip)
+            .unwrap();
+        assert_eq!(gzip, data);
+
+        let mut brotli = Vec::new();
+        brotli::Decompressor::new(&compress(&data, ContentEncoding::Brotli).unwrap()[..], 4096)
+            .read_to_end(&mut brotli)
+            .unwrap();
+        assert_eq!(brotli, data);
+
+        assert_eq!(compress(&data, ContentEncoding::Identity).unwrap(), data);
+    }
+
+    #[test]
+    fn only_text_based_types_are_compressible() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/json"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("font/woff2"));
+    }
+}
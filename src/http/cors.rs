@@ -0,0 +1,270 @@
+use std::time::Duration;
+
+use indexmap::IndexMap;
+
+use crate::http::common::{HttpRequest, HttpResponse, HttpStatus, HttpVerb};
+
+/// The cross-origin requests a route allows, see [`crate::http::router::Router::add_with_cors`].
+/// Routes without a policy (and static files) send no CORS headers, so browsers only allow
+/// same-origin requests to them.
+#[derive(Clone)]
+pub struct CorsPolicy {
+    /// The origins allowed, `*` allows any origin.
+    pub allowed_origins: Vec<String>,
+    /// The request headers allowed besides the CORS-safelisted ones, `*` allows any header.
+    pub allowed_headers: Vec<String>,
+    /// Allow requests with credentials (cookies or authorization headers). The request's origin
+    /// is sent back rather than `*`, as browsers require.
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight response.
+    pub max_age: Option<Duration>,
+}
+
+impl CorsPolicy {
+    /// Creates a new [`CorsPolicy`] allowing the given origins.
+    pub fn new(origins: &[&str]) -> CorsPolicy {
+        CorsPolicy {
+            allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            allowed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Creates a new [`CorsPolicy`] allowing any origin and request header.
+    pub fn permissive() -> CorsPolicy {
+        CorsPolicy::new(&["*"]).with_headers(&["*"])
+    }
+
+    /// Allow the given request headers.
+    pub fn with_headers(mut self, headers: &[&str]) -> CorsPolicy {
+        self.allowed_headers = headers.iter().map(|header| header.to_string()).collect();
+        self
+    }
+
+    /// Allow requests with credentials.
+    pub fn with_credentials(mut self) -> CorsPolicy {
+        self.allow_credentials = true;
+        self
+    }
+
+    /// Let browsers cache preflight responses for `max_age`.
+    pub fn with_max_age(mut self, max_age: Duration) -> CorsPolicy {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Returns the `Access-Control-Allow-Origin` value for a request's origin, `None` if the
+    /// origin is not allowed.
+    pub fn get_allowed_origin(&self, origin: &str) -> Option<String> {
+        let any = self.allowed_origins.iter().any(|allowed| allowed == "*");
+
+        if any && !self.allow_credentials {
+            Some("*".to_string())
+        } else if any || self.allowed_origins.iter().any(|allowed| allowed == origin) {
+            Some(origin.to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Add the CORS headers to the response to a cross-origin request (one with an `Origin`
+    /// header) from an allowed origin. Returns `false` if the origin is missing or not allowed.
+    pub fn apply(&self, request: &HttpRequest, response: &mut HttpResponse) -> bool {
+        let allowed_origin = match request.header.headers.get("ORIGIN") {
+            Some(origin) => self.get_allowed_origin(origin.trim()),
+            None => None,
+        };
+
+        let allowed_origin = match allowed_origin {
+            Some(allowed_origin) => allowed_origin,
+            None => return false,
+        };
+
+        // Caches must not reuse a response naming one origin for another.
+        if allowed_origin != "*" {
+            response.header.add_vary("Origin");
+        }
+
+        let headers = &mut response.header.headers;
+        headers.insert("Access-Control-Allow-Origin".to_string(), allowed_origin);
+
+        if self.allow_credentials {
+            headers.insert(
+                "Access-Control-Allow-Credentials".to_string(),
+                "true".to_string(),
+            );
+        }
+
+        true
+    }
+
+    /// Create the response to a preflight request, `allowed_methods` are the methods the route
+    /// accepts. A preflight from an origin that is not allowed gets a 403.
+    pub fn create_preflight_response(
+        &self,
+        request: &HttpRequest,
+        allowed_methods: &str,
+    ) -> HttpResponse {
+        let mut response = HttpResponse::create(
+            HttpStatus::NoContent,
+            "text/plain".to_string(),
+            IndexMap::new(),
+            None,
+        );
+
+        if !self.apply(request, &mut response) {
+            return HttpResponse::create_error(HttpStatus::Forbidden, "Origin not allowed");
+        }
+
+        let headers = &mut response.header.headers;
+
+        headers.insert(
+            "Access-Control-Allow-Methods".to_string(),
+            allowed_methods.to_string(),
+        );
+
+        // Any header is allowed by repeating back the ones asked for.
+        let requested = request.header.headers.get("ACCESS-CONTROL-REQUEST-HEADERS");
+        let allowed_headers = if self.allowed_headers.iter().any(|header| header == "*") {
+            requested.cloned()
+        } else if !self.allowed_headers.is_empty() {
+            Some(self.allowed_headers.join(", "))
+        } else {
+            None
+        };
+
+        if let Some(allowed_headers) = allowed_headers {
+            headers.insert("Access-Control-Allow-Headers".to_string(), allowed_headers);
+        }
+
+        if let Some(max_age) = self.max_age {
+            headers.insert(
+                "Access-Control-Max-Age".to_string(),
+                max_age.as_secs().to_string(),
+            );
+        }
+
+        response
+    }
+}
+
+/// Check if a request is a CORS preflight, an OPTIONS request with an `Origin` and
+/// `Access-Control-Request-Method` header.
+pub fn is_preflight(request: &HttpRequest) -> bool {
+    request.header.verb == HttpVerb::OPTIONS
+        && request.header.headers.contains_key("ORIGIN")
+        && request
+            .header
+            .headers
+            .contains_key("ACCESS-CONTROL-REQUEST-METHOD")
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+
+    use super::*;
+
+    fn request(verb: HttpVerb, headers: &[(&str, &str)]) -> HttpRequest {
+        HttpRequest::create(
+            "/api".to_string(),
+            verb,
+            "text/plain".to_string(),
+            headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<IndexMap<_, _>>(),
+            None,
+        )
+    }
+
+    fn ok_response() -> HttpResponse {
+        HttpResponse::create(
+            HttpStatus::Ok,
+            "text/plain".to_string(),
+            IndexMap::new(),
+            None,
+        )
+    }
+
+    #[test]
+    fn only_allowed_origins_get_cors_headers() {
+        let policy = CorsPolicy::new(&["http://localhost:3000"]);
+
+        let mut response = ok_response();
+        let allowed = request(HttpVerb::GET, &[("Origin", "http://localhost:3000")]);
+        assert!(policy.apply(&allowed, &mut response));
+        assert_eq!(
+            response.header.headers.get("Access-Control-Allow-Origin"),
+            Some(&"http://localhost:3000".to_string())
+        );
+        assert_eq!(
+            response.header.headers.get("Vary"),
+            Some(&"Origin".to_string())
+        );
+
+        let mut response = ok_response();
+        let other = request(HttpVerb::GET, &[("Origin", "http://example.com")]);
+        assert!(!policy.apply(&other, &mut response));
+        assert!(!policy.apply(&request(HttpVerb::GET, &[]), &mut response));
+        assert!(!response
+            .header
+            .headers
+            .contains_key("Access-Control-Allow-Origin"));
+    }
+
+    #[test]
+    fn credentials_send_back_the_origin_rather_than_any() {
+        assert_eq!(
+            CorsPolicy::new(&["*"]).get_allowed_origin("http://a.test"),
+            Some("*".to_string())
+        );
+        assert_eq!(
+            CorsPolicy::new(&["*"])
+                .with_credentials()
+                .get_allowed_origin("http://a.test"),
+            Some("http://a.test".to_string())
+        );
+    }
+
+    #[test]
+    fn preflights_list_the_allowed_methods_and_headers() {
+        let policy = CorsPolicy::permissive().with_max_age(Duration::from_secs(600));
+        let preflight = request(
+            HttpVerb::OPTIONS,
+            &[
+                ("Origin", "http://a.test"),
+                ("Access-Control-Request-Method", "PUT"),
+                ("Access-Control-Request-Headers", "X-Token"),
+            ],
+        );
+
+        assert!(is_preflight(&preflight));
+        assert!(!is_preflight(&request(
+            HttpVerb::OPTIONS,
+            &[("Origin", "http://a.test")]
+        )));
+
+        let response = policy.create_preflight_response(&preflight, "GET, PUT");
+        let headers = &response.header.headers;
+
+        assert_eq!(response.header.status.get_code(), 204);
+        assert_eq!(
+            headers.get("Access-Control-Allow-Methods"),
+            Some(&"GET, PUT".to_string())
+        );
+        assert_eq!(
+            headers.get("Access-Control-Allow-Headers"),
+            Some(&"X-Token".to_string())
+        );
+        assert_eq!(
+            headers.get("Access-Control-Max-Age"),
+            Some(&"600".to_string())
+        );
+
+        let response =
+            CorsPolicy::new(&["http://b.test"]).create_preflight_response(&preflight, "GET");
+        assert_eq!(response.header.status.get_code(), 403);
+    }
+}
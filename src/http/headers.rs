@@ -0,0 +1,127 @@
+use indexmap::IndexMap;
+
+/// The headers of a request or response. Names are case-insensitive, so a header set as
+/// `Content-Length` replaces one parsed as `CONTENT-LENGTH` rather than being sent alongside it.
+/// Headers are kept in the order they were first added, each serialized with the name it was
+/// last set with.
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct HeaderMap {
+    /// The name and value of each header, keyed by the lower case name.
+    entries: IndexMap<String, (String, String)>,
+}
+
+impl HeaderMap {
+    /// Creates a new, empty [`HeaderMap`].
+    pub fn new() -> HeaderMap {
+        HeaderMap {
+            entries: IndexMap::new(),
+        }
+    }
+
+    /// Set a header, returning the value it replaced.
+    pub fn insert(&mut self, name: String, value: String) -> Option<String> {
+        self.entries
+            .insert(get_key(&name), (name, value))
+            .map(|(_, value)| value)
+    }
+
+    /// Returns the value of a header.
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.entries.get(&get_key(name)).map(|(_, value)| value)
+    }
+
+    /// Returns a mutable reference to the value of a header.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut String> {
+        self.entries.get_mut(&get_key(name)).map(|(_, value)| value)
+    }
+
+    /// Check if a header is set.
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.entries.contains_key(&get_key(name))
+    }
+
+    /// Remove a header, keeping the order of the rest. Returns the removed value.
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        self.entries
+            .shift_remove(&get_key(name))
+            .map(|(_, value)| value)
+    }
+
+    /// Returns an iterator over the names and values of the headers, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.entries.values().map(|(name, value)| (name, value))
+    }
+
+    /// Returns the number of headers.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if there are no headers.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<'a> IntoIterator for &'a HeaderMap {
+    type Item = (&'a String, &'a String);
+    type IntoIter = Box<dyn Iterator<Item = (&'a String, &'a String)> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.iter())
+    }
+}
+
+impl FromIterator<(String, String)> for HeaderMap {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in iter {
+            headers.insert(name, value);
+        }
+
+        headers
+    }
+}
+
+/// Returns the key a header is stored under, its lower case name.
+fn get_key(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_case_insensitive() {
+        let mut headers = HeaderMap::new();
+
+        headers.insert("CONTENT-LENGTH".to_string(), "10".to_string());
+        headers.insert("Content-Length".to_string(), "20".to_string());
+
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers.get("content-length"), Some(&"20".to_string()));
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            [(&"Content-Length".to_string(), &"20".to_string())]
+        );
+        assert!(headers.contains_key("content-LENGTH"));
+        assert_eq!(headers.remove("content-length"), Some("20".to_string()));
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn headers_keep_their_order() {
+        let mut headers: HeaderMap = [("B", "1"), ("A", "2"), ("C", "3")]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        headers.insert("b".to_string(), "4".to_string());
+        headers.remove("A");
+
+        let names: Vec<&String> = headers.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["b", "C"]);
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    fs, io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, NaiveDateTime, UTC};
+
+/// The placeholder replaced with the rows of the listing.
+pub const ENTRIES_PLACEHOLDER: &str = "{{entries}}";
+
+/// The placeholder replaced with the route of the directory.
+pub const PATH_PLACEHOLDER: &str = "{{path}}";
+
+/// The built in directory listing template.
+pub const DEFAULT_TEMPLATE: &str = "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>Index of {{path}}</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; }
+th, td { padding: 0.2em 1em; text-align: left; }
+td.size { text-align: right; }
+</style>
+</head>
+<body>
+<h1>Index of {{path}}</h1>
+<table>
+<tr><th>Name</th><th>Size</th><th>Modified</th></tr>
+{{entries}}
+</table>
+</body>
+</html>
+";
+
+/// An entry in a directory listing.
+pub struct ListingEntry {
+    pub name: String,
+    pub is_dir: bool,
+    /// The size in bytes, 0 for directories.
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// Read the entries of a directory, directories first then files, each sorted by name.
+///
+/// # Errors
+///
+/// This function will return an error if the directory can not be read.
+pub fn read_entries(dir: &Path) -> io::Result<Vec<ListingEntry>> {
+    let mut entries = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+
+        entries.push(ListingEntry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    Ok(entries)
+}
+
+/// Render a directory listing for a route, replacing the placeholders in a template with the
+/// route and a table row (name, size and modified time) per entry.
+pub fn render(template: &str, route: &str, entries: &[ListingEntry]) -> String {
+    let base = if route.ends_with('/') {
+        route.to_string()
+    } else {
+        format!("{}/", route)
+    };
+
+    let mut rows = String::new();
+
+    // Links are absolute so they work whether or not the route ends with a `/`.
+    if let Some((parent, _)) = base.trim_end_matches('/').rsplit_once('/') {
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}/\">../</a></td><td></td><td></td></tr>\n",
+            escape_html(parent)
+        ));
+    }
+
+    for entry in entries {
+        let name = if entry.is_dir {
+            format!("{}/", entry.name)
+        } else {
+            entry.name.clone()
+        };
+
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}{}\">{}</a></td><td class=\"size\">{}</td><td>{}</td></tr>\n",
+            escape_html(&base),
+            escape_html(&name),
+            escape_html(&name),
+            if entry.is_dir {
+                "-".to_string()
+            } else {
+                format_size(entry.size)
+            },
+            entry.modified.map(format_modified).unwrap_or_default(),
+        ));
+    }
+
+    template
+        .replace(PATH_PLACEHOLDER, &escape_html(route))
+        .replace(ENTRIES_PLACEHOLDER, &rows)
+}
+
+/// Escape the characters with a special meaning in html.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Format a size in bytes for display, for example `1.5 KB`.
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Format a modified time for display (UTC).
+fn format_modified(time: SystemTime) -> String {
+    let duration = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let naive = NaiveDateTime::from_timestamp(duration.as_secs() as i64, 0);
+
+    DateTime::<UTC>::from_utc(naive, UTC)
+        .format("%Y-%m-%d %H:%M")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn entry(name: &str, is_dir: bool, size: u64) -> ListingEntry {
+        ListingEntry {
+            name: name.to_string(),
+            is_dir,
+            size,
+            modified: Some(UNIX_EPOCH + Duration::from_secs(86_400 + 3_660)),
+        }
+    }
+
+    #[test]
+    fn entries_are_read_directories_first() {
+        let dir = std::env::temp_dir().join(format!("dev_server_listing_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("zeta")).unwrap();
+        fs::write(dir.join("b.txt"), "bb").unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+
+        let entries = read_entries(&dir).unwrap();
+        let names: Vec<(&str, bool, u64)> = entries
+            .iter()
+            .map(|e| (e.name.as_str(), e.is_dir, e.size))
+            .collect();
+
+        assert_eq!(
+            names,
+            [("zeta", true, 0), ("a.txt", false, 1), ("b.txt", false, 2)]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn listings_link_each_entry_and_the_parent() {
+        let entries = [entry("docs", true, 0), entry("a <b>.txt", false, 1536)];
+
+        let html = render("{{path}}\n{{entries}}", "/files", &entries);
+
+        assert!(html.starts_with("/files\n"));
+        assert!(html.contains("<a href=\"/\">../</a>"));
+        assert!(html.contains("<a href=\"/files/docs/\">docs/</a></td><td class=\"size\">-</td>"));
+        assert!(html.contains(
+            "<a href=\"/files/a &lt;b&gt;.txt\">a &lt;b&gt;.txt</a></td><td class=\"size\">1.5 KB</td><td>1970-01-02 01:01</td>"
+        ));
+    }
+
+    #[test]
+    fn the_root_has_no_parent_link() {
+        let html = render(DEFAULT_TEMPLATE, "/", &[]);
+
+        assert!(html.contains("<title>Index of /</title>"));
+        assert!(!html.contains("../"));
+    }
+
+    #[test]
+    fn sizes_use_the_largest_whole_unit() {
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1024), "1.0 KB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MB");
+    }
+}
@@ -1,2 +1,7 @@
 pub mod common;
+pub mod compression;
+pub mod cors;
+pub mod headers;
+pub mod listing;
+pub mod router;
 pub mod server;
@@ -0,0 +1,429 @@
+use crate::http::{
+    common::{HttpRequest, HttpResponse, HttpStatus, HttpVerb},
+    cors::{self, CorsPolicy},
+};
+
+/// A function handling a request matched by the router. An error is rendered as an error
+/// response with the error's status.
+pub type Handler =
+    Box<dyn Fn(&HttpRequest, &RouteMatch) -> Result<HttpResponse, HandlerError> + Send + Sync>;
+
+/// A function creating the response for a request nothing else could handle, in place of the
+/// default 404.
+pub type NotFoundHandler = Box<dyn Fn(&HttpRequest) -> HttpResponse + Send + Sync>;
+
+/// Routes requests to handlers based on the verb and route.
+///
+/// Patterns are split into `/` separated segments, each segment is either:
+///
+/// * a literal, which must match exactly.
+/// * `*`, which matches any single segment.
+/// * `**`, which matches the rest of the route (any number of segments), it must be the last
+///   segment of the pattern.
+///
+/// When several patterns match, the most specific wins (the one with the most literal segments,
+/// then the fewest wildcards).
+///
+/// Routes can have a [`CorsPolicy`], their responses get CORS headers and preflight requests
+/// for them are answered by the router.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+/// The result of matching a request against a route, passed to the handler.
+pub struct RouteMatch {
+    /// The parts of the route matched by wildcards, in order. A `**` capture is the remaining
+    /// segments joined with `/`.
+    pub captures: Vec<String>,
+}
+
+/// An error returned by a handler.
+pub struct HandlerError {
+    pub status: HttpStatus,
+    pub message: String,
+}
+
+struct Route {
+    verb: HttpVerb,
+    segments: Vec<Segment>,
+    handler: Handler,
+    cors: Option<CorsPolicy>,
+}
+
+enum Segment {
+    Literal(String),
+    Wildcard,
+    MultiWildcard,
+}
+
+impl Router {
+    /// Creates a new, empty [`Router`].
+    pub fn new() -> Router {
+        Router { routes: Vec::new() }
+    }
+
+    /// Register a handler for a verb and route pattern.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `**` is used anywhere but the last segment.
+    pub fn add(
+        &mut self,
+        verb: HttpVerb,
+        pattern: &str,
+        handler: Handler,
+    ) -> Result<(), &'static str> {
+        self.add_route(verb, pattern, handler, None)
+    }
+
+    /// Register a handler for a verb and route pattern allowing cross-origin requests as set out
+    /// by a [`CorsPolicy`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `**` is used anywhere but the last segment.
+    pub fn add_with_cors(
+        &mut self,
+        verb: HttpVerb,
+        pattern: &str,
+        handler: Handler,
+        cors: CorsPolicy,
+    ) -> Result<(), &'static str> {
+        self.add_route(verb, pattern, handler, Some(cors))
+    }
+
+    /// Register a handler for a verb and route pattern, with an optional [`CorsPolicy`].
+    fn add_route(
+        &mut self,
+        verb: HttpVerb,
+        pattern: &str,
+        handler: Handler,
+        cors: Option<CorsPolicy>,
+    ) -> Result<(), &'static str> {
+        let segments: Vec<Segment> = split_route(pattern)
+            .map(|s| match s {
+                "*" => Segment::Wildcard,
+                "**" => Segment::MultiWildcard,
+                _ => Segment::Literal(s.to_string()),
+            })
+            .collect();
+
+        if let Some(i) = segments
+            .iter()
+            .position(|s| matches!(s, Segment::MultiWildcard))
+        {
+            if i != segments.len() - 1 {
+                return Err("`**` must be the last segment of a route pattern");
+            }
+        }
+
+        self.routes.push(Route {
+            verb,
+            segments,
+            handler,
+            cors,
+        });
+
+        Ok(())
+    }
+
+    /// Check if any route matches the request, or the request is a preflight for a route with a
+    /// [`CorsPolicy`].
+    pub fn is_match(&self, request: &HttpRequest) -> bool {
+        self.find(request).is_some() || self.find_preflight(request).is_some()
+    }
+
+    /// Handle a request with the most specific matching route, returns `None` if no route
+    /// matches. Preflight requests are answered with the route's [`CorsPolicy`].
+    pub fn handle(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        if let Some(cors) = self.find_preflight(request) {
+            return Some(cors.create_preflight_response(request, &self.get_cors_methods(request)));
+        }
+
+        self.find(request).map(|(route, route_match)| {
+            let mut response = match (route.handler)(request, &route_match) {
+                Ok(response) => response,
+                Err(e) => HttpResponse::create_error(e.status, &e.message),
+            };
+
+            if let Some(cors) = &route.cors {
+                cors.apply(request, &mut response);
+            }
+
+            response
+        })
+    }
+
+    /// Find the most specific route matching a request, routed by its effective verb (see
+    /// [`crate::http::common::HttpRequestHeader::get_effective_verb`]).
+    fn find(&self, request: &HttpRequest) -> Option<(&Route, RouteMatch)> {
+        self.find_route(request.header.get_effective_verb(), &request.header.route)
+    }
+
+    /// Find the most specific route matching a verb and route. HEAD requests also match GET
+    /// routes, the body is left out when the response is sent.
+    fn find_route(&self, verb: HttpVerb, route: &str) -> Option<(&Route, RouteMatch)> {
+        self.routes
+            .iter()
+            .filter(|r| r.verb == verb || (verb == HttpVerb::HEAD && r.verb == HttpVerb::GET))
+            .filter_map(|r| r.match_route(route).map(|route_match| (r, route_match)))
+            // A HEAD route is preferred over a GET route for the same pattern.
+            .max_by_key(|(r, _)| (r.specificity(), r.verb == verb))
+    }
+
+    /// Find the [`CorsPolicy`] for a preflight request, from the route matching the method the
+    /// preflight asks about. `None` if the request is not a preflight or the route has no policy.
+    fn find_preflight(&self, request: &HttpRequest) -> Option<&CorsPolicy> {
+        if !cors::is_preflight(request) {
+            return None;
+        }
+
+        let verb = request
+            .header
+            .headers
+            .get("ACCESS-CONTROL-REQUEST-METHOD")
+            .and_then(|verb| verb.trim().parse().ok())?;
+
+        self.find_route(verb, &request.header.route)
+            .and_then(|(route, _)| route.cors.as_ref())
+    }
+
+    /// Returns the methods of the routes with a [`CorsPolicy`] matching a request's route, for
+    /// the `Access-Control-Allow-Methods` header.
+    fn get_cors_methods(&self, request: &HttpRequest) -> String {
+        let mut methods: Vec<&str> = Vec::new();
+
+        for route in &self.routes {
+            if route.cors.is_some()
+                && route.match_route(&request.header.route).is_some()
+                && !methods.contains(&route.verb.get_str())
+            {
+                methods.push(route.verb.get_str());
+            }
+        }
+
+        methods.join(", ")
+    }
+}
+
+impl HandlerError {
+    /// Creates a new [`HandlerError`].
+    pub fn new(status: HttpStatus, message: &str) -> HandlerError {
+        HandlerError {
+            status,
+            message: message.to_string(),
+        }
+    }
+
+    /// Creates a new 400 [`HandlerError`].
+    pub fn bad_request(message: &str) -> HandlerError {
+        HandlerError::new(HttpStatus::BadRequest, message)
+    }
+
+    /// Creates a new 404 [`HandlerError`].
+    pub fn not_found(message: &str) -> HandlerError {
+        HandlerError::new(HttpStatus::NotFound, message)
+    }
+
+    /// Creates a new 500 [`HandlerError`].
+    pub fn internal_error(message: &str) -> HandlerError {
+        HandlerError::new(HttpStatus::InternalError, message)
+    }
+}
+
+impl Route {
+    /// Match a route against this pattern, returning the captured segments.
+    fn match_route(&self, route: &str) -> Option<RouteMatch> {
+        let parts: Vec<&str> = split_route(route).collect();
+        let mut captures = Vec::new();
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::MultiWildcard => {
+                    captures.push(parts.get(i..).unwrap_or_default().join("/"));
+                    return Some(RouteMatch { captures });
+                }
+                Segment::Wildcard => captures.push(parts.get(i)?.to_string()),
+                Segment::Literal(literal) => {
+                    if *parts.get(i)? != literal.as_str() {
+                        return None;
+                    }
+                }
+            }
+        }
+
+        if parts.len() == self.segments.len() {
+            Some(RouteMatch { captures })
+        } else {
+            None
+        }
+    }
+
+    /// Returns a sortable specificity for this route. More literal segments rank higher, then
+    /// fewer wildcards, with a trailing `**` ranking lowest.
+    fn specificity(&self) -> (usize, usize, usize) {
+        let (literals, wildcards, multi_wildcards) =
+            self.segments
+                .iter()
+                .fold((0, 0, 0), |(l, w, m), segment| match segment {
+                    Segment::Literal(_) => (l + 1, w, m),
+                    Segment::Wildcard => (l, w + 1, m),
+                    Segment::MultiWildcard => (l, w, m + 1),
+                });
+
+        (
+            literals,
+            usize::MAX - multi_wildcards,
+            usize::MAX - wildcards,
+        )
+    }
+}
+
+/// Split a route into its non-empty segments.
+fn split_route(route: &str) -> impl Iterator<Item = &str> {
+    route.split('/').filter(|s| !s.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+
+    use super::*;
+
+    fn request(verb: HttpVerb, route: &str) -> HttpRequest {
+        HttpRequest::create(
+            route.to_string(),
+            verb,
+            "text/plain".to_string(),
+            IndexMap::new(),
+            None,
+        )
+    }
+
+    /// A handler responding with its name and the captures.
+    fn named(name: &'static str) -> Handler {
+        Box::new(move |_, route_match| {
+            let body = format!("{} {}", name, route_match.captures.join(","));
+
+            Ok(HttpResponse::create(
+                HttpStatus::Ok,
+                "text/plain".to_string(),
+                IndexMap::new(),
+                Some(body.into_bytes()),
+            ))
+        })
+    }
+
+    fn handle(router: &Router, verb: HttpVerb, route: &str) -> Option<String> {
+        router
+            .handle(&request(verb, route))
+            .map(|response| String::from_utf8(response.body.unwrap_or_default()).unwrap())
+    }
+
+    #[test]
+    fn the_most_specific_route_wins() {
+        let mut router = Router::new();
+        router.add(HttpVerb::GET, "/api/**", named("rest")).unwrap();
+        router
+            .add(HttpVerb::GET, "/api/*/items", named("items"))
+            .unwrap();
+        router
+            .add(HttpVerb::GET, "/api/users/items", named("users"))
+            .unwrap();
+
+        assert_eq!(
+            handle(&router, HttpVerb::GET, "/api/users/items"),
+            Some("users ".to_string())
+        );
+        assert_eq!(
+            handle(&router, HttpVerb::GET, "/api/teams/items"),
+            Some("items teams".to_string())
+        );
+        assert_eq!(
+            handle(&router, HttpVerb::GET, "/api/teams/a/b"),
+            Some("rest teams/a/b".to_string())
+        );
+        assert_eq!(handle(&router, HttpVerb::GET, "/other"), None);
+        assert_eq!(handle(&router, HttpVerb::POST, "/api/users/items"), None);
+    }
+
+    #[test]
+    fn head_requests_match_get_routes() {
+        let mut router = Router::new();
+        router.add(HttpVerb::GET, "/page", named("get")).unwrap();
+        router.add(HttpVerb::GET, "/both", named("get")).unwrap();
+        router.add(HttpVerb::HEAD, "/both", named("head")).unwrap();
+
+        assert_eq!(
+            handle(&router, HttpVerb::HEAD, "/page"),
+            Some("get ".to_string())
+        );
+        assert_eq!(
+            handle(&router, HttpVerb::HEAD, "/both"),
+            Some("head ".to_string())
+        );
+    }
+
+    #[test]
+    fn multi_wildcards_must_be_last() {
+        let mut router = Router::new();
+
+        assert!(router.add(HttpVerb::GET, "/**/end", named("bad")).is_err());
+        assert!(router.add(HttpVerb::GET, "/start/**", named("ok")).is_ok());
+    }
+
+    #[test]
+    fn handler_errors_become_error_responses() {
+        let mut router = Router::new();
+        router
+            .add(
+                HttpVerb::GET,
+                "/missing",
+                Box::new(|_, _| Err(HandlerError::not_found("No such item"))),
+            )
+            .unwrap();
+
+        let response = router.handle(&request(HttpVerb::GET, "/missing")).unwrap();
+        assert_eq!(response.header.status.get_code(), 404);
+    }
+
+    #[test]
+    fn preflights_are_answered_for_cors_routes() {
+        let mut router = Router::new();
+        router
+            .add_with_cors(
+                HttpVerb::PUT,
+                "/api/*",
+                named("put"),
+                CorsPolicy::permissive(),
+            )
+            .unwrap();
+        router
+            .add(HttpVerb::DELETE, "/api/*", named("delete"))
+            .unwrap();
+
+        let mut preflight = request(HttpVerb::OPTIONS, "/api/1");
+        let headers = &mut preflight.header.headers;
+        headers.insert("Origin".to_string(), "http://a.test".to_string());
+        headers.insert(
+            "Access-Control-Request-Method".to_string(),
+            "PUT".to_string(),
+        );
+
+        assert!(router.is_match(&preflight));
+        let response = router.handle(&preflight).unwrap();
+        assert_eq!(response.header.status.get_code(), 204);
+        assert_eq!(
+            response.header.headers.get("Access-Control-Allow-Methods"),
+            Some(&"PUT".to_string())
+        );
+
+        // The DELETE route has no policy, so there is no preflight for it.
+        preflight.header.headers.insert(
+            "Access-Control-Request-Method".to_string(),
+            "DELETE".to_string(),
+        );
+        assert!(!router.is_match(&preflight));
+    }
+}
@@ -1,37 +1,235 @@
 use std::{
+    backtrace::Backtrace,
+    cell::{Cell, RefCell},
     collections::HashMap,
-    fs::File,
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
+    fs,
+    io::{self, ErrorKind, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
     sync::{
-        mpsc::{self, Receiver, Sender},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, Once, OnceLock,
     },
     thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
 };
 
-use regex::Regex;
+use indexmap::IndexMap;
+use regex::{NoExpand, Regex};
+use socket2::{Domain, Protocol, Socket, Type};
 
 use crate::{
-    http::common::{HttpRequest, HttpResponse, HttpStatus},
+    cache::LruCache,
+    config::{Config, NO_STORE},
+    files::{HeaderFiles, HEADERS_FILE_NAME},
+    http::{
+        common::{
+            create_etag, etag_matches, parse_range, CountingWriter, HttpRequest, HttpResponse,
+            HttpStatus, HttpVerb, RequestError, RequestReader, DEFAULT_CONTENT_TYPE,
+        },
+        compression::{self, ContentEncoding},
+        cors::CorsPolicy,
+        listing,
+        router::{HandlerError, Router},
+    },
     logging::logger::{Log, Logger},
-    messaging::Subscription,
+    messaging::{ChangeNotifier, Notification, RecentNotifications, Subscription, Unsubscriber},
     ws,
 };
 
+/// The methods supported when serving static files.
+const STATIC_ALLOWED_METHODS: &str = "GET, HEAD";
+
+/// The methods supported when serving static files in authoring mode.
+const WRITABLE_ALLOWED_METHODS: &str = "GET, HEAD, PUT, DELETE";
+
+/// The methods supported on the WebSocket route, only a GET is upgraded (a HEAD gets the same
+/// 426 as a GET without upgrade headers).
+const WS_ALLOWED_METHODS: &str = "GET, HEAD";
+
+/// The icon served for `/favicon.ico` when the file does not exist.
+const DEFAULT_FAVICON: &[u8] = include_bytes!("favicon.ico");
+
+/// The live reload client injected into html documents. Changed stylesheets are hot-swapped by
+/// bumping a cache busting parameter on their `<link>` tag (all stylesheets if the changed file
+/// is not linked directly, for example if it is imported), anything else reloads the page.
+///
+/// Messages are debounced, a burst arriving within `{{debounce}}` milliseconds (replaced when
+/// the script is injected) is handled once, with a full reload if any message needs one.
+///
+/// If the connection closes (for example when the server restarts) the client reconnects with
+/// an exponential backoff of up to `{{max_backoff}}` milliseconds, then reloads the page to pick
+/// up any changes made while it was disconnected.
+///
+/// If `{{sse_fallback}}` is `true` and the WebSocket never opens (it fails, or is still
+/// connecting after `{{fallback_timeout}}` milliseconds) the client switches to the server-sent
+/// events endpoint, for environments blocking WebSocket upgrades.
+const RELOAD_SCRIPT: &str = r#"(function () {
+    function swap(link) {
+        var url = new URL(link.href);
+        url.searchParams.set('_reload', Date.now());
+        link.href = url.toString();
+    }
+    function reloadCss(path) {
+        var links = Array.prototype.slice.call(document.querySelectorAll('link[rel=stylesheet]'));
+        var file = (path || '').replace(/\\/g, '/');
+        var matching = links.filter(function (link) {
+            return file.endsWith(new URL(link.href).pathname);
+        });
+        (matching.length > 0 ? matching : links).forEach(swap);
+    }
+    var pending = [];
+    var timer = null;
+    function flush() {
+        var messages = pending;
+        pending = [];
+        timer = null;
+        if (messages.some(function (msg) { return msg.strategy === 'full'; })) {
+            location.reload();
+            return;
+        }
+        messages.forEach(function (msg) {
+            if (msg.strategy === 'css') {
+                reloadCss(msg.path);
+            }
+        });
+    }
+    function onMessage(evt) {
+        var msg = JSON.parse(evt.data);
+        if (msg.strategy === 'none') {
+            return;
+        }
+        pending.push(msg);
+        clearTimeout(timer);
+        timer = setTimeout(flush, {{debounce}});
+    }
+    var backoff = 500;
+    var reconnecting = false;
+    var connected = false;
+    function connectSse() {
+        console.log('WebSocket unavailable, using server-sent events');
+        // EventSource reconnects by itself.
+        var source = new EventSource('/sse/notify');
+        source.addEventListener('reload', onMessage);
+        source.onopen = function () {
+            console.log('Connected');
+            if (reconnecting) {
+                location.reload();
+            }
+        };
+        source.onerror = function () {
+            reconnecting = true;
+        };
+    }
+    function connect() {
+        // Connect back to whichever address served the page.
+        var protocol = location.protocol === 'https:' ? 'wss:' : 'ws:';
+        var ws = new WebSocket(protocol + '//' + location.host + '/ws/notify');
+        var fallback = null;
+        if ({{sse_fallback}} && !connected) {
+            fallback = setTimeout(function () {
+                ws.onclose = null;
+                ws.close();
+                connectSse();
+            }, {{fallback_timeout}});
+        }
+        ws.onopen = function () {
+            console.log('Connected');
+            clearTimeout(fallback);
+            connected = true;
+            if (reconnecting) {
+                location.reload();
+            }
+            backoff = 500;
+        };
+        ws.onmessage = onMessage;
+        ws.onclose = function () {
+            clearTimeout(fallback);
+            // A WebSocket that never opened is likely blocked.
+            if ({{sse_fallback}} && !connected) {
+                connectSse();
+                return;
+            }
+            reconnecting = true;
+            console.log('Disconnected, reconnecting in ' + backoff + 'ms');
+            setTimeout(connect, backoff);
+            backoff = Math.min(backoff * 2, {{max_backoff}});
+        };
+    }
+    connect();
+})();"#;
+
+/// How often a comment is sent to server-sent events clients while there are no notifications,
+/// keeping proxies from closing the connection and noticing clients that have gone.
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// How long the reload client waits for the WebSocket to open before falling back to server-sent
+/// events.
+const WS_FALLBACK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long to wait before accepting again when the process or system is out of file
+/// descriptors, so the accept loop does not spin while connections close.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The error codes returned when the process (`EMFILE`) or system (`ENFILE`) has run out of file
+/// descriptors.
+const EMFILE: i32 = 24;
+const ENFILE: i32 = 23;
+
+/// How long a connection over the limit has to send its request and read the 503.
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The page returned when the WebSocket route is requested without an upgrade.
+const WS_UPGRADE_REQUIRED_PAGE: &str = "<html><body><h1>426 Upgrade Required</h1><p>This is a WebSocket endpoint used by the dev server to send reload notifications.</p></body></html>";
+
 pub(crate) struct Server {
     thread: JoinHandle<()>,
+    running: Arc<AtomicBool>,
+    address: SocketAddr,
+}
+
+/// Data read from disk that is shared by every connection.
+struct ServerCache {
+    /// The headers from `_headers` files.
+    header_files: HeaderFiles,
+    /// The contents of recently served files.
+    files: Mutex<LruCache<PathBuf, CachedFile>>,
+}
+
+/// A file's contents, with the modified time and length they were read at.
+struct CachedFile {
+    modified: SystemTime,
+    bytes: Vec<u8>,
+}
+
+/// Tracks an open connection, the count is decremented when the guard is dropped.
+struct ConnectionGuard {
+    open_connections: Arc<AtomicUsize>,
+    count: usize,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+/// Installs [`record_panic`] as the panic hook, once.
+static PANIC_HOOK: Once = Once::new();
+
+thread_local! {
+    /// The backtrace of the last panic on this thread, see [`take_panic_backtrace`].
+    static PANIC_BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+
+    /// Set once any of the response to the current request has been written, a 500 can not be
+    /// sent after a panic without corrupting the stream.
+    static RESPONSE_STARTED: Cell<bool> = const { Cell::new(false) };
+}
+
 struct ConnectionPool {
     sender: Sender<Job>,
     workers: Vec<Worker>,
 }
 
 struct Worker {
-    id: usize,
     thread: JoinHandle<()>,
 }
 
@@ -40,38 +238,232 @@ impl Server {
     ///
     /// # Errors
     ///
-    /// This function will return an error if TcpListener can not be bond to the address.
+    /// This function will return an error if the address is invalid or the TcpListener can not be
+    /// bound to it.
     pub fn start(
-        address: String,
+        config: Config,
         log: &Log,
         sub_sender: Sender<Subscription>,
-        base_path: String,
+        notifier: ChangeNotifier,
     ) -> Result<Server, &'static str> {
         let logger = log.get_logger("server".to_string());
-        let connection_pool = ConnectionPool::new(4);
+        let bind_address = parse_address(&config.address)?;
+
+        install_panic_hook();
+        let connection_pool = ConnectionPool::new(config.workers);
 
-        match TcpListener::bind(address) {
+        match bind_listener(bind_address, config.accept_backlog) {
             Ok(listener) => {
-                let thread = thread::spawn(move || loop {
+                let address = match listener.local_addr() {
+                    Ok(address) => address,
+                    Err(_) => return Err("Could not get server address."),
+                };
+                let config = Arc::new(config);
+                let cache = Arc::new(ServerCache::new(&config));
+                let open_connections = Arc::new(AtomicUsize::new(0));
+                let request_counter = AtomicUsize::new(0);
+                let running = Arc::new(AtomicBool::new(true));
+                let thread_running = running.clone();
+
+                let thread = thread::spawn(move || {
                     for stream in listener.incoming() {
+                        if !thread_running.load(Ordering::SeqCst) {
+                            logger.log_info("Server stopped".to_string()).unwrap();
+                            break;
+                        }
+
                         match stream {
                             Ok(stream) => {
-                                let request_logger = logger.create_from("connection".to_string());
+                                let request_id = format!(
+                                    "{:06x}",
+                                    request_counter.fetch_add(1, Ordering::SeqCst)
+                                );
+                                let request_logger =
+                                    logger.create_from(format!("connection[{}]", request_id));
+                                let guard = ConnectionGuard::new(open_connections.clone());
+
+                                logger
+                                    .log_debug(format!("Open connections: {}", guard.count))
+                                    .unwrap();
+
+                                // Past the limit the connection is rejected rather than queued.
+                                if guard.count > config.max_connections {
+                                    request_logger
+                                        .log_warning(format!(
+                                            "Connection limit ({}) reached, rejecting connection",
+                                            config.max_connections
+                                        ))
+                                        .unwrap();
+
+                                    // Rejected on its own thread so a slow client can not hold
+                                    // up accepting other connections.
+                                    let c = config.clone();
+                                    thread::spawn(move || {
+                                        reject_connection(stream, &request_id, &request_logger, &c);
+                                        drop(guard);
+                                    });
+                                    continue;
+                                }
+
                                 let ss = sub_sender.clone();
-                                let bp = base_path.clone();
-                                connection_pool
-                                    .execute(|| handle_connection(stream, request_logger, ss, bp));
+                                let c = config.clone();
+                                let sc = cache.clone();
+                                let n = notifier.clone();
+                                connection_pool.execute(move || {
+                                    handle_connection_safely(
+                                        stream,
+                                        request_id,
+                                        request_logger,
+                                        ss,
+                                        n,
+                                        c,
+                                        sc,
+                                    );
+                                    drop(guard);
+                                });
+                            }
+                            Err(e) => {
+                                logger
+                                    .log_warning(format!(
+                                        "Could not accept connection. Error: {}",
+                                        e
+                                    ))
+                                    .unwrap();
+
+                                if let Some(backoff) = get_accept_backoff(&e) {
+                                    thread::sleep(backoff);
+                                }
                             }
-                            Err(_) => todo!(),
                         };
                     }
+
+                    // Let the workers finish the connections already accepted.
+                    let in_flight = open_connections.load(Ordering::SeqCst);
+                    let drained = connection_pool.shutdown(config.shutdown_timeout);
+                    let remaining = open_connections.load(Ordering::SeqCst);
+
+                    logger
+                        .log_info(format!(
+                            "Drained {} connection(s)",
+                            in_flight.saturating_sub(remaining)
+                        ))
+                        .unwrap();
+
+                    if !drained {
+                        logger
+                            .log_warning(format!(
+                                "Shutdown timed out, {} connection(s) still open",
+                                remaining
+                            ))
+                            .unwrap();
+                    }
                 });
 
-                Ok(Server { thread })
+                Ok(Server {
+                    thread,
+                    running,
+                    address,
+                })
             }
             Err(_) => Err("Could not start server."),
         }
     }
+
+    /// Returns the address this [`Server`] is listening on, with the port the system picked if
+    /// the configured port was 0.
+    pub fn get_address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Stop accepting connections and wait for the server thread to finish. Connections already
+    /// accepted are given up to the configured shutdown timeout to finish.
+    pub fn shutdown(self) {
+        self.running.store(false, Ordering::SeqCst);
+
+        // The accept loop only checks the flag when a connection arrives, so wake it up.
+        let mut address = self.address;
+
+        if address.ip().is_unspecified() {
+            match address {
+                SocketAddr::V4(_) => address.set_ip(Ipv4Addr::LOCALHOST.into()),
+                SocketAddr::V6(_) => address.set_ip(Ipv6Addr::LOCALHOST.into()),
+            }
+        }
+
+        let _ = TcpStream::connect(address);
+        let _ = self.thread.join();
+    }
+}
+
+impl ServerCache {
+    /// Creates a new [`ServerCache`], with the file cache sized from the config.
+    fn new(config: &Config) -> ServerCache {
+        ServerCache {
+            header_files: HeaderFiles::new(),
+            files: Mutex::new(LruCache::with_max_size(
+                config.file_cache_entries,
+                config.file_cache_size,
+                |file: &CachedFile| file.bytes.len(),
+            )),
+        }
+    }
+
+    /// Read a file's contents, from the cache if the file has not changed since it was cached.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file can not be read.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the cache's lock is poisoned.
+    fn read_file(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let metadata = fs::metadata(path)?;
+
+        // Directories are read to get the same error as reading them directly.
+        if metadata.is_dir() {
+            return fs::read(path);
+        }
+
+        let modified = metadata.modified()?;
+
+        if let Some(file) = self.files.lock().unwrap().get(&path.to_path_buf()) {
+            if file.modified == modified && file.bytes.len() as u64 == metadata.len() {
+                return Ok(file.bytes.clone());
+            }
+        }
+
+        // Read without holding the lock so other connections are not held up.
+        let bytes = fs::read(path)?;
+
+        self.files.lock().unwrap().insert(
+            path.to_path_buf(),
+            CachedFile {
+                modified,
+                bytes: bytes.clone(),
+            },
+        );
+
+        Ok(bytes)
+    }
+}
+
+impl ConnectionGuard {
+    /// Creates a new [`ConnectionGuard`], incrementing the open connection count.
+    fn new(open_connections: Arc<AtomicUsize>) -> ConnectionGuard {
+        let count = open_connections.fetch_add(1, Ordering::SeqCst) + 1;
+
+        ConnectionGuard {
+            open_connections,
+            count,
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.open_connections.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl ConnectionPool {
@@ -84,7 +476,6 @@ impl ConnectionPool {
         let receiver = Arc::new(Mutex::new(receiver));
 
         for id in 0..size {
-            let name = format!("worker_{}", id);
             workers.push(Worker::new(id, receiver.clone()));
         }
 
@@ -98,218 +489,2854 @@ impl ConnectionPool {
         let job = Box::new(f);
         self.sender.send(job).unwrap();
     }
+
+    /// Stop sending jobs and wait up to `timeout` for the workers to finish the jobs already
+    /// sent. Returns `false` if a worker was still busy when the timeout passed, it is left to
+    /// finish in the background.
+    fn shutdown(self, timeout: Duration) -> bool {
+        let ConnectionPool { sender, workers } = self;
+
+        // The workers stop once the queue is empty and the sender has been dropped.
+        drop(sender);
+
+        let deadline = Instant::now() + timeout;
+
+        while workers.iter().any(|worker| !worker.thread.is_finished()) {
+            if Instant::now() >= deadline {
+                return false;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        for worker in workers {
+            let _ = worker.thread.join();
+        }
+
+        true
+    }
 }
 
 impl Worker {
-    /// Creates a new [`Worker`].
+    /// Creates a new [`Worker`], its thread is named after its id (`worker_0`) so panic
+    /// messages show which worker failed.
     ///
     /// # Panics
     ///
-    /// Panics if a lock can ot be gained on the receiver or a job received.
+    /// Panics if the thread can not be spawned or a lock can not be gained on the receiver.
     fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let job = receiver.lock().unwrap().recv().unwrap();
-            job();
-        });
+        let thread = thread::Builder::new()
+            .name(format!("worker_{}", id))
+            .spawn(move || loop {
+                let job = match receiver.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    // The pool has been dropped so no more jobs will be sent.
+                    Err(_) => break,
+                };
+
+                job();
+            })
+            .unwrap();
+
+        Worker { thread }
+    }
+}
+
+/// Returns how long to wait before accepting again after an error, `None` to carry on straight
+/// away. Only running out of file descriptors needs a pause, other errors (such as a client
+/// aborting the connection before it was accepted) only affect that connection.
+fn get_accept_backoff(error: &io::Error) -> Option<Duration> {
+    match error.raw_os_error() {
+        Some(EMFILE | ENFILE) if cfg!(unix) => Some(ACCEPT_BACKOFF),
+        _ => None,
+    }
+}
+
+/// Reject a connection over the connection limit with a 503. The request is read first (waiting
+/// at most [`REJECT_TIMEOUT`]) so closing the connection does not reset it before the client has
+/// read the response.
+fn reject_connection(mut stream: TcpStream, request_id: &str, logger: &Logger, config: &Config) {
+    let _ = stream.set_write_timeout(Some(REJECT_TIMEOUT));
+    let _ = RequestReader::new(config.read_buffer_size)
+        .with_max_body_size(config.max_body_size)
+        .next_request(&stream, logger, Some(REJECT_TIMEOUT));
+
+    let mut response =
+        HttpResponse::create_error(HttpStatus::ServiceUnavailable, "Too many connections");
+    response
+        .header
+        .headers
+        .insert("Connection".to_string(), "close".to_string());
+
+    send_response(&mut stream, response, false, config, request_id, logger);
+    let _ = stream.shutdown(Shutdown::Write);
+}
+
+/// Install a panic hook recording the backtrace of each panic for the thread it happened on, so
+/// a caught panic can be logged with it. The previous hook still runs.
+fn install_panic_hook() {
+    PANIC_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+
+        panic::set_hook(Box::new(move |info| {
+            record_panic();
+            previous(info);
+        }));
+    });
+}
+
+/// Record the backtrace of a panic, called from the panic hook on the panicking thread.
+fn record_panic() {
+    let backtrace = Backtrace::force_capture();
+
+    PANIC_BACKTRACE.with(|last| *last.borrow_mut() = Some(backtrace));
+}
+
+/// Take the backtrace of the last panic on this thread, `None` if there has not been one since
+/// it was last taken.
+fn take_panic_backtrace() -> Option<Backtrace> {
+    PANIC_BACKTRACE.with(|last| last.borrow_mut().take())
+}
+
+/// Handle a connection from a client, catching any panic so the worker survives and the client
+/// receives a 500 response instead of a dropped connection. The panic is logged with its
+/// backtrace. If part of a response had already been written the connection is closed instead,
+/// as a 500 would corrupt it.
+///
+/// # Panics
+///
+/// Panics if there is an issue with the logger.
+fn handle_connection_safely(
+    stream: TcpStream,
+    request_id: String,
+    logger: Logger,
+    sub_sender: Sender<Subscription>,
+    notifier: ChangeNotifier,
+    config: Arc<Config>,
+    cache: Arc<ServerCache>,
+) {
+    // Keep a handle to the stream so the error response can be written after a panic.
+    let error_stream = stream.try_clone();
+    let error_logger = logger.clone();
+    let error_config = config.clone();
+
+    RESPONSE_STARTED.with(|started| started.set(false));
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        handle_connection(
+            stream,
+            &request_id,
+            logger,
+            sub_sender,
+            &notifier,
+            config,
+            cache,
+        )
+    }));
+
+    if let Err(e) = result {
+        let message = match e.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match e.downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => "Unknown panic".to_string(),
+            },
+        };
+
+        let backtrace = take_panic_backtrace()
+            .map_or("No backtrace captured".to_string(), |backtrace| {
+                backtrace.to_string()
+            });
+
+        error_logger
+            .log_error(format!(
+                "Handler panicked. Error: {}, Backtrace:\n{}",
+                message, backtrace
+            ))
+            .unwrap();
+
+        if RESPONSE_STARTED.with(|started| started.get()) {
+            error_logger
+                .log_warning("Response already started, closing the connection".to_string())
+                .unwrap();
+
+            if let Ok(stream) = error_stream {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        } else if let Ok(mut stream) = error_stream {
+            let response = HttpResponse::create_error(HttpStatus::InternalError, "Internal error");
 
-        Worker { id, thread }
+            send_response(
+                &mut stream,
+                response,
+                false,
+                &error_config,
+                &request_id,
+                &error_logger,
+            );
+        }
     }
 }
 
 /// Handle a connection from a client.
 ///
+/// Requests are read and responded to in order, the connection is kept open for more requests
+/// until the client closes it (or asks for it to be closed), it is idle for longer than the keep
+/// alive timeout or it has served `max_requests_per_connection` requests.
+///
 /// # Panics
 ///
 /// Panics if an issue with the logger, a file can not be read or a failure to write to the stream.
 fn handle_connection(
     mut stream: TcpStream,
+    request_id: &str,
     logger: Logger,
     sub_sender: Sender<Subscription>,
-    base_path: String,
+    notifier: &ChangeNotifier,
+    config: Arc<Config>,
+    cache: Arc<ServerCache>,
 ) {
-    match HttpRequest::from_stream(&stream, &logger) {
-        Ok(request) => match request.header.route.as_str() {
-            "/ws/notify" => {
+    let mut reader =
+        RequestReader::new(config.read_buffer_size).with_max_body_size(config.max_body_size);
+
+    loop {
+        // Nothing of this request's response has been written yet.
+        RESPONSE_STARTED.with(|started| started.set(false));
+
+        // Requests after the first on a connection get their own id.
+        let (request_id, timeout) = match reader.get_served() {
+            0 => (request_id.to_string(), config.read_timeout),
+            n => (
+                format!("{}.{}", request_id, n),
+                Some(config.keep_alive_timeout),
+            ),
+        };
+
+        match reader.next_request(&stream, &logger, timeout) {
+            Ok(request)
+                if request.header.route == "/ws/notify"
+                    && matches!(request.header.verb, HttpVerb::GET | HttpVerb::HEAD) =>
+            {
                 logger
-                    .log_info(format!("Update notification requested"))
+                    .log_info("Update notification requested".to_string())
                     .unwrap();
-                handle_ws_connection(request, stream, sub_sender, logger);
+                handle_ws_connection(request, stream, &request_id, sub_sender, logger, config);
+                return;
             }
-            route if route == "/" || route == "/index" || route == "/index.html" => {
-                match File::open(format!("{}/index.html", base_path)) {
-                    Ok(mut file) => {
-                        let mut buf = Vec::new();
-
-                        let mut doc = String::new();
+            Ok(request)
+                if request.header.route == "/sse/notify"
+                    && matches!(request.header.verb, HttpVerb::GET | HttpVerb::HEAD) =>
+            {
+                logger
+                    .log_info("Update notification stream requested".to_string())
+                    .unwrap();
+                handle_sse_connection(request, stream, &request_id, sub_sender, logger, config);
+                return;
+            }
+            Ok(request) => {
+                let mut response = handle_request(&request, &config, &cache, notifier, &logger);
+                set_charset(&mut response, &config);
 
-                        file.read_to_string(&mut doc).unwrap();
+                let keep_alive = request.header.is_keep_alive()
+                    && reader.is_in_sync()
+                    && reader.get_served() < config.max_requests_per_connection;
 
-                        file.read_to_end(&mut buf).unwrap();
+                response.header.headers.insert(
+                    "Connection".to_string(),
+                    if keep_alive { "keep-alive" } else { "close" }.to_string(),
+                );
 
-                        let mut response = HttpResponse::create(
-                            HttpStatus::Ok,
-                            "text/html".to_string(),
-                            HashMap::new(),
-                            Some(inject_script(&doc).as_bytes().to_vec()),
-                        );
+                let head_only = request.header.verb == HttpVerb::HEAD;
 
-                        stream.write(&response.to_bytes()).unwrap();
-                    }
-                    Err(_) => todo!(),
+                if !send_response(
+                    &mut stream,
+                    response,
+                    head_only,
+                    &config,
+                    &request_id,
+                    &logger,
+                ) || !keep_alive
+                {
+                    return;
                 }
             }
-            _ => match File::open(get_path(format!(
-                "{}{}",
-                base_path,
-                request.header.route.clone()
-            ))) {
-                Ok(mut file) => {
-                    let mut buf = Vec::new();
+            // The client is done with the connection.
+            Err(RequestError::Closed) => return,
+            Err(e) => {
+                logger
+                    .log_warning(format!(
+                        "Malformed request received. Error: {}",
+                        e.get_str()
+                    ))
+                    .unwrap();
+
+                let response = HttpResponse::create_error(e.get_status(), e.get_str());
 
-                    file.read_to_end(&mut buf).unwrap();
+                send_response(&mut stream, response, false, &config, &request_id, &logger);
+                return;
+            }
+        };
+    }
+}
 
-                    let mut response = HttpResponse::create(
-                        HttpStatus::Ok,
-                        get_content_type(request.header.route.clone()),
-                        HashMap::new(),
-                        Some(buf),
-                    );
+/// Create the response to a request (other than a WebSocket request).
+///
+/// # Panics
+///
+/// Panics if an issue with the logger or a file can not be read.
+fn handle_request(
+    request: &HttpRequest,
+    config: &Config,
+    cache: &ServerCache,
+    notifier: &ChangeNotifier,
+    logger: &Logger,
+) -> HttpResponse {
+    match request.header.route.as_str() {
+        // Checked first so it stays cheap, without touching the file system or router.
+        "/healthz" if matches!(request.header.verb, HttpVerb::GET | HttpVerb::HEAD) => {
+            HttpResponse::create(
+                HttpStatus::Ok,
+                "text/plain".to_string(),
+                IndexMap::new(),
+                Some(b"ok".to_vec()),
+            )
+        }
+        _ if matches!(request.header.verb, HttpVerb::TRACE) => {
+            create_trace_response(request, config)
+        }
+        // Reached by any method other than the ones the WebSocket route handles.
+        "/ws/notify" => create_method_not_allowed_response(WS_ALLOWED_METHODS),
+        _ if config.router.is_match(request) => match config.router.handle(request) {
+            Some(response) => response,
+            None => create_not_found_response(request, config),
+        },
+        route if matches!(request.header.verb, HttpVerb::PUT | HttpVerb::DELETE) => {
+            handle_write_request(request, route, config, cache, notifier, logger)
+        }
+        // Static files can only be read.
+        _ if !matches!(request.header.verb, HttpVerb::GET | HttpVerb::HEAD) => {
+            logger
+                .log_info(format!(
+                    "Method {} not allowed. Route: {}",
+                    request.header.verb.get_str(),
+                    request.header.route
+                ))
+                .unwrap();
 
-                    stream.write(&&response.to_bytes()).unwrap();
+            create_method_not_allowed_response(get_allowed_methods(config))
+        }
+        // In allowlist mode only the listed routes are served, whatever is on disk.
+        route if !is_allowed(route, config) => {
+            logger
+                .log_info(format!("Route not in allowlist. Route: {}", route))
+                .unwrap();
 
+            create_not_found_response(request, config)
+        }
+        route => match get_path(route, config) {
+            Some(path) if config.directory_listing && path.is_dir() => {
+                create_directory_listing(route, &path, config, logger)
+            }
+            Some(path) => match cache.read_file(&path) {
+                Ok(body) => {
                     logger
-                        .log_info(format!("Request received. Route: {}", request.header.route))
+                        .log_info(format!(
+                            "Request received. Route: {}, Client: {}",
+                            route,
+                            format_client(request, config)
+                        ))
                         .unwrap();
+
+                    let mut response =
+                        HttpResponse::from_file_contents(body, &path.to_string_lossy());
+                    set_default_content_type(&mut response, config);
+
+                    // Placeholders are filled in before the script is injected, so the script's
+                    // own are left alone.
+                    if !config.template_vars.is_empty() && is_html(&response) {
+                        let doc = String::from_utf8_lossy(response.body.as_deref().unwrap_or(&[]))
+                            .into_owned();
+
+                        response.set_body(apply_template(&doc, &config.template_vars).into_bytes());
+                    }
+
+                    if is_index_file(&path, config)
+                        && should_inject_script(request, config, &response, logger)
+                    {
+                        let doc = String::from_utf8_lossy(response.body.as_deref().unwrap_or(&[]))
+                            .into_owned();
+
+                        response.set_body(inject_script(&doc, config).into_bytes());
+                    }
+
+                    // The ETag is based on the injected document so it changes with the script.
+                    let mut response = create_cacheable_response(request, config, response);
+                    add_download_disposition(&mut response, config);
+                    let (root, _) = get_mount(route, config);
+                    add_header_file_headers(&mut response, &path, Path::new(root), cache, logger);
+                    response
                 }
-                Err(_) => {
-                    let mut response = HttpResponse::create(
-                        HttpStatus::NotFound,
-                        "text/plain".to_string(),
-                        HashMap::new(),
-                        Some(b"Not found".to_vec()),
-                    );
-
-                    stream.write(&response.to_bytes()).unwrap();
+                Err(e)
+                    if route == "/favicon.ico"
+                        && config.default_favicon
+                        && e.kind() == ErrorKind::NotFound =>
+                {
+                    let response =
+                        HttpResponse::from_file_contents(DEFAULT_FAVICON.to_vec(), "favicon.ico");
+
+                    create_cacheable_response(request, config, response)
                 }
+                Err(e) => create_file_error_response(e, request, config, logger),
             },
+            None => create_not_found_response(request, config),
         },
-        Err(_) => todo!(),
-    };
-}
-
-/// Get a file path from a route.
-fn get_path(route: String) -> String {
-    route
+    }
 }
 
-/// Handle a WebSocket connection.
+/// Handle a PUT (write the body to the file) or DELETE (remove the file) request for a static
+/// file. Only allowed in authoring mode (see [`Config::writable`]), otherwise a 403 is returned.
+/// Connected clients are notified of the change straight away.
 ///
 /// # Panics
 ///
-/// Panics if a failure with the logger.
-fn handle_ws_connection(
-    request: HttpRequest,
-    mut stream: TcpStream,
-    sub_sender: Sender<Subscription>,
-    logger: Logger,
-) {
-    logger.log_debug("WS connection".to_string()).unwrap();
+/// Panics if there is an issue with the logger or the file cache's lock is poisoned.
+fn handle_write_request(
+    request: &HttpRequest,
+    route: &str,
+    config: &Config,
+    cache: &ServerCache,
+    notifier: &ChangeNotifier,
+    logger: &Logger,
+) -> HttpResponse {
+    if !config.writable {
+        return HttpResponse::create_error(HttpStatus::Forbidden, "Writing files is disabled");
+    }
 
-    match request.header.headers.get("SEC-WEBSOCKET-KEY") {
-        Some(key) => {
-            logger.log_info(format!("Key: {}", key)).unwrap();
-            let ws_handshake = ws::handle_handshake(key);
+    if !is_allowed(route, config) {
+        return create_not_found_response(request, config);
+    }
+
+    // The same path a GET for the route would read, routes escaping the directory (or for a
+    // `_headers` file) are refused.
+    let (dir, rest) = get_mount(route, config);
+    let path = match join_route(Path::new(dir), rest) {
+        Some(path) if !path.is_dir() && !is_headers_file(&path) => path,
+        _ => {
+            return HttpResponse::create_error(HttpStatus::Forbidden, "Can not write to this path")
+        }
+    };
+
+    let result = match request.header.verb {
+        HttpVerb::PUT => write_file(&path, request.body.as_deref().unwrap_or_default()),
+        _ => fs::remove_file(&path).map(|_| HttpStatus::NoContent),
+    };
+
+    // The modified time might not change if the file is written twice within its resolution.
+    cache.files.lock().unwrap().remove(&path);
+
+    match result {
+        Ok(status) => {
             logger
-                .log_debug(format!("Handshake: {}", ws_handshake))
+                .log_info(format!(
+                    "File {}. Path: {}",
+                    if request.header.verb == HttpVerb::PUT {
+                        "written"
+                    } else {
+                        "removed"
+                    },
+                    path.display()
+                ))
                 .unwrap();
 
-            let mut addition_headers = HashMap::new();
+            let changed = get_absolute_path(&path);
+            let notification = match (&request.header.verb, &status) {
+                (HttpVerb::DELETE, _) => Notification::FileRemoved(changed),
+                (_, HttpStatus::Created) => Notification::FileCreated(changed),
+                _ => Notification::FileUpdated(changed),
+            };
 
-            addition_headers.insert("Upgrade".to_string(), "websocket".to_string());
-            addition_headers.insert("Connection".to_string(), "Upgrade".to_string());
-            addition_headers.insert("Sec-WebSocket-Accept".to_string(), ws_handshake);
-            addition_headers.insert("Sec-WebSocket-Version".to_string(), "13".to_string());
+            if notifier.notify(notification).is_err() {
+                logger
+                    .log_warning("Could not send change notification".to_string())
+                    .unwrap();
+            }
 
-            let mut response = HttpResponse::create(
-                HttpStatus::SwitchingProtocols,
-                "text/plain".to_string(),
-                addition_headers,
-                None,
-            );
+            let mut addition_headers = IndexMap::new();
 
-            match stream.write(&mut response.to_bytes()) {
-                Ok(_) => {
-                    // Handle web socket connection
-                    let (tx, rx) = mpsc::channel();
-
-                    let thread = thread::spawn(move || loop {
-                        sub_sender.send(Subscription::new(tx.clone())).unwrap();
-
-                        match rx.recv() {
-                            Ok(notification) => {
-                                let (data, len) = match notification {
-                                    crate::messaging::Notification::FileCreated(_) => {
-                                        (b"File created", 12)
-                                    }
-                                    crate::messaging::Notification::FileUpdated(_) => {
-                                        (b"File updated", 12)
-                                    }
-                                    crate::messaging::Notification::FileRemoved(_) => {
-                                        (b"File removed", 12)
-                                    }
-                                    crate::messaging::Notification::FileRenamed(_, _) => {
-                                        (b"File renamed", 12)
-                                    }
-                                };
-
-                                let result =
-                                    stream.write(&ws::handle_write(&mut data.to_vec(), len));
-
-                                match result {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        logger
-                                            .log_error(format!(
-                                                "Failed sending to client, Error {}",
-                                                e
-                                            ))
-                                            .unwrap();
-                                        break;
-                                    }
-                                };
-                            }
-                            Err(_) => (),
-                        };
-                    });
-                }
-                Err(_) => todo!(),
+            if matches!(status, HttpStatus::Created) {
+                addition_headers.insert("Location".to_string(), route.to_string());
             }
+
+            HttpResponse::create(status, "text/plain".to_string(), addition_headers, None)
         }
+        Err(e) => create_file_error_response(e, request, config, logger),
+    }
+}
+
+/// Write a file, creating its parent directories. Returns [`HttpStatus::Created`] for a new file
+/// and [`HttpStatus::NoContent`] if an existing file was replaced.
+///
+/// # Errors
+///
+/// This function will return an error if the directories or file can not be written.
+fn write_file(path: &Path, body: &[u8]) -> io::Result<HttpStatus> {
+    let existed = path.exists();
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
 
-        None => todo!(),
+    fs::write(path, body)?;
+
+    Ok(if existed {
+        HttpStatus::NoContent
+    } else {
+        HttpStatus::Created
+    })
+}
+
+/// Get the absolute path of a file, as the file watcher reports it. The parent directory is
+/// resolved, so this works for files that have just been removed.
+fn get_absolute_path(path: &Path) -> String {
+    let absolute = match (path.parent().map(fs::canonicalize), path.file_name()) {
+        (Some(Ok(parent)), Some(name)) => parent.join(name),
+        _ => path.to_path_buf(),
     };
+
+    absolute.to_string_lossy().into_owned()
 }
 
-/// Get the content type from a path based on it's file extension.
-fn get_content_type(path: String) -> String {
-    match path {
-        _ if path.ends_with(".css") => "text/css".to_string(),
-        _ if path.ends_with(".js") => "application/javascript".to_string(),
-        _ if path.ends_with(".png") => "image/png".to_string(),
-        _ if path.ends_with(".jpg") || path.ends_with(".jpeg") => "image/jpeg".to_string(),
-        _ => "text/plain".to_string(),
+/// Returns the methods allowed for static files, for the `Allow` header.
+fn get_allowed_methods(config: &Config) -> &'static str {
+    if config.writable {
+        WRITABLE_ALLOWED_METHODS
+    } else {
+        STATIC_ALLOWED_METHODS
     }
 }
 
-/// Inject the handler script into a html document.
+/// Create the response to a TRACE request, echoing the request line and headers back as
+/// `message/http`. Credentials are never echoed. If TRACE is disabled a 405 is returned.
+fn create_trace_response(request: &HttpRequest, config: &Config) -> HttpResponse {
+    if !config.allow_trace {
+        return create_method_not_allowed_response(get_allowed_methods(config));
+    }
+
+    let mut header = request.header.clone();
+
+    for name in ["Authorization", "Proxy-Authorization", "Cookie"] {
+        header.headers.remove(name);
+    }
+
+    HttpResponse::create(
+        HttpStatus::Ok,
+        "message/http".to_string(),
+        IndexMap::new(),
+        Some(header.get_string().into_bytes()),
+    )
+}
+
+/// Create a 405 response with an `Allow` header listing the allowed methods.
+fn create_method_not_allowed_response(allowed: &str) -> HttpResponse {
+    let mut addition_headers = IndexMap::new();
+
+    addition_headers.insert("Allow".to_string(), allowed.to_string());
+
+    HttpResponse::create(
+        HttpStatus::MethodNotAllowed,
+        "text/plain".to_string(),
+        addition_headers,
+        Some(b"Method not allowed".to_vec()),
+    )
+}
+
+/// Send a response to a client, applying any server wide headers from the config and the
+/// request id. With `head_only` (the response to a HEAD request) the body is left out.
+/// Returns `true` if the response was sent.
+///
+/// Invalid headers are dropped (and logged) so they can not corrupt the response, a response
+/// that is still invalid is replaced with a 500.
 ///
 /// # Panics
 ///
-/// Panics if the regex can not be created.
-fn inject_script(document: &String) -> String {
-    let re = Regex::new("</body>").unwrap();
+/// Panics if there is an issue with the logger.
+fn send_response(
+    stream: &mut TcpStream,
+    mut response: HttpResponse,
+    head_only: bool,
+    config: &Config,
+    request_id: &str,
+    logger: &Logger,
+) -> bool {
+    response.header.set_server(config.server_name.as_deref());
+    response
+        .header
+        .headers
+        .insert("X-Request-Id".to_string(), request_id.to_string());
+
+    for name in response.header.remove_invalid_headers() {
+        logger
+            .log_warning(format!("Dropped invalid response header: {:?}", name))
+            .unwrap();
+    }
 
-    let replace = "<script>var ws = new WebSocket('ws://127.0.0.1:8080/ws/notify'); ws.onopen = function(evt) { console.log('Connected'); };  ws.onmessage = function (evt) { location.reload();  };</script>\n</body>";
+    if let Err(e) = response.header.validate() {
+        logger
+            .log_error(format!(
+                "Invalid response, sending a 500 instead. Error: {}",
+                e
+            ))
+            .unwrap();
 
-    re.replace(document, replace).to_string()
+        response = HttpResponse::create_error(HttpStatus::InternalError, "Invalid response");
+        response.header.set_server(config.server_name.as_deref());
+        response
+            .header
+            .headers
+            .insert("X-Request-Id".to_string(), request_id.to_string());
+    }
+
+    let mut writer = CountingWriter::new(stream);
+
+    RESPONSE_STARTED.with(|started| started.set(true));
+
+    let bytes = if head_only {
+        response.to_head_bytes()
+    } else {
+        response.to_bytes()
+    };
+
+    match writer.write_all(&bytes) {
+        Ok(_) => {
+            logger
+                .log_debug(format!(
+                    "Response sent. Status: {}, Bytes: {}",
+                    response.header.status.get_code(),
+                    writer.count()
+                ))
+                .unwrap();
+            true
+        }
+        Err(e) => {
+            logger
+                .log_error(format!(
+                    "Failed sending to client, Error {}. Bytes sent: {}",
+                    e,
+                    writer.count()
+                ))
+                .unwrap();
+            false
+        }
+    }
+}
+
+/// Create a cacheable version of a response with `Cache-Control` and `ETag` headers. If the request's
+/// `If-None-Match` matches the ETag a 304 is returned without the body. Routes configured as
+/// `no_store` are sent without an ETag.
+///
+/// Text based bodies are compressed with the best encoding the client accepts (if enabled in the
+/// config), the ETag is created from the encoded body so each encoding has its own. Range
+/// requests are served from the uncompressed body, as that is what the byte offsets refer to.
+fn create_cacheable_response(
+    request: &HttpRequest,
+    config: &Config,
+    response: HttpResponse,
+) -> HttpResponse {
+    let content_type = response
+        .header
+        .headers
+        .get("Content-Type")
+        .cloned()
+        .unwrap_or_default();
+    let body = response.body.unwrap_or_default();
+
+    let compressible = config.compression && compression::is_compressible(&content_type);
+
+    let range_requested = request.header.headers.contains_key("RANGE");
+
+    let encoding = match request.header.headers.get("ACCEPT-ENCODING") {
+        Some(accept_encoding)
+            if compressible && !range_requested && body.len() >= compression::MIN_COMPRESS_SIZE =>
+        {
+            compression::select_encoding(accept_encoding)
+        }
+        _ => ContentEncoding::Identity,
+    };
+
+    // Fall back to the uncompressed body if compression fails.
+    let (encoding, body) = match compression::compress(&body, encoding) {
+        Ok(compressed) => (encoding, compressed),
+        Err(_) => (ContentEncoding::Identity, body),
+    };
+
+    let cache_control = config.cache_control_for(&request.header.route);
+    let no_store = cache_control == NO_STORE;
+
+    let mut addition_headers = IndexMap::new();
+
+    addition_headers.insert("Cache-Control".to_string(), cache_control);
+
+    // Responses that must not be stored have no validators, so are never revalidated.
+    let not_modified = if no_store {
+        false
+    } else {
+        let etag = create_etag(&body);
+
+        let not_modified = request
+            .header
+            .headers
+            .get("IF-NONE-MATCH")
+            .map(|v| etag_matches(v, &etag))
+            .unwrap_or(false);
+
+        addition_headers.insert("ETag".to_string(), etag);
+
+        not_modified
+    };
+
+    if encoding != ContentEncoding::Identity {
+        addition_headers.insert(
+            "Content-Encoding".to_string(),
+            encoding.get_str().to_string(),
+        );
+    }
+
+    let mut response = if not_modified {
+        HttpResponse::create(
+            HttpStatus::NotModified,
+            content_type,
+            addition_headers,
+            None,
+        )
+    } else {
+        addition_headers.insert("Accept-Ranges".to_string(), "bytes".to_string());
+
+        match get_range(request, addition_headers.get("ETag")) {
+            Some(range) => create_range_response(range, content_type, addition_headers, body),
+            None => {
+                HttpResponse::create(HttpStatus::Ok, content_type, addition_headers, Some(body))
+            }
+        }
+    };
+
+    if compressible {
+        response.header.add_vary("Accept-Encoding");
+    }
+
+    response
+}
+
+/// Get the value of a request's `Range` header, if the range should be applied.
+///
+/// Requests for multiple ranges are served in full, as is a request with an `If-Range` ETag that
+/// does not match the current ETag (the client's partial copy is out of date).
+fn get_range<'a>(request: &'a HttpRequest, etag: Option<&String>) -> Option<&'a str> {
+    let range = request.header.headers.get("RANGE")?;
+
+    let if_range_matches = match request.header.headers.get("IF-RANGE") {
+        Some(if_range) => etag.is_some_and(|etag| if_range.trim() == etag),
+        None => true,
+    };
+
+    if range.contains(',') || !if_range_matches {
+        None
+    } else {
+        Some(range)
+    }
+}
+
+/// Create the response to a `Range` request, a 206 with the requested bytes or a 416 if the range
+/// is malformed or can not be satisfied.
+fn create_range_response(
+    range: &str,
+    content_type: String,
+    mut addition_headers: IndexMap<String, String>,
+    body: Vec<u8>,
+) -> HttpResponse {
+    match parse_range(range, body.len()) {
+        Some((start, end)) => {
+            addition_headers.insert(
+                "Content-Range".to_string(),
+                format!("bytes {}-{}/{}", start, end, body.len()),
+            );
+
+            HttpResponse::create(
+                HttpStatus::PartialContent,
+                content_type,
+                addition_headers,
+                Some(body[start..=end].to_vec()),
+            )
+        }
+        None => {
+            addition_headers.insert(
+                "Content-Range".to_string(),
+                format!("bytes */{}", body.len()),
+            );
+
+            HttpResponse::create(
+                HttpStatus::RangeNotSatisfiable,
+                "text/plain".to_string(),
+                addition_headers,
+                Some(b"Range not satisfiable".to_vec()),
+            )
+        }
+    }
+}
+
+/// Create the response for a request not matching a route or a file, from the config's not found
+/// handler if it has one.
+fn create_not_found_response(request: &HttpRequest, config: &Config) -> HttpResponse {
+    match &config.not_found {
+        Some(handler) => handler(request),
+        None => HttpResponse::create_error(HttpStatus::NotFound, "Not found"),
+    }
+}
+
+/// Create a html listing of a directory's entries, using the configured template if there is
+/// one.
+fn create_directory_listing(
+    route: &str,
+    dir: &Path,
+    config: &Config,
+    logger: &Logger,
+) -> HttpResponse {
+    let entries = match listing::read_entries(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            logger
+                .log_error(format!("Could not read directory. Error: {}", e))
+                .unwrap();
+
+            return HttpResponse::create_error(
+                HttpStatus::InternalError,
+                "Could not read directory",
+            );
+        }
+    };
+
+    // Read on each request so changes to the template show on reload.
+    let template = match &config.directory_template {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(template) => template,
+            Err(e) => {
+                logger
+                    .log_warning(format!(
+                        "Could not read directory template, using the default. Path: {}, Error: {}",
+                        path, e
+                    ))
+                    .unwrap();
+
+                listing::DEFAULT_TEMPLATE.to_string()
+            }
+        },
+        None => listing::DEFAULT_TEMPLATE.to_string(),
+    };
+
+    HttpResponse::create(
+        HttpStatus::Ok,
+        "text/html".to_string(),
+        IndexMap::new(),
+        Some(listing::render(&template, route, &entries).into_bytes()),
+    )
+}
+
+/// Create the response for an error reading a file, a not found response if the file does not
+/// exist (or is a directory) otherwise a 500.
+fn create_file_error_response(
+    error: io::Error,
+    request: &HttpRequest,
+    config: &Config,
+    logger: &Logger,
+) -> HttpResponse {
+    match error.kind() {
+        ErrorKind::NotFound | ErrorKind::IsADirectory => create_not_found_response(request, config),
+        _ => {
+            logger
+                .log_error(format!("Could not read file. Error: {}", error))
+                .unwrap();
+
+            HttpResponse::create_error(HttpStatus::InternalError, "Could not read file")
+        }
+    }
+}
+
+/// Add the configured charset for a response's content type to its `Content-Type` header.
+/// Content types that already have a charset are left as they are.
+fn set_charset(response: &mut HttpResponse, config: &Config) {
+    if let Some(content_type) = response.header.headers.get_mut("Content-Type") {
+        if content_type.to_lowercase().contains("charset=") {
+            return;
+        }
+
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_lowercase();
+
+        if let Some(charset) = config.charsets.get(&mime) {
+            *content_type = format!("{}; charset={}", content_type, charset);
+        }
+    }
+}
+
+/// Replace the content type of a file whose type could not be determined with the configured
+/// default.
+fn set_default_content_type(response: &mut HttpResponse, config: &Config) {
+    if let Some(content_type) = response.header.headers.get_mut("Content-Type") {
+        if content_type == DEFAULT_CONTENT_TYPE {
+            *content_type = config.default_content_type.clone();
+        }
+    }
+}
+
+/// Mark an `application/octet-stream` response as an attachment if configured, so browsers
+/// download it rather than trying to display it.
+fn add_download_disposition(response: &mut HttpResponse, config: &Config) {
+    let headers = &mut response.header.headers;
+
+    if config.download_octet_stream
+        && headers
+            .get("Content-Type")
+            .is_some_and(|content_type| content_type == "application/octet-stream")
+    {
+        headers.insert("Content-Disposition".to_string(), "attachment".to_string());
+    }
+}
+
+/// Check if a path is for a `_headers` file.
+fn is_headers_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name == HEADERS_FILE_NAME)
+}
+
+/// Get a file path from a route. The directory of the mount with the longest matching prefix is
+/// used, or the base path if no mount matches. A route for a directory resolves to its index
+/// file (see [`resolve_index`]) if it has one and is within the configured depth.
+///
+/// Returns `None` if the route tries to escape the directory (i.e. contains a `..` segment) or
+/// is for a `_headers` file, which configures the server rather than being served.
+fn get_path(route: &str, config: &Config) -> Option<PathBuf> {
+    // `/index` is kept as an alias of the root for existing links.
+    let route = if route == "/index" { "/" } else { route };
+    let (dir, rest) = get_mount(route, config);
+
+    let path = join_route(Path::new(dir), rest)?;
+
+    if is_headers_file(&path) {
+        return None;
+    }
+
+    let depth = rest
+        .split('/')
+        .filter(|s| !s.is_empty() && *s != ".")
+        .count();
+
+    if config
+        .index_depth
+        .is_none_or(|max_depth| depth <= max_depth)
+    {
+        if let Some(index) = resolve_index(Path::new(dir), rest, &config.index_names) {
+            return Some(index);
+        }
+    }
+
+    Some(path)
+}
+
+/// Resolve a route for a directory to the file to serve, the first of `index_names` that exists
+/// in the directory.
+///
+/// Returns `None` if the route is not for a directory, the directory has no index file or the
+/// route tries to escape the base directory.
+pub fn resolve_index(base: &Path, route: &str, index_names: &[String]) -> Option<PathBuf> {
+    let dir = join_route(base, route)?;
+
+    if !dir.is_dir() {
+        return None;
+    }
+
+    index_names
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Join the segments of a route onto a directory. Returns `None` if the route contains a `..`
+/// segment.
+fn join_route(base: &Path, route: &str) -> Option<PathBuf> {
+    let mut path = base.to_path_buf();
+
+    for segment in route.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            _ => path.push(segment),
+        }
+    }
+
+    Some(path)
+}
+
+/// Check if a path is for an index file, the reload script is injected into these.
+fn is_index_file(path: &Path, config: &Config) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| config.index_names.iter().any(|index| index == name))
+}
+
+/// Get the directory a route is served from and the rest of the route within it. The directory
+/// of the mount with the longest matching prefix is used, or the base path if no mount matches.
+fn get_mount<'a>(route: &'a str, config: &'a Config) -> (&'a str, &'a str) {
+    config
+        .mounts
+        .iter()
+        .map(|(prefix, dir)| (prefix.trim_end_matches('/'), dir))
+        .filter(|(prefix, _)| {
+            route.starts_with(prefix)
+                && matches!(route.as_bytes().get(prefix.len()), None | Some(b'/'))
+        })
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(prefix, dir)| (dir.as_str(), &route[prefix.len()..]))
+        .unwrap_or((config.base_path.as_str(), route))
+}
+
+/// Add the headers from the nearest `_headers` file to a file's response, replacing any headers
+/// with the same name.
+fn add_header_file_headers(
+    response: &mut HttpResponse,
+    path: &Path,
+    root: &Path,
+    cache: &ServerCache,
+    logger: &Logger,
+) {
+    for (name, value) in cache.header_files.get_headers(path, root, logger) {
+        response.header.headers.insert(name, value);
+    }
+}
+
+/// Handle a WebSocket connection.
+///
+/// # Panics
+///
+/// Panics if a failure with the logger.
+fn handle_ws_connection(
+    request: HttpRequest,
+    mut stream: TcpStream,
+    request_id: &str,
+    sub_sender: Sender<Subscription>,
+    logger: Logger,
+    config: Arc<Config>,
+) {
+    logger.log_debug("WS connection".to_string()).unwrap();
+
+    // A plain request (i.e. someone navigating to the route in a browser) can not be upgraded,
+    // HEAD requests get the same response without the body.
+    if !is_upgrade_request(&request) || request.header.verb == HttpVerb::HEAD {
+        logger
+            .log_info("Non upgrade request to WS route".to_string())
+            .unwrap();
+
+        let mut addition_headers = IndexMap::new();
+
+        addition_headers.insert("Upgrade".to_string(), "websocket".to_string());
+        addition_headers.insert("Connection".to_string(), "Upgrade".to_string());
+
+        let response = HttpResponse::create(
+            HttpStatus::UpgradeRequired,
+            "text/html".to_string(),
+            addition_headers,
+            Some(WS_UPGRADE_REQUIRED_PAGE.as_bytes().to_vec()),
+        );
+        let head_only = request.header.verb == HttpVerb::HEAD;
+
+        send_response(
+            &mut stream,
+            response,
+            head_only,
+            &config,
+            request_id,
+            &logger,
+        );
+
+        return;
+    }
+
+    // The key is required to complete the handshake, an empty one is treated as missing.
+    match request
+        .header
+        .headers
+        .get("SEC-WEBSOCKET-KEY")
+        .filter(|key| !key.trim().is_empty())
+    {
+        Some(key) => {
+            logger.log_info(format!("Key: {}", key)).unwrap();
+            let ws_handshake = ws::handle_handshake(key);
+            logger
+                .log_debug(format!("Handshake: {}", ws_handshake))
+                .unwrap();
+
+            let mut addition_headers = IndexMap::new();
+
+            addition_headers.insert("Upgrade".to_string(), "websocket".to_string());
+            addition_headers.insert("Connection".to_string(), "Upgrade".to_string());
+            addition_headers.insert("Sec-WebSocket-Accept".to_string(), ws_handshake);
+            addition_headers.insert("Sec-WebSocket-Version".to_string(), "13".to_string());
+
+            // Notifications are compressed if the client offers to decompress them.
+            let deflate = config.ws_deflate
+                && request
+                    .header
+                    .headers
+                    .get("SEC-WEBSOCKET-EXTENSIONS")
+                    .is_some_and(|extensions| ws::accepts_permessage_deflate(extensions));
+
+            if deflate {
+                addition_headers.insert(
+                    "Sec-WebSocket-Extensions".to_string(),
+                    ws::PERMESSAGE_DEFLATE_RESPONSE.to_string(),
+                );
+            }
+
+            let response = HttpResponse::create(
+                HttpStatus::SwitchingProtocols,
+                "text/plain".to_string(),
+                addition_headers,
+                None,
+            );
+
+            if send_response(&mut stream, response, false, &config, request_id, &logger) {
+                // Handle web socket connection
+                let (tx, rx) = mpsc::channel();
+
+                // Subscribe once, the hub sends every notification to the same channel.
+                let subscription = Subscription::new(tx);
+                let unsubscriber = subscription.get_unsubscriber();
+                sub_sender.send(subscription).unwrap();
+
+                // Messages from the client are read on their own thread.
+                match stream.try_clone() {
+                    Ok(read_stream) => {
+                        let read_logger = logger.clone();
+                        let max_message_size = config.ws_max_message_size;
+
+                        thread::spawn(move || {
+                            read_ws_messages(
+                                read_stream,
+                                max_message_size,
+                                unsubscriber,
+                                read_logger,
+                            )
+                        });
+                    }
+                    Err(e) => logger
+                        .log_warning(format!("Could not read from WS client. Error: {}", e))
+                        .unwrap(),
+                }
+
+                let css_hot_reload = config.css_hot_reload;
+
+                // Runs until the hub drops the subscription, after the client unsubscribes or a
+                // send fails.
+                thread::spawn(move || {
+                    while let Ok(notification) = rx.recv() {
+                        let message = create_notification_message(&notification, css_hot_reload);
+                        // Uncompressed frames are still valid if compression fails.
+                        let frame = if deflate {
+                            ws::handle_write_deflated(message.as_bytes(), ws::Opcode::Text)
+                                .unwrap_or_else(|_| {
+                                    ws::handle_write(message.as_bytes(), ws::Opcode::Text)
+                                })
+                        } else {
+                            ws::handle_write(message.as_bytes(), ws::Opcode::Text)
+                        };
+                        let result = stream.write_all(&frame);
+
+                        match result {
+                            Ok(_) => {}
+                            Err(e) => {
+                                logger
+                                    .log_error(format!("Failed sending to client, Error {}", e))
+                                    .unwrap();
+
+                                // The connection is most likely gone but try to close it
+                                // cleanly in case the failure was temporary.
+                                let _ = stream.write_all(&ws::handle_close(
+                                    ws::CLOSE_SERVER_ERROR,
+                                    "Failed sending notification",
+                                ));
+                                let _ = stream.shutdown(Shutdown::Both);
+                                break;
+                            }
+                        };
+                    }
+                });
+            }
+        }
+
+        None => {
+            logger
+                .log_warning("WS connection missing Sec-WebSocket-Key header".to_string())
+                .unwrap();
+
+            let response = HttpResponse::create_error(
+                HttpStatus::BadRequest,
+                "Missing Sec-WebSocket-Key header",
+            );
+
+            send_response(&mut stream, response, false, &config, request_id, &logger);
+        }
+    };
+}
+
+/// Handle a server-sent events connection, an alternative to the WebSocket for clients that can
+/// not upgrade. The response is left open and each notification is sent as a `reload` event, the
+/// connection stays open until the client closes it.
+///
+/// # Panics
+///
+/// Panics if a failure with the logger.
+fn handle_sse_connection(
+    request: HttpRequest,
+    mut stream: TcpStream,
+    request_id: &str,
+    sub_sender: Sender<Subscription>,
+    logger: Logger,
+    config: Arc<Config>,
+) {
+    let head_only = request.header.verb == HttpVerb::HEAD;
+
+    if !accepts_event_stream(&request) {
+        logger
+            .log_info("Event stream requested without accepting text/event-stream".to_string())
+            .unwrap();
+
+        let response = HttpResponse::create_error(
+            HttpStatus::NotAcceptable,
+            "Only text/event-stream is available",
+        );
+
+        send_response(
+            &mut stream,
+            response,
+            head_only,
+            &config,
+            request_id,
+            &logger,
+        );
+        return;
+    }
+
+    let mut addition_headers = IndexMap::new();
+
+    addition_headers.insert("Cache-Control".to_string(), "no-cache".to_string());
+    addition_headers.insert(
+        "Connection".to_string(),
+        if head_only { "close" } else { "keep-alive" }.to_string(),
+    );
+
+    let mut response = HttpResponse::create(
+        HttpStatus::Ok,
+        "text/event-stream".to_string(),
+        addition_headers,
+        None,
+    );
+
+    // The events have no length, they end when the connection closes.
+    response.header.headers.remove("Content-Length");
+
+    if !send_response(
+        &mut stream,
+        response,
+        head_only,
+        &config,
+        request_id,
+        &logger,
+    ) || head_only
+    {
+        return;
+    }
+
+    let (tx, rx) = mpsc::channel();
+
+    // Subscribe once, the hub sends every notification to the same channel.
+    let subscription = Subscription::new(tx);
+    let unsubscriber = subscription.get_unsubscriber();
+    sub_sender.send(subscription).unwrap();
+
+    // The client never sends anything else, so a read returning means it closed the connection.
+    match stream.try_clone() {
+        Ok(mut read_stream) => {
+            let unsubscriber = unsubscriber.clone();
+            let read_logger = logger.clone();
+
+            thread::spawn(move || {
+                let _ = read_stream.set_read_timeout(None);
+                let _ = read_stream.read(&mut [0; 64]);
+
+                read_logger
+                    .log_info("Event stream closed by client".to_string())
+                    .unwrap();
+                unsubscriber.unsubscribe();
+                let _ = read_stream.shutdown(Shutdown::Both);
+            });
+        }
+        Err(e) => logger
+            .log_warning(format!(
+                "Could not read from event stream client. Error: {}",
+                e
+            ))
+            .unwrap(),
+    }
+
+    let css_hot_reload = config.css_hot_reload;
+
+    thread::spawn(move || loop {
+        let event = match rx.recv_timeout(SSE_KEEP_ALIVE) {
+            Ok(notification) => create_sse_event(&notification, css_hot_reload),
+            // Comments are ignored by clients.
+            Err(RecvTimeoutError::Timeout) => ": keep-alive\n\n".to_string(),
+            // The hub has dropped the subscription.
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        if let Err(e) = stream.write_all(event.as_bytes()) {
+            logger
+                .log_info(format!("Event stream closed. Error: {}", e))
+                .unwrap();
+            unsubscriber.unsubscribe();
+            let _ = stream.shutdown(Shutdown::Both);
+            break;
+        }
+    });
+}
+
+/// Check a request's `Accept` header lists `text/event-stream`, as `EventSource` sends.
+fn accepts_event_stream(request: &HttpRequest) -> bool {
+    request.header.headers.get("ACCEPT").is_some_and(|accept| {
+        accept.split(',').any(|media_type| {
+            media_type
+                .split(';')
+                .next()
+                .is_some_and(|t| t.trim().eq_ignore_ascii_case("text/event-stream"))
+        })
+    })
+}
+
+/// Read messages from a WebSocket client until it closes the connection, then end its
+/// subscription. The connection is only used to send notifications, so messages are logged and
+/// otherwise ignored. If the client sends an invalid or oversized message the connection is
+/// closed with the matching close code.
+///
+/// # Panics
+///
+/// Panics if there is an issue with the logger.
+fn read_ws_messages(
+    mut stream: TcpStream,
+    max_message_size: usize,
+    unsubscriber: Unsubscriber,
+    logger: Logger,
+) {
+    let mut reader = ws::MessageReader::new(max_message_size);
+
+    // The read timeout is for the http request, a WebSocket client can be idle indefinitely.
+    let _ = stream.set_read_timeout(None);
+
+    loop {
+        match reader.read_message(&mut stream) {
+            Ok(message) if message.opcode == ws::Opcode::Close => {
+                logger
+                    .log_info("WS connection closed by client".to_string())
+                    .unwrap();
+
+                // Complete the closing handshake.
+                let _ = stream.write_all(&ws::handle_close(ws::CLOSE_NORMAL, ""));
+                let _ = stream.shutdown(Shutdown::Both);
+                break;
+            }
+            Ok(message) => logger
+                .log_debug(format!(
+                    "WS message received. Opcode: {:?}, Length: {}",
+                    message.opcode,
+                    message.payload.len()
+                ))
+                .unwrap(),
+            Err(ws::ReadError::Closed) => break,
+            Err(e) => {
+                logger
+                    .log_warning(format!("Invalid WS message. Error: {}", e.get_str()))
+                    .unwrap();
+
+                // Tell the client why before closing the connection.
+                if let Some(code) = e.get_close_code() {
+                    let _ = stream.write_all(&ws::handle_close(code, e.get_str()));
+                }
+
+                let _ = stream.shutdown(Shutdown::Both);
+                break;
+            }
+        }
+    }
+
+    // The notification thread stops once the hub drops the subscription.
+    unsubscriber.unsubscribe();
+}
+
+/// Check if a request is asking to be upgraded to a WebSocket connection.
+fn is_upgrade_request(request: &HttpRequest) -> bool {
+    request
+        .header
+        .headers
+        .get("UPGRADE")
+        .map(|v| v.trim().eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false)
+}
+
+/// Add the dev server's own routes to a router, `GET /__dev/changes` lists the recent file
+/// changes (most recent first) as JSON, `GET /__dev/metrics` returns the number of connected
+/// reload clients and file change notifications that overflowed the notification channel and
+/// `GET /__dev/status` reports the server's version and uptime. Each also answers HEAD.
+///
+/// # Errors
+///
+/// This function will return an error if a route can not be added.
+pub fn add_dev_routes(
+    router: &mut Router,
+    notifications: RecentNotifications,
+    subscribers: Arc<AtomicUsize>,
+    overflowed: Arc<AtomicUsize>,
+) -> Result<(), &'static str> {
+    let started = Instant::now();
+    let status_subscribers = subscribers.clone();
+
+    // Readable from tools served on other origins.
+    router.add_with_cors(
+        HttpVerb::GET,
+        "/__dev/status",
+        Box::new(move |_, _| {
+            let status = serde_json::json!({
+                "status": "ok",
+                "version": env!("CARGO_PKG_VERSION"),
+                "uptime_seconds": started.elapsed().as_secs(),
+                "subscribers": status_subscribers.load(Ordering::SeqCst),
+            });
+
+            HttpResponse::json(HttpStatus::Ok, &status)
+                .map_err(|e| HandlerError::new(HttpStatus::InternalError, e))
+        }),
+        CorsPolicy::permissive(),
+    )?;
+
+    router.add_with_cors(
+        HttpVerb::GET,
+        "/__dev/metrics",
+        Box::new(move |_, _| {
+            let metrics = serde_json::json!({
+                "subscribers": subscribers.load(Ordering::SeqCst),
+                "notifications_overflowed": overflowed.load(Ordering::SeqCst),
+            });
+
+            HttpResponse::json(HttpStatus::Ok, &metrics)
+                .map_err(|e| HandlerError::new(HttpStatus::InternalError, e))
+        }),
+        CorsPolicy::permissive(),
+    )?;
+
+    router.add_with_cors(
+        HttpVerb::GET,
+        "/__dev/changes",
+        Box::new(move |_, _| {
+            let changes: Vec<serde_json::Value> = notifications
+                .list()
+                .iter()
+                .filter_map(|recent| {
+                    recent.notification.get_path().map(|path| {
+                        serde_json::json!({
+                            "kind": recent.notification.get_kind().get_str(),
+                            "path": path,
+                            "time": recent.time,
+                        })
+                    })
+                })
+                .collect();
+
+            HttpResponse::json(HttpStatus::Ok, &changes)
+                .map_err(|e| HandlerError::new(HttpStatus::InternalError, e))
+        }),
+        CorsPolicy::permissive(),
+    )
+}
+
+/// Parse a bind address, either an IPv4 (`127.0.0.1:8080`) or bracketed IPv6 (`[::1]:8080`)
+/// address with a port.
+///
+/// # Errors
+///
+/// This function will return an error if the address is not a valid ip address and port.
+pub fn parse_address(address: &str) -> Result<SocketAddr, &'static str> {
+    address.trim().parse::<SocketAddr>().map_err(|_| {
+        "Invalid bind address, expected an ip address and port such as 127.0.0.1:8080 or [::1]:8080"
+    })
+}
+
+/// Bind a listener with `SO_REUSEADDR` set (on Unix, so the server can restart while the old
+/// socket is in `TIME_WAIT`) and room for `backlog` connections waiting to be accepted.
+///
+/// # Errors
+///
+/// This function will return an error if the socket can not be created, configured or bound.
+fn bind_listener(address: SocketAddr, backlog: u32) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+
+    // On Windows this would allow another process to take over the port.
+    if cfg!(unix) {
+        socket.set_reuse_address(true)?;
+    }
+
+    socket.bind(&address.into())?;
+    socket.listen(i32::try_from(backlog).unwrap_or(i32::MAX))?;
+
+    Ok(socket.into())
+}
+
+/// Format the client's IP address for logging.
+fn format_client(request: &HttpRequest, config: &Config) -> String {
+    match request.client_ip(config.trust_forwarded) {
+        Some(ip) => ip.to_string(),
+        None => "unknown".to_string(),
+    }
+}
+
+/// Check if a route may be served, always true when no allowlist is configured.
+fn is_allowed(route: &str, config: &Config) -> bool {
+    config
+        .allowlist
+        .as_ref()
+        .is_none_or(|allowlist| allowlist.contains(route))
+}
+
+/// Check if the reload script should be injected into a response. Injection can be disabled
+/// globally in the config or per request with the `noreload` query parameter (`?noreload=1`),
+/// and only html documents up to the configured maximum size are injected.
+///
+/// # Panics
+///
+/// Panics if there is an issue with the logger.
+fn should_inject_script(
+    request: &HttpRequest,
+    config: &Config,
+    response: &HttpResponse,
+    logger: &Logger,
+) -> bool {
+    let disabled_by_request =
+        matches!(request.header.get_query_param("noreload"), Some(v) if v != "0");
+
+    if !config.inject_reload || disabled_by_request {
+        return false;
+    }
+
+    if !is_html(response) {
+        logger
+            .log_debug("Reload script not injected, not a html document".to_string())
+            .unwrap();
+        return false;
+    }
+
+    let size = response.body.as_ref().map_or(0, |body| body.len());
+
+    if size > config.max_inject_size {
+        logger
+            .log_debug(format!(
+                "Reload script not injected, document too large ({} bytes)",
+                size
+            ))
+            .unwrap();
+        return false;
+    }
+
+    true
+}
+
+/// Create the server-sent event for a notification, a `reload` event with the same data as the
+/// WebSocket message (see [`create_notification_message`]).
+fn create_sse_event(notification: &Notification, css_hot_reload: bool) -> String {
+    format!(
+        "event: reload\ndata: {}\n\n",
+        create_notification_message(notification, css_hot_reload)
+    )
+}
+
+/// Create the message sent to WebSocket clients for a notification, a JSON object with the
+/// notification's `kind`, `path` (`null` if it is not for a file) and the reload `strategy`.
+fn create_notification_message(notification: &Notification, css_hot_reload: bool) -> String {
+    serde_json::json!({
+        "kind": notification.get_kind().get_str(),
+        "path": notification.get_path(),
+        "strategy": notification.get_reload_strategy(css_hot_reload).get_str(),
+    })
+    .to_string()
+}
+
+/// Check if a response is a html document.
+fn is_html(response: &HttpResponse) -> bool {
+    response
+        .header
+        .headers
+        .get("Content-Type")
+        .is_some_and(|content_type| content_type.starts_with("text/html"))
+}
+
+/// Matches the `{{KEY}}` placeholders replaced by [`apply_template`].
+static TEMPLATE_PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+
+/// Matches the end of the body the reload script is injected before.
+static BODY_END: OnceLock<Regex> = OnceLock::new();
+
+/// Replace the `{{KEY}}` placeholders in a document with their values, placeholders without a
+/// value are left as they are.
+///
+/// # Panics
+///
+/// Panics if the regex can not be created.
+fn apply_template(document: &str, vars: &HashMap<String, String>) -> String {
+    let re = TEMPLATE_PLACEHOLDER.get_or_init(|| Regex::new(r"\{\{([A-Za-z0-9_]+)\}\}").unwrap());
+
+    re.replace_all(document, |captures: &regex::Captures| {
+        match vars.get(&captures[1]) {
+            Some(value) => value.clone(),
+            None => captures[0].to_string(),
+        }
+    })
+    .into_owned()
+}
+
+/// Inject the handler script into a html document.
+///
+/// # Panics
+///
+/// Panics if the regex can not be created.
+fn inject_script(document: &str, config: &Config) -> String {
+    let re = BODY_END.get_or_init(|| Regex::new("</body>").unwrap());
+
+    let script = RELOAD_SCRIPT
+        .replace(
+            "{{debounce}}",
+            &config.reload_debounce.as_millis().to_string(),
+        )
+        .replace(
+            "{{max_backoff}}",
+            &config.reconnect_max_backoff.as_millis().to_string(),
+        )
+        .replace("{{sse_fallback}}", &config.sse_fallback.to_string())
+        .replace(
+            "{{fallback_timeout}}",
+            &WS_FALLBACK_TIMEOUT.as_millis().to_string(),
+        );
+    let replace = format!("<script>{}</script>\n</body>", script);
+
+    re.replace(document, NoExpand(&replace)).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::{
+        http::common::{HttpResponseHeader, DEFAULT_BUFFER_SIZE},
+        messaging::{MessageHub, ServerChanges},
+    };
+
+    /// A server on a free port, serving files from its own temporary directory, with a message
+    /// hub for its notifications. The server and hub are shut down and the directory removed
+    /// when dropped.
+    struct TestServer {
+        server: Option<Server>,
+        hub: Option<MessageHub>,
+        root: PathBuf,
+        _log: Log,
+    }
+
+    impl TestServer {
+        /// Start a server with the default config, changed by `configure`, serving `files`
+        /// (`(route, contents)` pairs).
+        fn start(files: &[(&str, &str)], configure: impl FnOnce(&mut Config)) -> TestServer {
+            static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+            let root = std::env::temp_dir().join(format!(
+                "dev_server_test_{}_{}",
+                std::process::id(),
+                NEXT_ID.fetch_add(1, Ordering::SeqCst)
+            ));
+
+            for (route, contents) in files {
+                let path = root.join(route.trim_start_matches('/'));
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, contents).unwrap();
+            }
+
+            let mut config = Config {
+                address: "127.0.0.1:0".to_string(),
+                base_path: root.to_string_lossy().into_owned(),
+                workers: 2,
+                ..Default::default()
+            };
+            configure(&mut config);
+
+            let log = Log::start().unwrap();
+            log.set_level(crate::logging::common::LogLevel::Error);
+
+            let (sub_tx, sub_rx) = mpsc::channel();
+            let (not_tx, not_rx) = mpsc::channel();
+            let hub = MessageHub::start(sub_rx, not_rx, Duration::from_secs(60), 10, &log);
+            let notifier = ChangeNotifier::new(not_tx, ServerChanges::new(Duration::ZERO));
+            let server = Server::start(config, &log, sub_tx, notifier).unwrap();
+
+            TestServer {
+                server: Some(server),
+                hub: Some(hub),
+                root,
+                _log: log,
+            }
+        }
+
+        fn connect(&self) -> TcpStream {
+            let stream = TcpStream::connect(self.server.as_ref().unwrap().get_address()).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            stream
+        }
+
+        /// Send a raw request and read until the server closes the connection.
+        fn send(&self, request: &str) -> Vec<u8> {
+            let mut stream = self.connect();
+            let mut response = Vec::new();
+
+            stream.write_all(request.as_bytes()).unwrap();
+            stream.read_to_end(&mut response).unwrap();
+            response
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            if let Some(server) = self.server.take() {
+                server.shutdown();
+            }
+
+            if let Some(hub) = self.hub.take() {
+                hub.stop();
+            }
+
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    /// Wait (up to five seconds) for a condition another thread sets.
+    fn wait_for(condition: impl Fn() -> bool) {
+        let start = Instant::now();
+
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Read from a stream until `end` has been received, returning everything read.
+    fn read_until(stream: &mut TcpStream, end: &[u8]) -> Vec<u8> {
+        let mut raw = Vec::new();
+        let mut buffer = [0; 1024];
+
+        while !raw.windows(end.len()).any(|w| w == end) {
+            let n = stream.read(&mut buffer).unwrap();
+            assert!(n > 0, "closed before {:?}", String::from_utf8_lossy(end));
+            raw.extend_from_slice(&buffer[..n]);
+        }
+
+        raw
+    }
+
+    /// Split a raw response into its parsed header and the bytes after it.
+    fn split_response(raw: &[u8]) -> (HttpResponseHeader, Vec<u8>) {
+        let (header, body_start) = HttpResponseHeader::create_from_buffer(raw).unwrap();
+        (header, raw[body_start..].to_vec())
+    }
+
+    /// Split the raw responses to pipelined requests, using their `Content-Length` headers.
+    fn split_responses(mut raw: &[u8]) -> Vec<(HttpResponseHeader, Vec<u8>)> {
+        let mut responses = Vec::new();
+
+        while !raw.is_empty() {
+            let (header, body_start) = HttpResponseHeader::create_from_buffer(raw).unwrap();
+            let length: usize = header
+                .headers
+                .get("Content-Length")
+                .unwrap()
+                .parse()
+                .unwrap();
+
+            responses.push((header, raw[body_start..body_start + length].to_vec()));
+            raw = &raw[body_start + length..];
+        }
+
+        responses
+    }
+
+    /// Count the headers with a name in a raw response, ignoring case.
+    fn count_headers(raw: &[u8], name: &str) -> usize {
+        String::from_utf8_lossy(raw)
+            .split("\r\n\r\n")
+            .next()
+            .unwrap()
+            .lines()
+            .filter(|line| {
+                line.split(':')
+                    .next()
+                    .is_some_and(|n| n.eq_ignore_ascii_case(name))
+            })
+            .count()
+    }
+
+    fn get_request(route: &str) -> HttpRequest {
+        HttpRequest::create(
+            route.to_string(),
+            HttpVerb::GET,
+            "text/plain".to_string(),
+            IndexMap::new(),
+            None,
+        )
+    }
+
+    fn html_response(body: &str) -> HttpResponse {
+        HttpResponse::create(
+            HttpStatus::Ok,
+            "text/html; charset=utf-8".to_string(),
+            IndexMap::new(),
+            Some(body.as_bytes().to_vec()),
+        )
+    }
+
+    #[test]
+    fn small_html_gets_the_reload_script() {
+        let config = Config {
+            max_inject_size: 100,
+            ..Default::default()
+        };
+        let response = html_response("<html><body></body></html>");
+
+        assert!(should_inject_script(
+            &get_request("/index.html"),
+            &config,
+            &response,
+            &Logger::for_tests()
+        ));
+    }
+
+    #[test]
+    fn oversized_or_non_html_documents_are_not_injected() {
+        let config = Config {
+            max_inject_size: 100,
+            ..Default::default()
+        };
+        let request = get_request("/index.html");
+        let oversized = html_response(&format!("<html><body>{}</body></html>", "a".repeat(100)));
+        let css = HttpResponse::create(
+            HttpStatus::Ok,
+            "text/css".to_string(),
+            IndexMap::new(),
+            Some(b"a{}".to_vec()),
+        );
+
+        assert!(!should_inject_script(
+            &request,
+            &config,
+            &oversized,
+            &Logger::for_tests()
+        ));
+        assert!(!should_inject_script(
+            &request,
+            &config,
+            &css,
+            &Logger::for_tests()
+        ));
+        assert!(!should_inject_script(
+            &get_request("/index.html?noreload=1"),
+            &config,
+            &html_response("<body></body>"),
+            &Logger::for_tests()
+        ));
+    }
+
+    #[test]
+    fn content_length_matches_the_body_after_injection_and_compression() {
+        let index = format!("<html><body>{}</body></html>", "<p>Hello</p>".repeat(50));
+        let server = TestServer::start(&[("/index.html", &index)], |_| {});
+
+        for accept_encoding in ["identity", "gzip"] {
+            let raw = server.send(&format!(
+                "GET / HTTP/1.1\r\nAccept-Encoding: {}\r\nConnection: close\r\n\r\n",
+                accept_encoding
+            ));
+            let (header, body) = split_response(&raw);
+
+            assert_eq!(header.status.get_code(), 200);
+            assert_eq!(count_headers(&raw, "Content-Length"), 1);
+            assert_eq!(
+                header.headers.get("Content-Length"),
+                Some(&body.len().to_string())
+            );
+
+            let document = if accept_encoding == "gzip" {
+                assert_eq!(
+                    header.headers.get("Content-Encoding"),
+                    Some(&"gzip".to_string())
+                );
+
+                let mut document = String::new();
+                GzDecoder::new(&body[..])
+                    .read_to_string(&mut document)
+                    .unwrap();
+                document
+            } else {
+                String::from_utf8(body).unwrap()
+            };
+
+            assert!(document.contains("<script>"));
+            assert!(document.len() > index.len());
+        }
+    }
+
+    #[test]
+    fn invalid_response_headers_are_not_sent() {
+        let server = TestServer::start(&[], |config| {
+            config
+                .router
+                .add(
+                    HttpVerb::GET,
+                    "/bad",
+                    Box::new(|_, _| {
+                        let mut response = HttpResponse::create(
+                            HttpStatus::Ok,
+                            "text/plain".to_string(),
+                            IndexMap::new(),
+                            Some(b"ok".to_vec()),
+                        );
+                        response
+                            .header
+                            .headers
+                            .insert("X-Split".to_string(), "a\r\nInjected: yes".to_string());
+                        Ok(response)
+                    }),
+                )
+                .unwrap();
+        });
+
+        let raw = server.send("GET /bad HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let (header, body) = split_response(&raw);
+
+        assert_eq!(header.status.get_code(), 200);
+        assert_eq!(body, b"ok");
+        assert!(!header.headers.contains_key("X-Split"));
+        assert!(!header.headers.contains_key("Injected"));
+    }
+
+    /// Send the same request as GET and HEAD, returning both raw responses.
+    fn get_and_head(server: &TestServer, route: &str) -> (Vec<u8>, Vec<u8>) {
+        let request =
+            |verb: &str| format!("{} {} HTTP/1.1\r\nConnection: close\r\n\r\n", verb, route);
+
+        (server.send(&request("GET")), server.send(&request("HEAD")))
+    }
+
+    /// Check a HEAD response has the same status and headers as the GET response (other than
+    /// those that change per request) and no body.
+    fn assert_head_matches_get(get: &[u8], head: &[u8]) {
+        let (get_header, get_body) = split_response(get);
+        let (head_header, head_body) = split_response(head);
+
+        let comparable = |header: &HttpResponseHeader| -> Vec<(String, String)> {
+            header
+                .headers
+                .iter()
+                .filter(|(name, _)| {
+                    !name.eq_ignore_ascii_case("Date") && !name.eq_ignore_ascii_case("X-Request-Id")
+                })
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect()
+        };
+
+        assert_eq!(head_header.status.get_code(), get_header.status.get_code());
+        assert_eq!(comparable(&head_header), comparable(&get_header));
+        assert!(!get_body.is_empty());
+        assert!(head_body.is_empty());
+    }
+
+    #[test]
+    fn head_requests_to_healthz_get_the_get_headers() {
+        let server = TestServer::start(&[], |_| {});
+
+        let (get, head) = get_and_head(&server, "/healthz");
+
+        assert_eq!(split_response(&get).0.status.get_code(), 200);
+        assert_head_matches_get(&get, &head);
+    }
+
+    #[test]
+    fn plain_requests_to_the_ws_route_get_a_426() {
+        let server = TestServer::start(&[], |_| {});
+
+        let raw = server.send("GET /ws/notify HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let (header, body) = split_response(&raw);
+
+        assert_eq!(header.status.get_code(), 426);
+        assert_eq!(
+            header.headers.get("Upgrade"),
+            Some(&"websocket".to_string())
+        );
+        assert_eq!(
+            header.headers.get("Content-Type"),
+            Some(&"text/html".to_string())
+        );
+        assert!(String::from_utf8_lossy(&body).contains("WebSocket"));
+    }
+
+    #[test]
+    fn only_get_requests_to_the_ws_route_are_upgraded() {
+        let server = TestServer::start(&[], |_| {});
+
+        for verb in ["POST", "PUT", "DELETE", "OPTIONS"] {
+            let raw = server.send(&format!(
+                "{} /ws/notify HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+                 Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                 Content-Length: 0\r\nConnection: close\r\n\r\n",
+                verb
+            ));
+            let (header, _) = split_response(&raw);
+
+            assert_eq!(header.status.get_code(), 405, "{}", verb);
+            assert_eq!(header.headers.get("Allow"), Some(&"GET, HEAD".to_string()));
+        }
+    }
+
+    #[test]
+    fn event_streams_send_notifications_until_closed() {
+        let server = TestServer::start(&[], |config| config.writable = true);
+        let subscribers = server.hub.as_ref().unwrap().get_subscriber_counter();
+        let mut stream = server.connect();
+
+        stream
+            .write_all(b"GET /sse/notify HTTP/1.1\r\nAccept: text/event-stream\r\n\r\n")
+            .unwrap();
+
+        let raw = read_until(&mut stream, b"\r\n\r\n");
+        let (header, _) = split_response(&raw);
+
+        assert_eq!(header.status.get_code(), 200);
+        assert_eq!(
+            header.headers.get("Content-Type"),
+            Some(&"text/event-stream".to_string())
+        );
+        assert_eq!(
+            header.headers.get("Cache-Control"),
+            Some(&"no-cache".to_string())
+        );
+        assert!(!header.headers.contains_key("Content-Length"));
+
+        wait_for(|| subscribers.load(Ordering::SeqCst) == 1);
+
+        let raw = server
+            .send("PUT /style.css HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        assert_eq!(split_response(&raw).0.status.get_code(), 201);
+
+        let event = String::from_utf8(read_until(&mut stream, b"\n\n")).unwrap();
+
+        assert!(event.starts_with("event: reload\ndata: {"), "{}", event);
+        assert!(event.contains("\"kind\":\"created\""), "{}", event);
+        assert!(event.contains("style.css"), "{}", event);
+
+        // Closing the connection ends the subscription.
+        stream.shutdown(Shutdown::Both).unwrap();
+        wait_for(|| subscribers.load(Ordering::SeqCst) == 0);
+    }
+
+    #[test]
+    fn event_streams_need_an_accept_header() {
+        let server = TestServer::start(&[], |_| {});
+
+        for accept in ["", "Accept: text/html\r\n"] {
+            let raw = server.send(&format!(
+                "GET /sse/notify HTTP/1.1\r\n{}Connection: close\r\n\r\n",
+                accept
+            ));
+
+            assert_eq!(split_response(&raw).0.status.get_code(), 406, "{}", accept);
+        }
+    }
+
+    #[test]
+    fn head_requests_to_the_ws_route_are_not_upgraded() {
+        let server = TestServer::start(&[], |_| {});
+
+        let (get, head) = get_and_head(&server, "/ws/notify");
+        let (header, _) = split_response(&head);
+
+        assert_eq!(header.status.get_code(), 426);
+        assert_eq!(
+            header.headers.get("Upgrade"),
+            Some(&"websocket".to_string())
+        );
+        assert_head_matches_get(&get, &head);
+
+        // Even with the upgrade headers a HEAD request gets the 426 rather than a handshake.
+        let raw = server.send(
+            "HEAD /ws/notify HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        );
+        let (header, body) = split_response(&raw);
+
+        assert_eq!(header.status.get_code(), 426);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn dev_status_reports_the_version() {
+        let server = TestServer::start(&[], |config| {
+            add_dev_routes(
+                &mut config.router,
+                RecentNotifications::new(10),
+                Arc::new(AtomicUsize::new(3)),
+                Arc::new(AtomicUsize::new(0)),
+            )
+            .unwrap();
+        });
+
+        let (get, head) = get_and_head(&server, "/__dev/status");
+        let (header, body) = split_response(&get);
+        let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(header.status.get_code(), 200);
+        assert_eq!(status["status"], "ok");
+        assert_eq!(status["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(status["subscribers"], 3);
+        assert!(status["uptime_seconds"].is_u64());
+        assert_head_matches_get(&get, &head);
+    }
+
+    #[test]
+    fn dev_changes_lists_recent_file_changes() {
+        let recent = RecentNotifications::new(10);
+
+        recent.push(&Notification::FileUpdated("/a.html".to_string()));
+        recent.push(&Notification::FilesSettled);
+        recent.push(&Notification::FileRemoved("/b.css".to_string()));
+
+        let notifications = recent.clone();
+        let server = TestServer::start(&[], |config| {
+            add_dev_routes(
+                &mut config.router,
+                notifications,
+                Arc::new(AtomicUsize::new(0)),
+                Arc::new(AtomicUsize::new(0)),
+            )
+            .unwrap();
+        });
+
+        let raw = server.send("GET /__dev/changes HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let (header, body) = split_response(&raw);
+        let changes: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let list = recent.list();
+
+        assert_eq!(header.status.get_code(), 200);
+        assert_eq!(
+            header.headers.get("Content-Type"),
+            Some(&"application/json; charset=utf-8".to_string())
+        );
+
+        // The most recent first, without the settled notification.
+        assert_eq!(
+            changes,
+            serde_json::json!([
+                { "kind": "removed", "path": "/b.css", "time": list[0].time },
+                { "kind": "updated", "path": "/a.html", "time": list[2].time },
+            ])
+        );
+    }
+
+    #[test]
+    fn only_routes_with_a_cors_policy_send_cors_headers() {
+        let server = TestServer::start(&[("/app.js", "// app")], |config| {
+            config
+                .router
+                .add_with_cors(
+                    HttpVerb::GET,
+                    "/api/items",
+                    Box::new(|_, _| {
+                        HttpResponse::json(HttpStatus::Ok, &serde_json::json!([]))
+                            .map_err(|e| HandlerError::new(HttpStatus::InternalError, e))
+                    }),
+                    CorsPolicy::permissive(),
+                )
+                .unwrap();
+        });
+
+        let raw = server.send(
+            "GET /api/items HTTP/1.1\r\nOrigin: http://example.com\r\nConnection: close\r\n\r\n",
+        );
+        let (header, _) = split_response(&raw);
+
+        assert_eq!(header.status.get_code(), 200);
+        assert_eq!(
+            header.headers.get("Access-Control-Allow-Origin"),
+            Some(&"*".to_string())
+        );
+
+        let raw = server.send(
+            "OPTIONS /api/items HTTP/1.1\r\nOrigin: http://example.com\r\n\
+             Access-Control-Request-Method: GET\r\nConnection: close\r\n\r\n",
+        );
+        let (header, _) = split_response(&raw);
+
+        assert!(header.headers.contains_key("Access-Control-Allow-Origin"));
+        assert!(header.headers.contains_key("Access-Control-Allow-Methods"));
+
+        let raw = server.send(
+            "GET /app.js HTTP/1.1\r\nOrigin: http://example.com\r\nConnection: close\r\n\r\n",
+        );
+        let (header, _) = split_response(&raw);
+
+        assert_eq!(header.status.get_code(), 200);
+        assert!(!header
+            .headers
+            .iter()
+            .any(|(name, _)| name.starts_with("Access-Control-")));
+    }
+
+    #[test]
+    fn ranges_are_served_uncompressed_from_the_cached_file() {
+        let content: String = (0..100).map(|i| format!("line {:03}\n", i)).collect();
+        let server = TestServer::start(&[("/data.txt", &content)], |_| {});
+        let path = server.root.join("data.txt");
+
+        let raw = server
+            .send("GET /data.txt HTTP/1.1\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n");
+        let (header, _) = split_response(&raw);
+
+        assert_eq!(header.status.get_code(), 200);
+        assert_eq!(
+            header.headers.get("Content-Encoding"),
+            Some(&"gzip".to_string())
+        );
+
+        // Change the file on disk without changing its size or modified time, so the cached copy
+        // is still used and a read from disk would show up in the response.
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, content.to_uppercase()).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let raw = server.send(
+            "GET /data.txt HTTP/1.1\r\nAccept-Encoding: gzip\r\nRange: bytes=90-107\r\n\
+             Connection: close\r\n\r\n",
+        );
+        let (header, body) = split_response(&raw);
+
+        assert_eq!(header.status.get_code(), 206);
+        assert!(!header.headers.contains_key("Content-Encoding"));
+        assert_eq!(
+            header.headers.get("Content-Range"),
+            Some(&format!("bytes 90-107/{}", content.len()))
+        );
+        assert_eq!(body, b"line 010\nline 011\n");
+    }
+
+    const PIPELINE_FILES: [(&str, &str); 3] = [("/a.txt", "A"), ("/b.txt", "B"), ("/c.txt", "C")];
+
+    #[test]
+    fn compressed_responses_vary_on_accept_encoding() {
+        let content: String = (0..100).map(|i| format!("line {:03}\n", i)).collect();
+        let server = TestServer::start(&[("/data.txt", &content)], |_| {});
+
+        let raw = server
+            .send("GET /data.txt HTTP/1.1\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n");
+        let (header, _) = split_response(&raw);
+
+        assert_eq!(
+            header.headers.get("Content-Encoding"),
+            Some(&"gzip".to_string())
+        );
+        assert_eq!(
+            header.headers.get("Vary"),
+            Some(&"Accept-Encoding".to_string())
+        );
+        assert_eq!(count_headers(&raw, "Vary"), 1);
+
+        // The uncompressed response varies too, so caches do not serve it to gzip clients.
+        let raw = server.send("GET /data.txt HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let (header, _) = split_response(&raw);
+
+        assert!(!header.headers.contains_key("Content-Encoding"));
+        assert_eq!(
+            header.headers.get("Vary"),
+            Some(&"Accept-Encoding".to_string())
+        );
+    }
+
+    #[test]
+    fn index_routes_answer_conditional_requests() {
+        let server = TestServer::start(&[("/index.html", "<p>Home</p>")], |_| {});
+
+        let raw = server.send("GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let (header, _) = split_response(&raw);
+        let etag = header.headers.get("ETag").unwrap().clone();
+
+        assert_eq!(header.status.get_code(), 200);
+
+        for route in ["/", "/index", "/index.html"] {
+            let raw = server.send(&format!(
+                "GET {} HTTP/1.1\r\nIf-None-Match: {}\r\nConnection: close\r\n\r\n",
+                route, etag
+            ));
+            let (header, body) = split_response(&raw);
+
+            assert_eq!(header.status.get_code(), 304, "{}", route);
+            assert_eq!(header.headers.get("ETag"), Some(&etag));
+            assert!(body.is_empty());
+        }
+    }
+
+    #[test]
+    fn trace_requests_are_echoed_only_when_enabled() {
+        let request = "TRACE /page.html HTTP/1.1\r\nX-Test: value\r\nCookie: secret=1\r\n\
+                       Connection: close\r\n\r\n";
+
+        let server = TestServer::start(&[("/page.html", "<p>Page</p>")], |_| {});
+        let (header, _) = split_response(&server.send(request));
+
+        assert_eq!(header.status.get_code(), 405);
+        assert_eq!(header.headers.get("Allow"), Some(&"GET, HEAD".to_string()));
+
+        let server = TestServer::start(&[("/page.html", "<p>Page</p>")], |config| {
+            config.allow_trace = true;
+        });
+        let (header, body) = split_response(&server.send(request));
+        let body = String::from_utf8(body).unwrap();
+
+        assert_eq!(header.status.get_code(), 200);
+        assert_eq!(
+            header.headers.get("Content-Type"),
+            Some(&"message/http".to_string())
+        );
+        assert!(
+            body.starts_with("TRACE /page.html HTTP/1.1\r\n"),
+            "{}",
+            body
+        );
+        assert!(body.contains("X-Test: value\r\n"));
+        assert!(!body.contains("secret"));
+    }
+
+    #[test]
+    fn unsupported_methods_on_static_files_get_a_405() {
+        let server = TestServer::start(&[("/index.html", "<p>Home</p>")], |_| {});
+
+        // PUT and DELETE are refused with a 403 outside authoring mode instead.
+        for verb in ["POST", "PATCH", "OPTIONS"] {
+            let raw = server.send(&format!(
+                "{} /index.html HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                verb
+            ));
+            let (header, _) = split_response(&raw);
+
+            assert_eq!(header.status.get_code(), 405, "{}", verb);
+            assert_eq!(header.headers.get("Allow"), Some(&"GET, HEAD".to_string()));
+        }
+
+        let server = TestServer::start(&[("/index.html", "<p>Home</p>")], |config| {
+            config.writable = true;
+        });
+        let raw = server
+            .send("POST /index.html HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        let (header, _) = split_response(&raw);
+
+        assert_eq!(header.status.get_code(), 405);
+        assert_eq!(
+            header.headers.get("Allow"),
+            Some(&"GET, HEAD, PUT, DELETE".to_string())
+        );
+        assert!(server.root.join("index.html").exists());
+    }
+
+    #[test]
+    fn bind_addresses_are_validated() {
+        assert_eq!(
+            parse_address("127.0.0.1:8080").unwrap(),
+            "127.0.0.1:8080".parse::<SocketAddr>().unwrap()
+        );
+        assert!(parse_address(" [::1]:8080 ").unwrap().is_ipv6());
+
+        for address in [
+            "::1:8080",
+            "localhost:8080",
+            "127.0.0.1",
+            "127.0.0.1:99999",
+            "",
+        ] {
+            assert!(parse_address(address).is_err(), "{}", address);
+        }
+    }
+
+    #[test]
+    fn servers_bind_to_ipv6_addresses() {
+        // Skipped where the loopback interface has no IPv6 address.
+        if std::net::TcpListener::bind("[::1]:0").is_err() {
+            return;
+        }
+
+        let server = TestServer::start(&[("/index.html", "<p>Home</p>")], |config| {
+            config.address = "[::1]:0".to_string();
+        });
+        let address = server.server.as_ref().unwrap().get_address();
+
+        assert!(address.is_ipv6());
+
+        let raw = server.send("GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+
+        assert_eq!(split_response(&raw).0.status.get_code(), 200);
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_get_a_416() {
+        let server = TestServer::start(&[("/data.txt", "0123456789")], |_| {});
+
+        for range in ["bytes=10-", "bytes=20-30", "bytes=-0", "bytes=5-2"] {
+            let raw = server.send(&format!(
+                "GET /data.txt HTTP/1.1\r\nRange: {}\r\nConnection: close\r\n\r\n",
+                range
+            ));
+            let (header, body) = split_response(&raw);
+
+            assert_eq!(header.status.get_code(), 416, "{}", range);
+            assert_eq!(
+                header.headers.get("Content-Range"),
+                Some(&"bytes */10".to_string())
+            );
+            assert_eq!(body, b"Range not satisfiable");
+        }
+
+        // A range past the end is limited to the body.
+        let raw =
+            server.send("GET /data.txt HTTP/1.1\r\nRange: bytes=8-20\r\nConnection: close\r\n\r\n");
+        let (header, body) = split_response(&raw);
+
+        assert_eq!(header.status.get_code(), 206);
+        assert_eq!(body, b"89");
+    }
+
+    #[test]
+    fn mounted_directories_serve_their_prefix() {
+        let vendor = std::env::temp_dir().join(format!("dev_server_vendor_{}", std::process::id()));
+
+        fs::create_dir_all(&vendor).unwrap();
+        fs::write(vendor.join("lib.js"), "// vendor").unwrap();
+
+        let server = TestServer::start(
+            &[("/app.js", "// app"), ("/secret.txt", "secret")],
+            |config| {
+                config
+                    .mounts
+                    .push(("/vendor".to_string(), vendor.to_string_lossy().into_owned()));
+            },
+        );
+
+        let get = |route: &str| {
+            split_response(&server.send(&format!(
+                "GET {} HTTP/1.1\r\nConnection: close\r\n\r\n",
+                route
+            )))
+        };
+
+        let (header, body) = get("/vendor/lib.js");
+        assert_eq!(header.status.get_code(), 200);
+        assert_eq!(body, b"// vendor");
+
+        let (header, body) = get("/app.js");
+        assert_eq!(header.status.get_code(), 200);
+        assert_eq!(body, b"// app");
+
+        // Routes are resolved within the mount, they can not reach the base path.
+        assert_eq!(get("/vendor/app.js").0.status.get_code(), 404);
+        assert_eq!(get("/vendor/../secret.txt").0.status.get_code(), 404);
+        assert_eq!(get("/vendorlib.js").0.status.get_code(), 404);
+
+        let _ = fs::remove_dir_all(vendor);
+    }
+
+    #[test]
+    fn no_store_routes_are_sent_without_an_etag() {
+        let server =
+            TestServer::start(&[("/config.json", "{}"), ("/app.js", "// app")], |config| {
+                config.no_store = vec!["config.json".to_string()]
+            });
+
+        let raw = server.send("GET /config.json HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let (header, _) = split_response(&raw);
+
+        assert_eq!(header.status.get_code(), 200);
+        assert_eq!(
+            header.headers.get("Cache-Control"),
+            Some(&"no-store".to_string())
+        );
+        assert!(!header.headers.contains_key("ETag"));
+
+        let raw = server.send("GET /app.js HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let (header, _) = split_response(&raw);
+
+        assert_eq!(
+            header.headers.get("Cache-Control"),
+            Some(&"max-age=10".to_string())
+        );
+        assert!(header.headers.contains_key("ETag"));
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let server = TestServer::start(&PIPELINE_FILES, |_| {});
+
+        let raw = server.send(
+            "GET /a.txt HTTP/1.1\r\n\r\n\
+             GET /b.txt HTTP/1.1\r\n\r\n\
+             GET /c.txt HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        let responses = split_responses(&raw);
+
+        let bodies: Vec<&[u8]> = responses.iter().map(|(_, body)| &body[..]).collect();
+        assert_eq!(bodies, [b"A", b"B", b"C"]);
+
+        let connection: Vec<&String> = responses
+            .iter()
+            .map(|(header, _)| header.headers.get("Connection").unwrap())
+            .collect();
+        assert_eq!(connection, ["keep-alive", "keep-alive", "close"]);
+
+        // Each request on the connection has its own id.
+        let ids: Vec<&String> = responses
+            .iter()
+            .map(|(header, _)| header.headers.get("X-Request-Id").unwrap())
+            .collect();
+        assert!(ids[1].ends_with(".1") && ids[2].ends_with(".2"));
+        assert!(ids[1].starts_with(ids[0].as_str()));
+    }
+
+    #[test]
+    fn connections_close_after_the_maximum_requests() {
+        let server = TestServer::start(&PIPELINE_FILES, |config| {
+            config.max_requests_per_connection = 2;
+        });
+
+        let raw = server.send(
+            "GET /a.txt HTTP/1.1\r\n\r\n\
+             GET /b.txt HTTP/1.1\r\n\r\n\
+             GET /c.txt HTTP/1.1\r\n\r\n",
+        );
+        let responses = split_responses(&raw);
+
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[1].1, b"B");
+        assert_eq!(
+            responses[1].0.headers.get("Connection"),
+            Some(&"close".to_string())
+        );
+    }
+
+    #[test]
+    fn idle_connections_close_after_the_keep_alive_timeout() {
+        let timeout = Duration::from_millis(300);
+        let server = TestServer::start(&PIPELINE_FILES, |config| {
+            config.keep_alive_timeout = timeout;
+        });
+
+        let start = Instant::now();
+        let raw = server.send("GET /a.txt HTTP/1.1\r\n\r\n");
+        let elapsed = start.elapsed();
+
+        // The server closed the connection after answering, well before the client gave up.
+        let responses = split_responses(&raw);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(
+            responses[0].0.headers.get("Connection"),
+            Some(&"keep-alive".to_string())
+        );
+        assert!(elapsed >= timeout);
+        assert!(elapsed < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn connections_over_the_limit_get_a_503() {
+        let server = TestServer::start(&[("/index.html", "<p>Home</p>")], |config| {
+            config.max_connections = 1;
+        });
+
+        // Holds the only connection allowed while its request is awaited.
+        let _held = server.connect();
+
+        let raw = server.send("GET /index.html HTTP/1.1\r\n\r\n");
+        let (header, _) = split_response(&raw);
+
+        assert_eq!(header.status.get_code(), 503);
+        assert_eq!(header.headers.get("Connection"), Some(&"close".to_string()));
+    }
+
+    #[test]
+    fn accept_errors_only_back_off_when_out_of_file_descriptors() {
+        assert_eq!(
+            get_accept_backoff(&io::Error::from_raw_os_error(EMFILE)),
+            Some(ACCEPT_BACKOFF)
+        );
+        assert_eq!(
+            get_accept_backoff(&io::Error::from_raw_os_error(ENFILE)),
+            Some(ACCEPT_BACKOFF)
+        );
+        assert_eq!(
+            get_accept_backoff(&io::Error::from(ErrorKind::ConnectionAborted)),
+            None
+        );
+    }
+
+    #[test]
+    fn panicking_handlers_get_a_500_and_the_worker_survives() {
+        let server = TestServer::start(&[("/index.html", "<p>Home</p>")], |config| {
+            config
+                .router
+                .add(
+                    HttpVerb::GET,
+                    "/panic",
+                    Box::new(|_, _| panic!("handler failed")),
+                )
+                .unwrap();
+        });
+
+        let raw = server.send("GET /panic HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let (header, _) = split_response(&raw);
+
+        assert_eq!(header.status.get_code(), 500);
+
+        // Both workers are still there to answer.
+        for _ in 0..2 {
+            let raw = server.send("GET /index.html HTTP/1.1\r\nConnection: close\r\n\r\n");
+            let (header, _) = split_response(&raw);
+
+            assert_eq!(header.status.get_code(), 200);
+        }
+    }
+
+    #[test]
+    fn panics_record_a_backtrace() {
+        install_panic_hook();
+
+        let result = panic::catch_unwind(|| panic!("recorded"));
+
+        assert!(result.is_err());
+        assert!(take_panic_backtrace().is_some());
+        assert!(take_panic_backtrace().is_none());
+    }
+
+    #[test]
+    fn headers_files_apply_to_their_directory() {
+        let server = TestServer::start(
+            &[
+                (
+                    "/secure/_headers",
+                    "X-Frame-Options: DENY\nBad Name: value\n",
+                ),
+                ("/secure/page.html", "<p>Secure</p>"),
+                ("/open.html", "<p>Open</p>"),
+            ],
+            |_| {},
+        );
+
+        let raw = server.send("GET /secure/page.html HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let (header, _) = split_response(&raw);
+
+        assert_eq!(header.status.get_code(), 200);
+        assert_eq!(
+            header.headers.get("X-Frame-Options"),
+            Some(&"DENY".to_string())
+        );
+        assert!(!header.headers.contains_key("Bad Name"));
+
+        let raw = server.send("GET /open.html HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let (header, _) = split_response(&raw);
+
+        assert_eq!(header.status.get_code(), 200);
+        assert!(!header.headers.contains_key("X-Frame-Options"));
+    }
+
+    #[test]
+    fn writes_create_replace_and_remove_files() {
+        let server = TestServer::start(&[("/page.html", "<p>Old</p>")], |config| {
+            config.writable = true;
+        });
+
+        let raw = server.send(
+            "PUT /new/file.txt HTTP/1.1\r\nContent-Length: 3\r\nConnection: close\r\n\r\nnew",
+        );
+        let (header, _) = split_response(&raw);
+
+        assert_eq!(header.status.get_code(), 201);
+        assert_eq!(
+            header.headers.get("Location"),
+            Some(&"/new/file.txt".to_string())
+        );
+        assert_eq!(
+            fs::read_to_string(server.root.join("new/file.txt")).unwrap(),
+            "new"
+        );
+
+        let raw = server.send(
+            "PUT /page.html HTTP/1.1\r\nContent-Length: 10\r\nConnection: close\r\n\r\n<p>New</p>",
+        );
+
+        assert_eq!(split_response(&raw).0.status.get_code(), 204);
+        assert_eq!(
+            fs::read_to_string(server.root.join("page.html")).unwrap(),
+            "<p>New</p>"
+        );
+
+        let raw = server.send("DELETE /page.html HTTP/1.1\r\nConnection: close\r\n\r\n");
+
+        assert_eq!(split_response(&raw).0.status.get_code(), 204);
+        assert!(!server.root.join("page.html").exists());
+    }
+
+    #[test]
+    fn writes_are_forbidden_unless_writable_or_for_headers_files() {
+        let server = TestServer::start(&[("/page.html", "<p>Page</p>")], |_| {});
+
+        for request in [
+            "PUT /page.html HTTP/1.1\r\nContent-Length: 1\r\nConnection: close\r\n\r\nx",
+            "DELETE /page.html HTTP/1.1\r\nConnection: close\r\n\r\n",
+        ] {
+            assert_eq!(
+                split_response(&server.send(request)).0.status.get_code(),
+                403
+            );
+        }
+
+        assert!(server.root.join("page.html").exists());
+
+        let server = TestServer::start(&[("/sub/_headers", "X-Test: 1\n")], |config| {
+            config.writable = true;
+        });
+
+        for request in [
+            "PUT /_headers HTTP/1.1\r\nContent-Length: 1\r\nConnection: close\r\n\r\nx",
+            "PUT /sub/_headers HTTP/1.1\r\nContent-Length: 1\r\nConnection: close\r\n\r\nx",
+            "DELETE /sub/_headers HTTP/1.1\r\nConnection: close\r\n\r\n",
+        ] {
+            assert_eq!(
+                split_response(&server.send(request)).0.status.get_code(),
+                403
+            );
+        }
+
+        assert!(!server.root.join("_headers").exists());
+        assert_eq!(
+            fs::read_to_string(server.root.join("sub/_headers")).unwrap(),
+            "X-Test: 1\n"
+        );
+    }
+
+    #[test]
+    fn unknown_files_use_the_configured_content_type() {
+        let files = [("/data.xyz", "\u{1}\u{2}"), ("/module.wasm", "\0asm")];
+        let server = TestServer::start(&files, |config| {
+            config.default_content_type = "application/x-custom".to_string();
+            config.download_octet_stream = true;
+        });
+
+        let raw = server.send("GET /data.xyz HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let (header, _) = split_response(&raw);
+
+        assert_eq!(
+            header.headers.get("Content-Type"),
+            Some(&"application/x-custom".to_string())
+        );
+        assert!(!header.headers.contains_key("Content-Disposition"));
+
+        let raw = server.send("GET /module.wasm HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let (header, _) = split_response(&raw);
+
+        assert_eq!(
+            header.headers.get("Content-Type"),
+            Some(&"application/wasm".to_string())
+        );
+        assert!(!header.headers.contains_key("Content-Disposition"));
+
+        let server = TestServer::start(&files, |config| config.download_octet_stream = true);
+
+        let raw = server.send("GET /data.xyz HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let (header, _) = split_response(&raw);
+
+        assert_eq!(
+            header.headers.get("Content-Type"),
+            Some(&DEFAULT_CONTENT_TYPE.to_string())
+        );
+        assert_eq!(
+            header.headers.get("Content-Disposition"),
+            Some(&"attachment".to_string())
+        );
+    }
+
+    #[test]
+    fn setting_a_body_replaces_a_parsed_content_length() {
+        let raw = b"HTTP/1.1 200 OK\r\nCONTENT-LENGTH: 5\r\n\r\nhello";
+        let mut response = HttpResponse::from_stream(&raw[..], DEFAULT_BUFFER_SIZE).unwrap();
+
+        response.set_body(b"hello, world".to_vec());
+        let bytes = response.to_bytes();
+
+        assert_eq!(count_headers(&bytes, "Content-Length"), 1);
+        assert!(String::from_utf8_lossy(&bytes).contains("Content-Length: 12\r\n"));
+    }
+
+    #[test]
+    fn injected_script_reconnects_with_backoff() {
+        let config = Config {
+            reconnect_max_backoff: Duration::from_millis(7000),
+            ..Default::default()
+        };
+
+        let document = inject_script("<html><body><p>Hi</p></body></html>", &config);
+
+        assert!(document.starts_with("<html><body><p>Hi</p><script>"));
+        assert!(document.ends_with("</script>\n</body></html>"));
+        assert!(document.contains("ws.onclose = function ()"));
+        assert!(document.contains("setTimeout(connect, backoff);"));
+        assert!(document.contains("backoff = Math.min(backoff * 2, 7000);"));
+        assert!(document.contains("location.host + '/ws/notify'"));
+        assert!(!document.contains("127.0.0.1"));
+        assert!(!document.contains("{{"));
+    }
+
+    #[test]
+    fn template_replaces_known_placeholders() {
+        let vars = HashMap::from([("TITLE".to_string(), "Home".to_string())]);
+
+        assert_eq!(
+            apply_template("<h1>{{TITLE}}</h1>{{OTHER}}", &vars),
+            "<h1>Home</h1>{{OTHER}}"
+        );
+    }
 }
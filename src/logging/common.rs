@@ -1,4 +1,6 @@
-﻿pub struct LogItem {
+﻿use std::{fmt, str::FromStr};
+
+pub struct LogItem {
     pub(crate) from: String,
     pub(crate) message: String,
     pub(crate) item_type: LogItemType,
@@ -13,6 +15,16 @@ pub enum LogItemType {
     Debug,
 }
 
+/// The severity of a log item, items below a log's level are not logged.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warning,
+    Error,
+}
+
 pub enum ConsoleColor {
     Black,
     BlackBright,
@@ -75,6 +87,59 @@ impl LogItem {
     }
 }
 
+impl LogItemType {
+    /// Returns the level of this [`LogItemType`], success items are informational.
+    pub fn get_level(&self) -> LogLevel {
+        match self {
+            LogItemType::Trace => LogLevel::Trace,
+            LogItemType::Debug => LogLevel::Debug,
+            LogItemType::Information | LogItemType::Success => LogLevel::Info,
+            LogItemType::Warning => LogLevel::Warning,
+            LogItemType::Error => LogLevel::Error,
+        }
+    }
+}
+
+impl LogLevel {
+    /// Returns the name of this [`LogLevel`].
+    pub fn get_str(&self) -> &'static str {
+        match self {
+            LogLevel::Trace => "trace",
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warning => "warning",
+            LogLevel::Error => "error",
+        }
+    }
+
+    /// Returns the position of this [`LogLevel`] in the ordering, `Trace` is 0.
+    pub fn get_code(&self) -> u8 {
+        *self as u8
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = &'static str;
+
+    /// Parse a level name, ignoring case. `warn` is accepted for warning.
+    fn from_str(data: &str) -> Result<LogLevel, &'static str> {
+        match data.trim().to_lowercase().as_str() {
+            "trace" => Ok(LogLevel::Trace),
+            "debug" => Ok(LogLevel::Debug),
+            "info" => Ok(LogLevel::Info),
+            "warning" | "warn" => Ok(LogLevel::Warning),
+            "error" => Ok(LogLevel::Error),
+            _ => Err("Unknown log level"),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.get_str())
+    }
+}
+
 impl ConsoleColor {
     pub fn set_foreground(&self) {
         print!("{}", self.get_foreground_color())
@@ -85,7 +150,11 @@ impl ConsoleColor {
     }
 
     pub fn reset() {
-        print!("\x1B[0m")
+        print!("{}", ConsoleColor::get_reset())
+    }
+
+    pub fn get_reset() -> &'static str {
+        "\x1B[0m"
     }
 
     pub fn get_foreground_color(&self) -> &'static str {
@@ -1,38 +1,110 @@
-﻿use crate::logging::common::{ConsoleColor, LogItem, LogItemType};
+﻿use crate::logging::common::{ConsoleColor, LogItem, LogItemType, LogLevel};
 
+use chrono::format::{Item, StrftimeItems};
 use chrono::UTC;
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
 use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
 
+/// The default format of the timestamp at the start of each line (RFC 3339, with milliseconds).
+pub const DEFAULT_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3f%:z";
+
+/// The minimum level logged by default, everything but trace items.
+pub const DEFAULT_LEVEL: LogLevel = LogLevel::Debug;
+
+/// The number of messages waiting to be printed the log holds by default.
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+#[derive(Clone)]
 pub struct Logger {
     name: String,
-    sender: Sender<LogItem>,
+    sender: Arc<LogQueue>,
+    level: Arc<AtomicU8>,
 }
 
 pub struct Log {
     handler: JoinHandle<()>,
-    sender: Sender<LogItem>,
+    sender: Arc<LogQueue>,
+    /// The code of the minimum level logged, shared with every logger.
+    level: Arc<AtomicU8>,
+    /// Set if lines are colored by their type.
+    color: Arc<AtomicBool>,
+}
+
+/// A message sent to the log thread.
+pub enum LogMessage {
+    Item(LogItem),
+    /// Request a reply once every item sent before it has been printed.
+    Flush(Sender<()>),
+    /// Change the timestamp format for items sent after it.
+    SetTimeFormat(String),
+    /// Stop the log thread once the messages sent before it have been handled.
+    Stop,
+}
+
+/// What to do with an item logged when the log's queue is full.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OverflowPolicy {
+    /// Drop the oldest item waiting to be printed, so logging never slows the caller.
+    DropOldest,
+    /// Wait until there is space in the queue.
+    Block,
+}
+
+/// The bounded queue of messages waiting for the log thread.
+///
+/// Only items count towards the capacity and can be dropped, control messages (such as a flush)
+/// are always queued.
+pub struct LogQueue {
+    state: Mutex<QueueState>,
+    /// Signalled when a message is pushed.
+    pushed: Condvar,
+    /// Signalled when a message is popped.
+    popped: Condvar,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: AtomicUsize,
+}
+
+struct QueueState {
+    messages: VecDeque<LogMessage>,
+    /// The number of items in the queue, not counting control messages.
+    items: usize,
 }
 
 impl Logger {
-    pub fn create(name: String, sender: Sender<LogItem>) -> Logger {
-        Logger { name, sender }
+    pub fn create(name: String, sender: Arc<LogQueue>) -> Logger {
+        Logger {
+            name,
+            sender,
+            level: Arc::new(AtomicU8::new(DEFAULT_LEVEL.get_code())),
+        }
     }
 
+    /// Create a child logger, its name is appended to this logger's name to give a dotted path
+    /// (for example `server.connection`) so the source of each line is clear in the output.
     pub fn create_from(&self, name: String) -> Logger {
         Logger {
-            name,
+            name: format!("{}.{}", self.name, name),
             sender: self.sender.clone(),
+            level: self.level.clone(),
         }
     }
 
+    /// Log an item, items below the log's level are ignored.
     pub fn log(&self, item: LogItem) -> Result<(), &'static str> {
-        match self.sender.send(item) {
-            Ok(_) => Ok(()),
-            Err(_) => Err("Could not write to log."),
+        if !self.is_enabled(item.item_type.get_level()) {
+            return Ok(());
         }
+
+        self.sender.push(LogMessage::Item(item));
+        Ok(())
     }
 
     pub fn log_info(&self, message: String) -> Result<(), &'static str> {
@@ -54,55 +126,391 @@ impl Logger {
     pub fn log_debug(&self, message: String) -> Result<(), &'static str> {
         self.log(LogItem::debug(self.name.clone(), message))
     }
+
+    /// Log a trace item, for output too verbose to log normally. Nothing is logged unless the
+    /// level has been set to [`LogLevel::Trace`] with [`Log::set_level`].
+    pub fn log_trace(&self, message: String) -> Result<(), &'static str> {
+        self.log(LogItem::trace(self.name.clone(), message))
+    }
+
+    /// Returns `true` if items of a level are logged, so expensive messages can be skipped.
+    pub fn is_enabled(&self, level: LogLevel) -> bool {
+        level.get_code() >= self.level.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+impl Logger {
+    /// Creates a [`Logger`] for tests, its items are queued (dropping the oldest) but never
+    /// printed.
+    pub fn for_tests() -> Logger {
+        Logger::create(
+            "test".to_string(),
+            Arc::new(LogQueue::new(1000, OverflowPolicy::DropOldest)),
+        )
+    }
 }
 
 impl Log {
+    /// Start the [`Log`] with the default capacity, dropping the oldest items when full. Lines
+    /// are colored if stdout is a terminal, see [`Log::set_color`].
     pub fn start() -> Result<Log, &'static str> {
-        let (sender, receiver) = mpsc::channel::<LogItem>();
+        Log::start_bounded(DEFAULT_CAPACITY, OverflowPolicy::DropOldest)
+    }
+
+    /// Start the [`Log`], holding at most `capacity` items waiting to be printed. When the
+    /// queue is full items are dropped or the caller blocks, depending on the policy.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the capacity is 0.
+    pub fn start_bounded(capacity: usize, policy: OverflowPolicy) -> Result<Log, &'static str> {
+        if capacity == 0 {
+            return Err("Log capacity must be at least 1");
+        }
 
-        let _ = sender.send(LogItem::info(
+        let sender = Arc::new(LogQueue::new(capacity, policy));
+        let receiver = sender.clone();
+
+        let color = Arc::new(AtomicBool::new(io::stdout().is_terminal()));
+        let print_color = color.clone();
+
+        sender.push(LogMessage::Item(LogItem::info(
             "Logger".to_string(),
             "Starting log".to_string(),
-        ));
+        )));
+
+        // Timestamps are created on this thread, when the item is printed.
+        let mut time_format = DEFAULT_TIME_FORMAT.to_string();
+
+        // The number of dropped items already reported.
+        let mut reported = 0;
 
         let handler = thread::spawn(move || loop {
-            let item = receiver.recv().unwrap();
-            Log::print(item);
+            let dropped = receiver.get_dropped();
+
+            if dropped > reported {
+                let item = LogItem::warning(
+                    "Log".to_string(),
+                    format!("Log full, {} items dropped", dropped - reported),
+                );
+
+                println!(
+                    "{}",
+                    Log::format_item(&item, &time_format, print_color.load(Ordering::Relaxed))
+                );
+                reported = dropped;
+            }
+
+            match receiver.pop() {
+                LogMessage::Item(item) => println!(
+                    "{}",
+                    Log::format_item(&item, &time_format, print_color.load(Ordering::Relaxed))
+                ),
+                LogMessage::SetTimeFormat(format) => time_format = format,
+                LogMessage::Flush(reply) => {
+                    let _ = reply.send(());
+                }
+                LogMessage::Stop => break,
+            }
         });
 
-        let _ = sender.send(LogItem::success(
+        sender.push(LogMessage::Item(LogItem::success(
             "Log".to_string(),
             "Log started".to_string(),
-        ));
+        )));
 
-        Ok(Log { handler, sender })
+        Ok(Log {
+            handler,
+            sender,
+            level: Arc::new(AtomicU8::new(DEFAULT_LEVEL.get_code())),
+            color,
+        })
     }
 
     pub fn get_logger(&self, name: String) -> Logger {
         Logger {
             name,
             sender: self.sender.clone(),
+            level: self.level.clone(),
         }
     }
 
-    fn print(item: LogItem) {
-        let (color, name) = match item.item_type {
-            LogItemType::Information => (ConsoleColor::WhiteBright, "info  "), //{}
-            LogItemType::Success => (ConsoleColor::Green, "ok    "),
-            LogItemType::Error => (ConsoleColor::Red, "error "),
-            LogItemType::Warning => (ConsoleColor::Yellow, "warn  "),
-            LogItemType::Trace => (ConsoleColor::BlackBright, "debug "),
-            LogItemType::Debug => (ConsoleColor::Magenta, "trace "),
+    /// Set the `strftime` style format of the timestamp at the start of each line.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the format is invalid.
+    pub fn set_time_format(&self, format: &str) -> Result<(), &'static str> {
+        if !is_valid_time_format(format) {
+            return Err("Invalid log time format");
+        }
+
+        self.sender
+            .push(LogMessage::SetTimeFormat(format.to_string()));
+        Ok(())
+    }
+
+    /// Set if lines are colored, overriding the check for a terminal.
+    pub fn set_color(&self, color: bool) {
+        self.color.store(color, Ordering::Relaxed);
+    }
+
+    /// Set the minimum level logged, for this log and every logger from it.
+    pub fn set_level(&self, level: LogLevel) {
+        self.level.store(level.get_code(), Ordering::Relaxed);
+    }
+
+    /// Block until every item logged so far has been printed.
+    pub fn flush(&self) {
+        let (reply_sender, reply_receiver) = mpsc::channel();
+
+        self.sender.push(LogMessage::Flush(reply_sender));
+        let _ = reply_receiver.recv();
+    }
+
+    /// Returns the number of items dropped because the queue was full.
+    pub fn get_dropped(&self) -> usize {
+        self.sender.get_dropped()
+    }
+
+    /// Print every item logged so far and stop the log thread. Items logged after this are
+    /// never printed.
+    pub fn stop(self) {
+        self.sender.push(LogMessage::Stop);
+        let _ = self.handler.join();
+    }
+
+    /// Format an item as a log line starting with the current time, colored by its type when
+    /// `color` is set. The time format must be valid (see [`is_valid_time_format`]).
+    pub fn format_item(item: &LogItem, time_format: &str, color: bool) -> String {
+        let (item_color, name) = match item.item_type {
+            LogItemType::Information => (None, "info  "),
+            LogItemType::Success => (Some(ConsoleColor::Green), "ok    "),
+            LogItemType::Error => (Some(ConsoleColor::Red), "error "),
+            LogItemType::Warning => (Some(ConsoleColor::Yellow), "warn  "),
+            LogItemType::Trace => (Some(ConsoleColor::BlackBright), "trace "),
+            LogItemType::Debug => (Some(ConsoleColor::BlackBright), "debug "),
         };
 
-        color.set_foreground();
-        println!(
+        let line = format!(
             "[{} {}] {} - {}",
-            UTC::now().format("%F %H:%M:%S%.3f"),
+            UTC::now().format(time_format),
             name,
             item.from,
             item.message
         );
-        ConsoleColor::reset();
+
+        match item_color {
+            Some(item_color) if color => format!(
+                "{}{}{}",
+                item_color.get_foreground_color(),
+                line,
+                ConsoleColor::get_reset()
+            ),
+            _ => line,
+        }
+    }
+}
+
+impl LogQueue {
+    /// Creates a new [`LogQueue`].
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> LogQueue {
+        LogQueue {
+            state: Mutex::new(QueueState {
+                messages: VecDeque::new(),
+                items: 0,
+            }),
+            pushed: Condvar::new(),
+            popped: Condvar::new(),
+            capacity,
+            policy,
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add a message to the back of the queue, dropping the oldest item or waiting for space if
+    /// it is full.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned.
+    pub fn push(&self, message: LogMessage) {
+        let mut state = self.state.lock().unwrap();
+        let is_item = matches!(message, LogMessage::Item(_));
+
+        if is_item {
+            while state.items >= self.capacity {
+                match self.policy {
+                    OverflowPolicy::DropOldest => {
+                        let oldest = state
+                            .messages
+                            .iter()
+                            .position(|m| matches!(m, LogMessage::Item(_)));
+
+                        if let Some(i) = oldest {
+                            state.messages.remove(i);
+                            state.items -= 1;
+                            self.dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    OverflowPolicy::Block => state = self.popped.wait(state).unwrap(),
+                }
+            }
+
+            state.items += 1;
+        }
+
+        state.messages.push_back(message);
+        self.pushed.notify_one();
+    }
+
+    /// Take the message at the front of the queue, waiting for one if it is empty.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned.
+    pub fn pop(&self) -> LogMessage {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if let Some(message) = state.messages.pop_front() {
+                if matches!(message, LogMessage::Item(_)) {
+                    state.items -= 1;
+                }
+
+                self.popped.notify_all();
+                return message;
+            }
+
+            state = self.pushed.wait(state).unwrap();
+        }
+    }
+
+    /// Returns the number of items waiting in this [`LogQueue`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().items
+    }
+
+    /// Returns `true` if no items are waiting in this [`LogQueue`].
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of items dropped because this [`LogQueue`] was full.
+    pub fn get_dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = &'static str;
+
+    /// Parse a policy name (`drop` or `block`), ignoring case.
+    fn from_str(data: &str) -> Result<OverflowPolicy, &'static str> {
+        match data.trim().to_lowercase().as_str() {
+            "drop" | "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "block" => Ok(OverflowPolicy::Block),
+            _ => Err("Unknown overflow policy"),
+        }
+    }
+}
+
+/// Check if a `strftime` style time format is valid, formatting with an invalid format panics.
+pub fn is_valid_time_format(format: &str) -> bool {
+    !StrftimeItems::new(format).any(|item| item == Item::Error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Take the messages of the items waiting in a queue.
+    fn drain(queue: &LogQueue) -> Vec<String> {
+        let mut messages = Vec::new();
+
+        while !queue.is_empty() {
+            if let LogMessage::Item(item) = queue.pop() {
+                messages.push(format!("{} {}", item.from, item.message));
+            }
+        }
+
+        messages
+    }
+
+    #[test]
+    fn full_queues_drop_the_oldest_item() {
+        let queue = LogQueue::new(2, OverflowPolicy::DropOldest);
+        let (reply, _) = mpsc::channel();
+
+        queue.push(LogMessage::Item(LogItem::info(
+            "a".to_string(),
+            "1".to_string(),
+        )));
+        queue.push(LogMessage::Flush(reply));
+        queue.push(LogMessage::Item(LogItem::info(
+            "a".to_string(),
+            "2".to_string(),
+        )));
+        queue.push(LogMessage::Item(LogItem::info(
+            "a".to_string(),
+            "3".to_string(),
+        )));
+
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.get_dropped(), 1);
+
+        // The flush is a control message, so it was kept.
+        assert!(matches!(queue.pop(), LogMessage::Flush(_)));
+        assert_eq!(drain(&queue), ["a 2", "a 3"]);
+    }
+
+    #[test]
+    fn items_below_the_level_are_not_logged() {
+        let logger = Logger::for_tests();
+        logger
+            .level
+            .store(LogLevel::Warning.get_code(), Ordering::Relaxed);
+
+        let child = logger.create_from("child".to_string());
+        child.log_info("ignored".to_string()).unwrap();
+        child.log_warning("kept".to_string()).unwrap();
+        logger.log_error("also kept".to_string()).unwrap();
+
+        assert!(!child.is_enabled(LogLevel::Debug));
+        assert_eq!(drain(&logger.sender), ["test.child kept", "test also kept"]);
+    }
+
+    #[test]
+    fn items_are_formatted_with_their_type() {
+        let item = LogItem::error("server".to_string(), "Failed".to_string());
+
+        assert_eq!(
+            Log::format_item(&item, "[%Y]", false)
+                .split_once(']')
+                .unwrap()
+                .1,
+            " error ] server - Failed"
+        );
+        assert!(Log::format_item(&item, "%Y", true)
+            .starts_with(ConsoleColor::Red.get_foreground_color()));
+    }
+
+    #[test]
+    fn invalid_time_formats_are_rejected() {
+        assert!(is_valid_time_format(DEFAULT_TIME_FORMAT));
+        assert!(is_valid_time_format("%H:%M"));
+        assert!(!is_valid_time_format("%Q"));
+    }
+
+    #[test]
+    fn overflow_policies_parse_ignoring_case() {
+        assert_eq!("Block".parse(), Ok(OverflowPolicy::Block));
+        assert_eq!("drop-oldest".parse(), Ok(OverflowPolicy::DropOldest));
+        assert!("later".parse::<OverflowPolicy>().is_err());
     }
 }
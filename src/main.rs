@@ -1,10 +1,18 @@
-use std::sync::mpsc;
+use std::{net::SocketAddr, sync::mpsc};
 
+use crate::config::Config;
 use crate::files::FileWatcher;
-use crate::http::server::Server;
+use crate::hooks::HookRunner;
+use crate::http::server::{self, Server};
 use crate::logging::logger::Log;
-use crate::messaging::MessageHub;
+use crate::messaging::{
+    notification_channel, ChangeNotifier, MessageHub, ServerChanges, SERVER_CHANGE_WINDOW,
+};
+pub mod cache;
+pub mod clock;
+pub mod config;
 pub mod files;
+pub mod hooks;
 pub mod http;
 pub mod logging;
 pub mod messaging;
@@ -12,18 +20,285 @@ pub mod watcher;
 pub mod ws;
 
 fn main() {
-    let log = Log::start().unwrap();
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(e) => exit_with_error(e),
+    };
 
+    // Only check the config, for use in scripts.
+    if std::env::args().skip(1).any(|arg| arg == "--check") {
+        match check(&config) {
+            Ok(output) => println!("{}", output),
+            Err(e) => exit_with_error(e),
+        }
+
+        return;
+    }
+
+    // Block until ctrl-c is pressed, then shut everything down.
+    let (shutdown_tx, shutdown_rx) = mpsc::channel();
+
+    if ctrlc::set_handler(move || {
+        let _ = shutdown_tx.send(());
+    })
+    .is_err()
+    {
+        exit_with_error("Could not set ctrl-c handler.");
+    }
+
+    let handle = match start(config) {
+        Ok(handle) => handle,
+        Err(e) => exit_with_error(e),
+    };
+
+    let _ = shutdown_rx.recv();
+    handle.shutdown();
+}
+
+/// Check a config is valid without starting anything, returning the output for `--check`.
+///
+/// # Errors
+///
+/// This function will return an error if the config is invalid.
+fn check(config: &Config) -> Result<String, &'static str> {
+    config.validate()?;
+
+    Ok(format!("{}\nConfig OK", config.get_summary()))
+}
+
+/// Print an error and exit with a failure status.
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+/// The running components of the dev server, returned by [`start`].
+pub struct ServerHandle {
+    server: Server,
+    watcher: FileWatcher,
+    hooks: HookRunner,
+    message_hub: MessageHub,
+    log: Log,
+}
+
+/// Start the dev server components, returning once they are running. The server runs until
+/// [`ServerHandle::shutdown`] is called.
+///
+/// # Errors
+///
+/// This function will return an error if the config is invalid or the log, file watcher or server
+/// can not be started.
+pub fn start(mut config: Config) -> Result<ServerHandle, &'static str> {
+    config.validate()?;
+
+    let log = Log::start_bounded(config.log_capacity, config.log_overflow)?;
+    log.set_level(config.log_level);
+    log.set_time_format(&config.log_time_format)?;
+
+    if let Some(color) = config.log_color {
+        log.set_color(color);
+    }
+
+    // A change storm can only fill the watcher's channel when it is bounded.
+    let (change_tx, change_rx) =
+        notification_channel(config.notification_capacity, config.notification_overflow);
+    let overflow_counter = change_tx.get_overflow_counter();
+
+    // Files written by the server notify the message hub straight away, the watcher's later
+    // notifications for them are dropped. Polling can see a change up to an interval late.
+    let server_changes =
+        ServerChanges::new(SERVER_CHANGE_WINDOW + config.poll_interval.unwrap_or_default());
+    let change_tx = change_tx.with_server_changes(server_changes.clone());
     let (not_tx, not_rx) = mpsc::channel();
     let (sub_tx, sub_rx) = mpsc::channel();
 
-    let base_path = "/home/max/Projects/sites/test".to_string();
+    let watcher = FileWatcher::start(
+        change_tx,
+        config.base_path.clone(),
+        config.poll_interval,
+        &log,
+    )
+    .map_err(|e| e.get_str())?;
+
+    // Changes pass through the build hooks before reaching the message hub.
+    let notifier = ChangeNotifier::new(not_tx.clone(), server_changes);
+    let hooks = HookRunner::start(
+        change_rx,
+        not_tx,
+        config.build_hooks.clone(),
+        config.hook_debounce,
+        config.hook_max_batch_age,
+        &log,
+    );
+
+    let message_hub = MessageHub::start(
+        sub_rx,
+        not_rx,
+        config.settle_period,
+        config.recent_notifications,
+        &log,
+    );
+
+    server::add_dev_routes(
+        &mut config.router,
+        message_hub.get_recent_notifications(),
+        message_hub.get_subscriber_counter(),
+        overflow_counter,
+    )?;
+
+    let server = Server::start(config, &log, sub_tx, notifier)?;
+
+    let logger = log.get_logger("main".to_string());
+    logger.log_info(format!("Listening on http://{}", server.get_address()))?;
+
+    Ok(ServerHandle {
+        server,
+        watcher,
+        hooks,
+        message_hub,
+        log,
+    })
+}
+
+impl ServerHandle {
+    /// Returns the address the server is listening on.
+    pub fn get_address(&self) -> SocketAddr {
+        self.server.get_address()
+    }
+
+    /// Shut the dev server down, returning once every component's thread has finished. The
+    /// server stops first (finishing the connections already accepted) then the file watcher,
+    /// build hooks and message hub, and finally the log is flushed.
+    pub fn shutdown(self) {
+        let logger = self.log.get_logger("main".to_string());
+        let _ = logger.log_info("Shutting down".to_string());
+
+        self.server.shutdown();
+        self.watcher.stop();
+        // The hooks finish once the watcher has stopped, then the hub once both have.
+        self.hooks.stop();
+        self.message_hub.stop();
+        self.log.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::TcpStream,
+        path::{Path, PathBuf},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::logging::common::LogLevel;
+
+    /// Create an empty directory to serve, unique to the test.
+    fn create_root(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("dev_server_main_{}_{}", std::process::id(), name));
+
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("index.html"), "<p>Hello</p>").unwrap();
+        root
+    }
+
+    fn test_config(root: &Path) -> Config {
+        Config {
+            address: "127.0.0.1:0".to_string(),
+            base_path: root.to_string_lossy().into_owned(),
+            log_level: LogLevel::Error,
+            ..Default::default()
+        }
+    }
+
+    fn get_status_line(address: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut response = String::new();
+
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        stream.read_to_string(&mut response).unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn check_reports_whether_the_config_is_valid() {
+        let root = create_root("check");
+        let config = test_config(&root);
+        let output = check(&config).unwrap();
+
+        assert!(output.starts_with("Address: 127.0.0.1:0\n"));
+        assert!(output.ends_with("\nConfig OK"));
+
+        let config = Config {
+            base_path: root.join("missing").to_string_lossy().into_owned(),
+            ..test_config(&root)
+        };
+
+        assert_eq!(check(&config), Err("The base path does not exist"));
+
+        let config = Config {
+            address: "localhost".to_string(),
+            ..test_config(&root)
+        };
+
+        assert!(check(&config)
+            .unwrap_err()
+            .starts_with("Invalid bind address"));
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn shutdown_callback_stops_every_thread() {
+        let root = create_root("shutdown");
+        let handle = start(test_config(&root)).unwrap();
+        let address = handle.get_address();
+
+        assert_eq!(get_status_line(address), "HTTP/1.1 200 OK");
+
+        // The same callback main registers for ctrl-c.
+        let (shutdown_tx, shutdown_rx) = mpsc::channel();
+        let callback = move || {
+            let _ = shutdown_tx.send(());
+        };
+        let (done_tx, done_rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let _ = shutdown_rx.recv();
+            // Only returns once every component's thread has been joined.
+            handle.shutdown();
+            let _ = done_tx.send(());
+        });
+
+        callback();
+
+        assert!(done_rx.recv_timeout(Duration::from_secs(10)).is_ok());
+        assert!(TcpStream::connect(address).is_err());
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn servers_can_be_started_and_stopped_repeatedly() {
+        let root = create_root("repeat");
 
-    let watch = FileWatcher::start(not_tx, base_path.clone(), &log);
+        for _ in 0..2 {
+            let first = start(test_config(&root)).unwrap();
+            let second = start(test_config(&root)).unwrap();
 
-    let message_hub = MessageHub::start(sub_rx, not_rx, &log);
+            assert_eq!(get_status_line(first.get_address()), "HTTP/1.1 200 OK");
+            assert_eq!(get_status_line(second.get_address()), "HTTP/1.1 200 OK");
 
-    let server = Server::start("127.0.0.1:8080".to_string(), &log, sub_tx, base_path);
+            first.shutdown();
+            second.shutdown();
+        }
 
-    loop {}
+        let _ = fs::remove_dir_all(root);
+    }
 }
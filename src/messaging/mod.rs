@@ -1,10 +1,24 @@
 use std::{
-    sync::mpsc::{Receiver, Sender},
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SendError, Sender, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use crate::logging::logger::Log;
+use crate::{
+    clock::{Clock, SystemClock},
+    files::path_matches,
+    logging::logger::{Log, Logger},
+};
+
+/// How long after the server changes a file the file watcher's notification for the same path is
+/// dropped. The native watcher delivers events after a one second delay.
+pub const SERVER_CHANGE_WINDOW: Duration = Duration::from_secs(2);
 
 #[derive(Clone)]
 pub enum Notification {
@@ -12,18 +26,134 @@ pub enum Notification {
     FileUpdated(String),
     FileRemoved(String),
     FileRenamed(String, String),
+    /// Sent once changes have stopped for the settle period, for example after a build that
+    /// writes many files has finished.
+    FilesSettled,
+}
+
+/// The kind of a [`Notification`], used by subscribers to pick the notifications they receive.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NotificationKind {
+    FileCreated,
+    FileUpdated,
+    FileRemoved,
+    FileRenamed,
+    FilesSettled,
+}
+
+/// What happens when a notification is sent to a full bounded channel.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NotificationOverflow {
+    /// Drop the notification being sent, the receiver still has the earlier ones to act on.
+    DropNewest,
+    /// Wait until there is space in the channel.
+    Block,
+}
+
+/// Tracks when the files settle, once there have been no changes for the settle period after a
+/// change.
+struct SettleTimer {
+    period: Duration,
+    /// When the last change happened, cleared once the files have settled.
+    last_change: Option<Instant>,
+}
+
+/// The sending half of a notification channel, see [`notification_channel`].
+#[derive(Clone)]
+pub struct NotificationSender {
+    sender: ChannelSender,
+    overflow: NotificationOverflow,
+    overflowed: Arc<AtomicUsize>,
+    /// Notifications for files recently changed by the server are dropped, it has already sent
+    /// its own.
+    server_changes: Option<ServerChanges>,
+}
+
+#[derive(Clone)]
+enum ChannelSender {
+    Unbounded(Sender<Notification>),
+    Bounded(SyncSender<Notification>),
+}
+
+/// The files the server recently changed itself, so the file watcher's notifications for the same
+/// changes can be dropped. Clones share the same record.
+#[derive(Clone)]
+pub struct ServerChanges {
+    /// When each path was last changed by the server.
+    changes: Arc<Mutex<HashMap<String, Instant>>>,
+    /// How long after a change notifications for the path are dropped.
+    window: Duration,
+}
+
+/// Sends notifications for changes the server made itself straight to the [`MessageHub`], so
+/// clients reload without waiting for the file watcher.
+#[derive(Clone)]
+pub struct ChangeNotifier {
+    sender: Sender<Notification>,
+    changes: ServerChanges,
+    /// Times the recorded changes, this must be the clock the file watcher checks them with.
+    clock: Arc<dyn Clock>,
+}
+
+/// How a client should reload for a [`Notification`].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ReloadStrategy {
+    /// Reload the whole page.
+    Full,
+    /// Reload only the changed stylesheet, keeping the page's state.
+    Css,
+    /// Nothing to reload.
+    None,
+}
+
+/// A notification the [`MessageHub`] processed.
+#[derive(Clone)]
+pub struct RecentNotification {
+    pub notification: Notification,
+    /// When the notification was processed, in RFC 3339 format.
+    pub time: String,
+}
+
+/// A bounded buffer of the most recent notifications the [`MessageHub`] processed, the oldest
+/// are dropped as new ones arrive. Clones share the same buffer so it can be read from other
+/// threads.
+#[derive(Clone)]
+pub struct RecentNotifications {
+    notifications: Arc<Mutex<VecDeque<RecentNotification>>>,
+    capacity: usize,
+    /// Timestamps the notifications.
+    clock: Arc<dyn Clock>,
 }
 
+/// A subscriber's channel and the notifications it wants. By default all file change
+/// notifications are sent, [`Notification::FilesSettled`] is only sent if subscribed to.
 pub struct Subscription {
     sender: Sender<Notification>,
+    /// The kinds of notification to send, `None` for all file change kinds.
+    kinds: Option<Vec<NotificationKind>>,
+    /// A glob pattern the changed file must match (see [`path_matches`]), `None` for all files.
+    pattern: Option<String>,
+    /// Set once the subscriber has gone, see [`Subscription::get_unsubscriber`].
+    closed: Arc<AtomicBool>,
+}
+
+/// Ends a [`Subscription`] from another thread, for example when a client closes its connection.
+#[derive(Clone)]
+pub struct Unsubscriber {
+    closed: Arc<AtomicBool>,
 }
 
 pub struct MessageHub {
     thread: JoinHandle<()>,
+    running: Arc<AtomicBool>,
+    subscriber_count: Arc<AtomicUsize>,
+    recent_notifications: RecentNotifications,
 }
 
 impl MessageHub {
-    /// Start the [`MessageHub`].
+    /// Start the [`MessageHub`]. A [`Notification::FilesSettled`] is sent once there have been no
+    /// changes for `settle_period` after a change. The last `recent_capacity` notifications are
+    /// kept, see [`MessageHub::get_recent_notifications`].
     ///
     /// # Panics
     ///
@@ -31,74 +161,670 @@ impl MessageHub {
     pub fn start(
         receiver: Receiver<Subscription>,
         notifications: Receiver<Notification>,
+        settle_period: Duration,
+        recent_capacity: usize,
         log: &Log,
     ) -> MessageHub {
-        let mut subscribers: Vec<Sender<Notification>> = Vec::new();
+        MessageHub::start_with_clock(
+            receiver,
+            notifications,
+            settle_period,
+            recent_capacity,
+            log,
+            Arc::new(SystemClock),
+        )
+    }
 
-        // A vec to the index of any broken subscriptions, so the can be dropped.
-        let mut dead_subs: Vec<usize> = Vec::new();
+    /// Start the [`MessageHub`], timing the settle period with `clock`.
+    pub fn start_with_clock(
+        receiver: Receiver<Subscription>,
+        notifications: Receiver<Notification>,
+        settle_period: Duration,
+        recent_capacity: usize,
+        log: &Log,
+        clock: Arc<dyn Clock>,
+    ) -> MessageHub {
+        let mut subscribers: Vec<Subscription> = Vec::new();
+        let mut settle_timer = SettleTimer::new(settle_period);
         let logger = log.get_logger("message_hub".to_string());
 
-        let thread = thread::spawn(move || loop {
-            // Check for new subscribers
-            match receiver.try_recv() {
-                Ok(sub) => {
+        let subscriber_count = Arc::new(AtomicUsize::new(0));
+        let count = subscriber_count.clone();
+
+        let recent_notifications = RecentNotifications::with_clock(recent_capacity, clock.clone());
+        let recent = recent_notifications.clone();
+
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+
+        let thread = thread::spawn(move || {
+            while thread_running.load(Ordering::SeqCst) {
+                // Check for new subscribers
+                if let Ok(sub) = receiver.try_recv() {
                     logger
                         .log_info("Subscription received".to_string())
                         .unwrap();
-                    subscribers.push(sub.sender);
+                    subscribers.push(sub);
+                    count.store(subscribers.len(), Ordering::Relaxed);
                 }
-                Err(_) => {}
-            };
 
-            // Check for notifications and send them to subscribers.
-            match notifications.recv_timeout(Duration::from_secs(1)) {
-                Ok(notification) => {
+                // Drop the subscribers that have unsubscribed, this also closes their channels.
+                let subscribed = subscribers.len();
+                subscribers.retain(|sub| !sub.is_closed());
+
+                if subscribers.len() < subscribed {
                     logger
-                        .log_info("Notification received".to_string())
+                        .log_info(format!(
+                            "{} subscription(s) ended",
+                            subscribed - subscribers.len()
+                        ))
                         .unwrap();
-                    for (i, sub) in &mut subscribers.iter().enumerate() {
-                        match sub.send(notification.clone()) {
-                            Ok(_) => logger
-                                .log_info("Notification sent to subscriber".to_string())
-                                .unwrap(),
-                            Err(e) => {
-                                // Subscriber pipe broken. Drop subscriber.
-                                logger.log_warning(format!("Failure sending to subscriber, subscription to be dropped. Error: {}", e)).unwrap();
-                                dead_subs.push(i);
-                            }
-                        };
-                    }
+                    count.store(subscribers.len(), Ordering::Relaxed);
+                }
+
+                // Wait for notifications, waking up in time to send a settled notification.
+                let timeout = settle_timer
+                    .remaining(clock.now())
+                    .map_or(Duration::from_secs(1), |remaining| {
+                        remaining.min(Duration::from_secs(1))
+                    });
 
-                    // Remove any dead subs.
-                    if dead_subs.len() > 0 {
-                        // Revserve so subs with a highest index are removed first.
-                        // Example:
-                        // 0, 1*, 2, 3* (* = remove).
-                        // 3 will be removed leaving 0, 1, 2.
-                        // Then 1 will be removed. To avoid calculating new next etc.
-                        dead_subs.reverse();
-
-                        for i in &dead_subs {
-                            subscribers.remove(*i);
+                // Check for notifications and send them to subscribers.
+                match notifications.recv_timeout(timeout) {
+                    Ok(notification) => {
+                        logger
+                            .log_info("Notification received".to_string())
+                            .unwrap();
+                        recent.push(&notification);
+                        broadcast(&mut subscribers, &notification, &logger);
+                        count.store(subscribers.len(), Ordering::Relaxed);
+                        settle_timer.record_change(clock.now());
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        // The files have settled once there have been no changes for the settle
+                        // period.
+                        if settle_timer.check_settled(clock.now()) {
+                            logger.log_info("Files settled".to_string()).unwrap();
+                            recent.push(&Notification::FilesSettled);
+                            broadcast(&mut subscribers, &Notification::FilesSettled, &logger);
+                            count.store(subscribers.len(), Ordering::Relaxed);
                         }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        // No more notifications will arrive, dropping the subscribers closes their
+                        // channels.
+                        break;
+                    }
+                };
+            }
+        });
 
-                        dead_subs.clear();
-                    };
-                }
-                Err(_) => {
-                    // No notifications after timeout. Do nothing.
+        MessageHub {
+            thread,
+            running,
+            subscriber_count,
+            recent_notifications,
+        }
+    }
+
+    /// Get a handle to the most recent notifications this [`MessageHub`] processed.
+    pub fn get_recent_notifications(&self) -> RecentNotifications {
+        self.recent_notifications.clone()
+    }
+
+    /// Returns the number of subscribers this [`MessageHub`] currently has.
+    ///
+    /// Subscribers are dropped when they unsubscribe or sending a notification to them fails, so
+    /// a client that disconnects without unsubscribing is counted until the next notification.
+    pub fn subscriber_count(&self) -> usize {
+        self.subscriber_count.load(Ordering::Relaxed)
+    }
+
+    /// Get a handle to the subscriber count, for reading it from other threads.
+    pub fn get_subscriber_counter(&self) -> Arc<AtomicUsize> {
+        self.subscriber_count.clone()
+    }
+
+    /// Stop the [`MessageHub`] and wait for its thread to finish, dropping the subscribers.
+    pub fn stop(self) {
+        self.running.store(false, Ordering::SeqCst);
+        let _ = self.thread.join();
+    }
+}
+
+impl SettleTimer {
+    /// Creates a new [`SettleTimer`].
+    fn new(period: Duration) -> SettleTimer {
+        SettleTimer {
+            period,
+            last_change: None,
+        }
+    }
+
+    /// Record a change at `now`, restarting the settle period.
+    fn record_change(&mut self, now: Instant) {
+        self.last_change = Some(now);
+    }
+
+    /// Returns how long until the files settle, `None` if there are no unsettled changes.
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.last_change.map(|time| {
+            self.period
+                .saturating_sub(now.saturating_duration_since(time))
+        })
+    }
+
+    /// Returns `true` if the settle period has passed since the last change, only once per
+    /// settle.
+    fn check_settled(&mut self, now: Instant) -> bool {
+        if self
+            .remaining(now)
+            .is_some_and(|remaining| remaining.is_zero())
+        {
+            self.last_change = None;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl NotificationSender {
+    /// Send a notification. If the channel is bounded and full the notification is dropped or
+    /// the caller blocks (depending on the overflow policy), either way the overflow counter is
+    /// incremented.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the receiver has been dropped.
+    pub fn send(&self, notification: Notification) -> Result<(), SendError<Notification>> {
+        self.send_at(notification, Instant::now())
+    }
+
+    /// Send a notification for a change seen at `now`, see [`NotificationSender::send`]. The
+    /// time is checked against the server's own recent changes.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the receiver has been dropped.
+    pub fn send_at(
+        &self,
+        notification: Notification,
+        now: Instant,
+    ) -> Result<(), SendError<Notification>> {
+        if let (Some(changes), Some(path)) = (&self.server_changes, notification.get_path()) {
+            if changes.is_recent(path, now) {
+                return Ok(());
+            }
+        }
+
+        let sender = match &self.sender {
+            ChannelSender::Unbounded(sender) => return sender.send(notification),
+            ChannelSender::Bounded(sender) => sender,
+        };
+
+        match sender.try_send(notification) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Disconnected(notification)) => Err(SendError(notification)),
+            Err(TrySendError::Full(notification)) => {
+                self.overflowed.fetch_add(1, Ordering::SeqCst);
+
+                match self.overflow {
+                    NotificationOverflow::DropNewest => Ok(()),
+                    NotificationOverflow::Block => sender.send(notification),
                 }
-            };
+            }
+        }
+    }
+
+    /// Get the counter of notifications sent to a full channel (dropped or blocked).
+    pub fn get_overflow_counter(&self) -> Arc<AtomicUsize> {
+        self.overflowed.clone()
+    }
+
+    /// Drop notifications for files the server recently changed itself.
+    pub fn with_server_changes(mut self, changes: ServerChanges) -> NotificationSender {
+        self.server_changes = Some(changes);
+        self
+    }
+}
+
+impl ServerChanges {
+    /// Creates a new [`ServerChanges`], changes are remembered for `window`.
+    pub fn new(window: Duration) -> ServerChanges {
+        ServerChanges {
+            changes: Arc::new(Mutex::new(HashMap::new())),
+            window,
+        }
+    }
+
+    /// Record a change the server made to a file at `now`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned.
+    pub fn record(&self, path: &str, now: Instant) {
+        self.changes.lock().unwrap().insert(path.to_string(), now);
+    }
+
+    /// Check if the server changed a file within the window before `now`. Expired changes are
+    /// forgotten.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned.
+    pub fn is_recent(&self, path: &str, now: Instant) -> bool {
+        let mut changes = self.changes.lock().unwrap();
+
+        changes.retain(|_, time| now.saturating_duration_since(*time) < self.window);
+        changes.contains_key(path)
+    }
+}
+
+impl ChangeNotifier {
+    /// Creates a new [`ChangeNotifier`] sending to the [`MessageHub`]'s notification channel
+    /// and recording the changes in `changes`.
+    pub fn new(sender: Sender<Notification>, changes: ServerChanges) -> ChangeNotifier {
+        ChangeNotifier {
+            sender,
+            changes,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Time the recorded changes with `clock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> ChangeNotifier {
+        self.clock = clock;
+        self
+    }
+
+    /// Send a notification for a change the server made, recording it so the file watcher's
+    /// notification for the same file is dropped.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the [`MessageHub`] has stopped.
+    pub fn notify(&self, notification: Notification) -> Result<(), SendError<Notification>> {
+        if let Some(path) = notification.get_path() {
+            self.changes.record(path, self.clock.now());
+        }
+
+        self.sender.send(notification)
+    }
+}
+
+impl FromStr for NotificationOverflow {
+    type Err = &'static str;
+
+    /// Parse a policy name (`drop` or `block`), ignoring case.
+    fn from_str(data: &str) -> Result<NotificationOverflow, &'static str> {
+        match data.trim().to_lowercase().as_str() {
+            "drop" | "drop-newest" => Ok(NotificationOverflow::DropNewest),
+            "block" => Ok(NotificationOverflow::Block),
+            _ => Err("Unknown overflow policy"),
+        }
+    }
+}
+
+impl ReloadStrategy {
+    /// Returns the name of this [`ReloadStrategy`] sent to clients.
+    pub fn get_str(&self) -> &'static str {
+        match self {
+            ReloadStrategy::Full => "full",
+            ReloadStrategy::Css => "css",
+            ReloadStrategy::None => "none",
+        }
+    }
+}
+
+impl Notification {
+    /// Returns how a client should reload for this [`Notification`]. Created and updated
+    /// stylesheets are hot-swapped if `css_hot_reload` is set, any other file change needs a full
+    /// reload.
+    pub fn get_reload_strategy(&self, css_hot_reload: bool) -> ReloadStrategy {
+        match self {
+            Notification::FileCreated(path) | Notification::FileUpdated(path)
+                if css_hot_reload && path.to_lowercase().ends_with(".css") =>
+            {
+                ReloadStrategy::Css
+            }
+            Notification::FilesSettled => ReloadStrategy::None,
+            _ => ReloadStrategy::Full,
+        }
+    }
+
+    /// Returns the kind of this [`Notification`].
+    pub fn get_kind(&self) -> NotificationKind {
+        match self {
+            Notification::FileCreated(_) => NotificationKind::FileCreated,
+            Notification::FileUpdated(_) => NotificationKind::FileUpdated,
+            Notification::FileRemoved(_) => NotificationKind::FileRemoved,
+            Notification::FileRenamed(_, _) => NotificationKind::FileRenamed,
+            Notification::FilesSettled => NotificationKind::FilesSettled,
+        }
+    }
+}
+
+impl NotificationKind {
+    /// Returns the name of this [`NotificationKind`].
+    pub fn get_str(&self) -> &'static str {
+        match self {
+            NotificationKind::FileCreated => "created",
+            NotificationKind::FileUpdated => "updated",
+            NotificationKind::FileRemoved => "removed",
+            NotificationKind::FileRenamed => "renamed",
+            NotificationKind::FilesSettled => "settled",
+        }
+    }
+}
+
+impl Notification {
+    /// Returns the path of the file this [`Notification`] is for (the new path for renames),
+    /// `None` if it is not for a file.
+    pub fn get_path(&self) -> Option<&str> {
+        match self {
+            Notification::FileCreated(path)
+            | Notification::FileUpdated(path)
+            | Notification::FileRemoved(path)
+            | Notification::FileRenamed(_, path) => Some(path),
+            Notification::FilesSettled => None,
+        }
+    }
+}
+
+impl Unsubscriber {
+    /// End the [`Subscription`], the [`MessageHub`] drops it (and closes its channel) the next
+    /// time it checks for subscribers.
+    pub fn unsubscribe(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+impl RecentNotifications {
+    /// Creates a new [`RecentNotifications`] keeping at most `capacity` notifications.
+    pub fn new(capacity: usize) -> RecentNotifications {
+        RecentNotifications::with_clock(capacity, Arc::new(SystemClock))
+    }
+
+    /// Creates a new [`RecentNotifications`], timestamping the notifications with `clock`.
+    pub fn with_clock(capacity: usize, clock: Arc<dyn Clock>) -> RecentNotifications {
+        RecentNotifications {
+            notifications: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+            clock,
+        }
+    }
+
+    /// Record a notification, dropping the oldest if the buffer is full.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned.
+    pub fn push(&self, notification: &Notification) {
+        let mut notifications = self.notifications.lock().unwrap();
+
+        notifications.push_back(RecentNotification {
+            notification: notification.clone(),
+            time: self.clock.now_utc().to_rfc3339(),
         });
 
-        MessageHub { thread }
+        while notifications.len() > self.capacity {
+            notifications.pop_front();
+        }
+    }
+
+    /// Get the recorded notifications, the most recent first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock is poisoned.
+    pub fn list(&self) -> Vec<RecentNotification> {
+        self.notifications
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
     }
 }
 
 impl Subscription {
-    /// Creates a new [`Subscription`].
+    /// Creates a new [`Subscription`] to all notifications.
     pub fn new(sender: Sender<Notification>) -> Subscription {
-        Subscription { sender }
+        Subscription {
+            sender,
+            kinds: None,
+            pattern: None,
+            closed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Get a handle for ending this [`Subscription`] once it has been sent to the
+    /// [`MessageHub`].
+    pub fn get_unsubscriber(&self) -> Unsubscriber {
+        Unsubscriber {
+            closed: self.closed.clone(),
+        }
+    }
+
+    /// Check if this [`Subscription`] has been ended.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Only send notifications of the given kinds.
+    pub fn with_kinds(mut self, kinds: &[NotificationKind]) -> Subscription {
+        self.kinds = Some(kinds.to_vec());
+        self
+    }
+
+    /// Only send notifications for files matching a glob pattern.
+    pub fn with_pattern(mut self, pattern: &str) -> Subscription {
+        self.pattern = Some(pattern.to_string());
+        self
+    }
+
+    /// Check if a notification should be sent to this subscriber, it must be one of the
+    /// subscribed kinds and (for renames, either path) match the pattern.
+    pub fn is_match(&self, notification: &Notification) -> bool {
+        let kind = notification.get_kind();
+        let kind_matches = match &self.kinds {
+            Some(kinds) => kinds.contains(&kind),
+            None => kind != NotificationKind::FilesSettled,
+        };
+
+        let path_ok = |path: &str| {
+            self.pattern
+                .as_ref()
+                .is_none_or(|pattern| path_matches(pattern, path))
+        };
+
+        kind_matches
+            && match notification {
+                Notification::FileCreated(path)
+                | Notification::FileUpdated(path)
+                | Notification::FileRemoved(path) => path_ok(path),
+                Notification::FileRenamed(from, to) => path_ok(from) || path_ok(to),
+                // Not for a single file, so the pattern does not apply.
+                Notification::FilesSettled => true,
+            }
+    }
+}
+
+/// Send a notification to the subscribers wanting it, dropping any subscribers that can not be
+/// sent to.
+///
+/// # Panics
+///
+/// Panics if there is an issue with the logger.
+fn broadcast(subscribers: &mut Vec<Subscription>, notification: &Notification, logger: &Logger) {
+    subscribers.retain(|sub| {
+        if !sub.is_match(notification) {
+            return true;
+        }
+
+        match sub.sender.send(notification.clone()) {
+            Ok(_) => {
+                logger
+                    .log_info("Notification sent to subscriber".to_string())
+                    .unwrap();
+                true
+            }
+            Err(e) => {
+                // Subscriber pipe broken. Drop subscriber.
+                logger
+                    .log_warning(format!(
+                        "Failure sending to subscriber, subscription to be dropped. Error: {}",
+                        e
+                    ))
+                    .unwrap();
+                false
+            }
+        }
+    });
+}
+
+/// Create a notification channel, bounded to `capacity` notifications or unbounded if `None`.
+pub fn notification_channel(
+    capacity: Option<usize>,
+    overflow: NotificationOverflow,
+) -> (NotificationSender, Receiver<Notification>) {
+    let (sender, receiver) = match capacity {
+        Some(capacity) => {
+            let (sender, receiver) = mpsc::sync_channel(capacity);
+            (ChannelSender::Bounded(sender), receiver)
+        }
+        None => {
+            let (sender, receiver) = mpsc::channel();
+            (ChannelSender::Unbounded(sender), receiver)
+        }
+    };
+
+    let sender = NotificationSender {
+        sender,
+        overflow,
+        overflowed: Arc::new(AtomicUsize::new(0)),
+        server_changes: None,
+    };
+
+    (sender, receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn unsubscribed_subscribers_are_dropped() {
+        let log = Log::start().unwrap();
+        let (sub_tx, sub_rx) = mpsc::channel();
+        let (_not_tx, not_rx) = mpsc::channel();
+        let hub = MessageHub::start(sub_rx, not_rx, Duration::from_secs(60), 10, &log);
+
+        let (tx, rx) = mpsc::channel();
+        let subscription = Subscription::new(tx);
+        let unsubscriber = subscription.get_unsubscriber();
+        sub_tx.send(subscription).unwrap();
+
+        wait_for(|| hub.subscriber_count() == 1);
+        unsubscriber.unsubscribe();
+        wait_for(|| hub.subscriber_count() == 0);
+
+        // The hub dropped its sender, so the subscriber's channel is closed.
+        assert!(matches!(
+            rx.recv_timeout(Duration::from_secs(1)),
+            Err(RecvTimeoutError::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn files_settle_once_after_the_settle_period() {
+        let clock = ManualClock::new();
+        let mut timer = SettleTimer::new(Duration::from_secs(2));
+
+        assert_eq!(timer.remaining(clock.now()), None);
+        assert!(!timer.check_settled(clock.now()));
+
+        timer.record_change(clock.now());
+        clock.advance(Duration::from_millis(1500));
+        timer.record_change(clock.now());
+
+        // The second change restarted the period.
+        clock.advance(Duration::from_millis(1999));
+        assert!(!timer.check_settled(clock.now()));
+        assert_eq!(timer.remaining(clock.now()), Some(Duration::from_millis(1)));
+
+        clock.advance(Duration::from_millis(1));
+        assert!(timer.check_settled(clock.now()));
+        assert!(!timer.check_settled(clock.now()));
+    }
+
+    #[test]
+    fn server_changes_are_dropped_within_the_window() {
+        let clock = ManualClock::new();
+        let changes = ServerChanges::new(Duration::from_secs(5));
+        let (sender, receiver) = notification_channel(None, NotificationOverflow::Block);
+        let sender = sender.with_server_changes(changes.clone());
+
+        let (hub_tx, hub_rx) = mpsc::channel();
+        let notifier = ChangeNotifier::new(hub_tx, changes).with_clock(Arc::new(clock.clone()));
+        notifier
+            .notify(Notification::FileUpdated("/a.html".to_string()))
+            .unwrap();
+        assert!(hub_rx.try_recv().is_ok());
+
+        // The watcher's notification for the same change is dropped, other files are not.
+        clock.advance(Duration::from_millis(4999));
+        let update = |path: &str| Notification::FileUpdated(path.to_string());
+        sender.send_at(update("/a.html"), clock.now()).unwrap();
+        sender.send_at(update("/b.html"), clock.now()).unwrap();
+
+        clock.advance(Duration::from_millis(1));
+        sender.send_at(update("/a.html"), clock.now()).unwrap();
+
+        let sent: Vec<String> = receiver
+            .try_iter()
+            .map(|n| n.get_path().unwrap().to_string())
+            .collect();
+        assert_eq!(sent, ["/b.html", "/a.html"]);
+    }
+
+    #[test]
+    fn recent_notifications_are_timestamped_by_the_clock() {
+        let clock = ManualClock::new();
+        let recent = RecentNotifications::with_clock(2, Arc::new(clock.clone()));
+        let start = clock.now_utc();
+
+        for path in ["/a.html", "/b.html", "/c.html"] {
+            recent.push(&Notification::FileUpdated(path.to_string()));
+            clock.advance(Duration::from_secs(60));
+        }
+
+        let list = recent.list();
+        let paths: Vec<&str> = list
+            .iter()
+            .map(|r| r.notification.get_path().unwrap())
+            .collect();
+        assert_eq!(paths, ["/c.html", "/b.html"]);
+        assert_eq!(
+            list[0].time,
+            (start + chrono::Duration::seconds(120)).to_rfc3339()
+        );
+        assert_eq!(
+            list[1].time,
+            (start + chrono::Duration::seconds(60)).to_rfc3339()
+        );
+    }
+
+    /// Wait (up to five seconds) for a condition the hub's thread sets.
+    fn wait_for(condition: impl Fn() -> bool) {
+        let start = Instant::now();
+
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
     }
 }
@@ -1,31 +1,519 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    mem,
+};
+
+use flate2::{write::DeflateEncoder, Compression};
 use sha1::{Digest, Sha1};
 
+/// The close code for a connection closing normally.
+pub const CLOSE_NORMAL: u16 = 1000;
+
+/// The close code for a connection closed because the other end broke the protocol.
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+/// The close code for a connection closed because a message was too big to process.
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+/// The close code for a connection closed because of an error on the server.
+pub const CLOSE_SERVER_ERROR: u16 = 1011;
+
+/// The GUID combined with the client's key to create the `Sec-WebSocket-Accept` value (RFC 6455
+/// section 1.3).
+pub const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The name of the `permessage-deflate` extension (RFC 7692).
+pub const PERMESSAGE_DEFLATE: &str = "permessage-deflate";
+
+/// The `Sec-WebSocket-Extensions` response accepting `permessage-deflate`. Each message is
+/// compressed on its own, so the server never needs to keep a compression context.
+pub const PERMESSAGE_DEFLATE_RESPONSE: &str = "permessage-deflate; server_no_context_takeover";
+
+/// The bytes a sync flush ends with, left off compressed messages (RFC 7692 section 7.2.1).
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+/// The largest payload a control frame (close, ping or pong) can have.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// The type of a frame.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+/// A frame received from a client.
+pub struct Frame {
+    /// Set on the final frame of a message.
+    pub fin: bool,
+    pub opcode: Opcode,
+    /// The unmasked payload.
+    pub payload: Vec<u8>,
+}
+
+/// A complete message received from a client, fragmented messages are reassembled.
+pub struct Message {
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+/// Errors returned when reading messages from a client.
+#[derive(Debug)]
+pub enum ReadError {
+    /// The client broke the WebSocket protocol.
+    Protocol(&'static str),
+    /// A message larger than the maximum message size was sent.
+    MessageTooBig,
+    /// The connection was closed or could not be read.
+    Closed,
+}
+
+/// Reads messages from a client, buffering received data until whole frames have arrived and
+/// continuation frames until the final frame of a fragmented message.
+pub struct MessageReader {
+    buffer: Vec<u8>,
+    fragments: Option<(Opcode, Vec<u8>)>,
+    max_message_size: usize,
+}
+
+impl Opcode {
+    /// Create an [`Opcode`] from a frame's opcode.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the opcode is reserved.
+    pub fn from_code(code: u8) -> Result<Opcode, &'static str> {
+        match code {
+            0x0 => Ok(Opcode::Continuation),
+            0x1 => Ok(Opcode::Text),
+            0x2 => Ok(Opcode::Binary),
+            0x8 => Ok(Opcode::Close),
+            0x9 => Ok(Opcode::Ping),
+            0xA => Ok(Opcode::Pong),
+            _ => Err("Unknown frame opcode"),
+        }
+    }
+
+    /// Returns the frame opcode of this [`Opcode`].
+    pub fn get_code(&self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    /// Returns true if this [`Opcode`] is for a control frame (close, ping or pong).
+    pub fn is_control(&self) -> bool {
+        self.get_code() & 0x8 != 0
+    }
+}
+
+impl ReadError {
+    /// Returns a reference to the message of this [`ReadError`].
+    pub fn get_str(&self) -> &'static str {
+        match self {
+            ReadError::Protocol(message) => message,
+            ReadError::MessageTooBig => "Message too big",
+            ReadError::Closed => "Connection closed",
+        }
+    }
+
+    /// Returns the close code to send to the client for this [`ReadError`], `None` if the
+    /// connection is already closed.
+    pub fn get_close_code(&self) -> Option<u16> {
+        match self {
+            ReadError::Protocol(_) => Some(CLOSE_PROTOCOL_ERROR),
+            ReadError::MessageTooBig => Some(CLOSE_MESSAGE_TOO_BIG),
+            ReadError::Closed => None,
+        }
+    }
+}
+
+impl MessageReader {
+    /// Creates a new [`MessageReader`] accepting messages of up to `max_message_size` bytes.
+    pub fn new(max_message_size: usize) -> MessageReader {
+        MessageReader {
+            buffer: Vec::new(),
+            fragments: None,
+            max_message_size,
+        }
+    }
+
+    /// Add data received from the client.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Returns the next complete message from the received data, or `None` if more data is
+    /// needed. Control frames can arrive between the fragments of a message and are returned
+    /// straight away.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a frame is malformed, fragments are out of order or
+    /// a message is larger than the maximum message size.
+    pub fn next_message(&mut self) -> Result<Option<Message>, ReadError> {
+        loop {
+            // The frames of a fragmented message count towards the same limit, so the space left
+            // changes with each frame.
+            let received = self
+                .fragments
+                .as_ref()
+                .map_or(0, |(_, payload)| payload.len());
+            let max_length = self.max_message_size.saturating_sub(received);
+
+            let (frame, used) = match parse_frame(&self.buffer, max_length)? {
+                Some(parsed) => parsed,
+                None => return Ok(None),
+            };
+            self.buffer.drain(..used);
+
+            if frame.opcode.is_control() {
+                if !frame.fin {
+                    return Err(ReadError::Protocol("Control frames can not be fragmented"));
+                }
+
+                return Ok(Some(Message {
+                    opcode: frame.opcode,
+                    payload: frame.payload,
+                }));
+            }
+
+            let (opcode, payload) = match (self.fragments.take(), frame.opcode) {
+                (None, Opcode::Continuation) => {
+                    return Err(ReadError::Protocol("Continuation frame without a message"))
+                }
+                (None, opcode) => (opcode, frame.payload),
+                (Some((opcode, mut payload)), Opcode::Continuation) => {
+                    payload.extend_from_slice(&frame.payload);
+                    (opcode, payload)
+                }
+                (Some(_), _) => {
+                    return Err(ReadError::Protocol("Expected a continuation frame"));
+                }
+            };
+
+            if frame.fin {
+                return Ok(Some(Message { opcode, payload }));
+            }
+
+            self.fragments = Some((opcode, payload));
+        }
+    }
+
+    /// Read from a stream until a complete message has been received.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the stream is closed or can not be read, or the
+    /// client sends an invalid frame.
+    pub fn read_message<R: Read>(&mut self, stream: &mut R) -> Result<Message, ReadError> {
+        let mut buffer = [0; 4096];
+
+        loop {
+            if let Some(message) = self.next_message()? {
+                return Ok(message);
+            }
+
+            match stream.read(&mut buffer) {
+                Ok(0) => return Err(ReadError::Closed),
+                Ok(n) => self.push(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return Err(ReadError::Closed),
+            }
+        }
+    }
+}
+
+/// Parse a frame from the start of a buffer, returning the frame and the number of bytes it used.
+/// Returns `None` if the buffer does not contain a whole frame yet.
+///
+/// # Errors
+///
+/// This function will return an error if the opcode is unknown, the frame is not masked (all
+/// frames from a client must be) or the payload is longer than `max_length`. The length is
+/// checked as soon as it is received, without waiting for the payload.
+pub fn parse_frame(buffer: &[u8], max_length: usize) -> Result<Option<(Frame, usize)>, ReadError> {
+    let (byte1, byte2) = match buffer {
+        [byte1, byte2, ..] => (*byte1, *byte2),
+        _ => return Ok(None),
+    };
+
+    let fin = byte1 & 0x80 != 0;
+    let opcode = Opcode::from_code(byte1 & 0x0F).map_err(ReadError::Protocol)?;
+
+    if byte2 & 0x80 == 0 {
+        return Err(ReadError::Protocol("Frames from the client must be masked"));
+    }
+
+    // The 7 bit length, or a marker for a 16 or 64 bit length following it.
+    let (length, mut position) = match byte2 & 0x7F {
+        126 => match buffer.get(2..4) {
+            Some(bytes) => (u16::from_be_bytes([bytes[0], bytes[1]]) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match buffer.get(2..10) {
+            Some(bytes) => {
+                let mut length = [0; 8];
+                length.copy_from_slice(bytes);
+                (u64::from_be_bytes(length), 10)
+            }
+            None => return Ok(None),
+        },
+        length => (length as u64, 2),
+    };
+
+    if length > max_length as u64 {
+        return Err(ReadError::MessageTooBig);
+    }
+
+    let mask = match buffer.get(position..position + 4) {
+        Some(mask) => [mask[0], mask[1], mask[2], mask[3]],
+        None => return Ok(None),
+    };
+    position += 4;
+
+    let length = usize::try_from(length).map_err(|_| ReadError::MessageTooBig)?;
+
+    let payload = match buffer.get(position..position.saturating_add(length)) {
+        Some(payload) => payload
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect(),
+        None => return Ok(None),
+    };
+
+    Ok(Some((
+        Frame {
+            fin,
+            opcode,
+            payload,
+        },
+        position + length,
+    )))
+}
+
 /// Handle the WebSockets handshake and return a WebSockets key for use in the Sec-WebSocket-Accept
-//  http header.
-pub fn handle_handshake(key: &String) -> String {
+//  http header. Any surrounding whitespace in the key is ignored.
+pub fn handle_handshake(key: &str) -> String {
+    create_accept(key, HANDSHAKE_GUID)
+}
+
+/// Create the `Sec-WebSocket-Accept` value for a key, using `guid` in place of the standard
+/// [`HANDSHAKE_GUID`] (for testing against servers using another one).
+pub fn create_accept(key: &str, guid: &str) -> String {
     let mut hasher = Sha1::new();
 
-    // Combine the key and standard websocket uuid.
-    hasher.update(format!("{}{}", key, "258EAFA5-E914-47DA-95CA-C5AB0DC85B11").as_bytes());
+    // Combine the key and the GUID.
+    hasher.update(format!("{}{}", key.trim(), guid).as_bytes());
 
     // Sha1 hash and then base64 encode.
     base64::encode(hasher.finalize())
 }
 
-/// Handle creating a short WebSocket message to be send to a client.
-pub fn handle_write(data: &mut Vec<u8>, length: u8) -> Vec<u8> {
-    let mut response = Vec::with_capacity(length as usize + 2);
+/// Check if a `Sec-WebSocket-Accept` value is the correct response to a key, for example to
+/// verify the handshake of another server.
+pub fn verify_accept(key: &str, accept: &str) -> bool {
+    handle_handshake(key) == accept.trim()
+}
+
+/// Handle creating a Close frame to be sent to a client, with a status code and a reason.
+///
+/// Control frames are limited to 125 bytes so the reason is truncated (on a character boundary)
+/// to fit after the 2 byte code.
+pub fn handle_close(code: u16, reason: &str) -> Vec<u8> {
+    let mut end = reason.len().min(MAX_CONTROL_PAYLOAD - 2);
+
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+
+    handle_write(&payload, Opcode::Close)
+}
+
+/// Handle creating a WebSocket message to be send to a client. Payloads longer than 125 bytes
+/// use the extended 16 or 64 bit length.
+pub fn handle_write(data: &[u8], opcode: Opcode) -> Vec<u8> {
+    create_frame(data, opcode, false)
+}
+
+/// Handle creating a WebSocket message compressed with the negotiated `permessage-deflate`
+/// extension, the frame has the RSV1 bit set to mark it as compressed.
+///
+/// # Errors
+///
+/// This function will return an error if the data can not be compressed.
+pub fn handle_write_deflated(data: &[u8], opcode: Opcode) -> Result<Vec<u8>, &'static str> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+
+    // A flush ends the data with an empty stored block, which is left off.
+    encoder
+        .write_all(data)
+        .and_then(|_| encoder.flush())
+        .map_err(|_| "Could not compress message")?;
+
+    let mut compressed = mem::take(encoder.get_mut());
+
+    if compressed.ends_with(&DEFLATE_TAIL) {
+        compressed.truncate(compressed.len() - DEFLATE_TAIL.len());
+    }
+
+    Ok(create_frame(&compressed, opcode, true))
+}
+
+/// Check if a `Sec-WebSocket-Extensions` request header offers `permessage-deflate` with
+/// parameters the server can accept. Offers asking the server to use a smaller window than the
+/// default are declined.
+pub fn accepts_permessage_deflate(extensions: &str) -> bool {
+    extensions.split(',').any(|offer| {
+        let mut parts = offer.split(';').map(str::trim);
+
+        parts.next() == Some(PERMESSAGE_DEFLATE)
+            && parts.all(|parameter| match parameter.split_once('=') {
+                Some((name, bits)) if name.trim() == "server_max_window_bits" => {
+                    bits.trim().trim_matches('"') == "15"
+                }
+                _ => true,
+            })
+    })
+}
+
+/// Create a frame, the mask bit is not set because it is from the server.
+fn create_frame(data: &[u8], opcode: Opcode, compressed: bool) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 10);
 
     // Fin byte
     let fin: u8 = 0x80;
-    let byte1 = fin | 1;
+    let rsv1: u8 = if compressed { 0x40 } else { 0 };
+    frame.push(fin | rsv1 | opcode.get_code());
+
+    if data.len() <= 125 {
+        frame.push(data.len() as u8);
+    } else if data.len() <= u16::MAX as usize {
+        frame.push(126);
+        frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(data.len() as u64).to_be_bytes());
+    }
+
+    frame.extend_from_slice(data);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a masked frame, as sent by a client.
+    fn client_frame(fin: bool, opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode.get_code()];
+
+        if payload.len() <= 125 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn fragments_are_reassembled_around_control_frames() {
+        let mut reader = MessageReader::new(100);
+
+        reader.push(&client_frame(false, Opcode::Text, b"Hello, "));
+        reader.push(&client_frame(true, Opcode::Ping, b"ping"));
+        reader.push(&client_frame(true, Opcode::Continuation, b"world"));
+
+        let ping = reader.next_message().unwrap().unwrap();
+        assert_eq!(ping.opcode, Opcode::Ping);
+        assert_eq!(ping.payload, b"ping");
+
+        let message = reader.next_message().unwrap().unwrap();
+        assert_eq!(message.opcode, Opcode::Text);
+        assert_eq!(message.payload, b"Hello, world");
+
+        assert!(reader.next_message().unwrap().is_none());
+    }
+
+    #[test]
+    fn fragments_in_one_buffer_share_the_size_limit() {
+        let mut reader = MessageReader::new(100);
+
+        // Each fragment fits on its own, together they are too big.
+        reader.push(&client_frame(false, Opcode::Text, &[b'a'; 40]));
+        reader.push(&client_frame(false, Opcode::Continuation, &[b'b'; 40]));
+        reader.push(&client_frame(true, Opcode::Continuation, &[b'c'; 40]));
+
+        let error = reader.next_message().err().unwrap();
+
+        assert!(matches!(error, ReadError::MessageTooBig));
+        assert_eq!(error.get_close_code(), Some(CLOSE_MESSAGE_TOO_BIG));
+    }
+
+    #[test]
+    fn frames_are_read_once_complete() {
+        let frame = client_frame(true, Opcode::Binary, &[7; 200]);
+        let mut reader = MessageReader::new(1000);
+
+        reader.push(&frame[..150]);
+        assert!(reader.next_message().unwrap().is_none());
+
+        reader.push(&frame[150..]);
+        assert_eq!(reader.next_message().unwrap().unwrap().payload, [7; 200]);
+    }
+
+    #[test]
+    fn unmasked_frames_are_rejected() {
+        let mut reader = MessageReader::new(100);
+
+        reader.push(&handle_write(b"hi", Opcode::Text));
+
+        let error = reader.next_message().err().unwrap();
+        assert_eq!(error.get_close_code(), Some(CLOSE_PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn close_frames_carry_the_code_and_reason() {
+        let frame = handle_close(CLOSE_NORMAL, "Server shutting down");
+
+        assert_eq!(frame[0], 0x88);
+        assert_eq!(frame[1] as usize, 2 + "Server shutting down".len());
+        assert_eq!(u16::from_be_bytes([frame[2], frame[3]]), 1000);
+        assert_eq!(&frame[4..], b"Server shutting down");
+    }
+
+    #[test]
+    fn close_frames_fit_in_a_control_frame() {
+        let frame = handle_close(CLOSE_NORMAL, &"é".repeat(100));
 
-    // 0 used because this is from the server.
-    let byte2: u8 = 0 | length;
+        assert_eq!(frame[0], 0x88);
+        assert!(frame[1] as usize <= MAX_CONTROL_PAYLOAD);
+        assert!(String::from_utf8(frame[4..].to_vec()).is_ok());
+    }
 
-    response.push(byte1);
-    response.push(byte2);
+    #[test]
+    fn accept_matches_the_rfc_example() {
+        let accept = handle_handshake("dGhlIHNhbXBsZSBub25jZQ==");
 
-    response.append(data);
-    response
+        assert_eq!(accept, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert!(verify_accept("dGhlIHNhbXBsZSBub25jZQ==", &accept));
+    }
 }